@@ -0,0 +1,60 @@
+// Cost of starting a sample on the hot path: decoding per hit (the old player)
+// versus playing from a buffer decoded once at load time.
+use std::hint::black_box;
+use std::io::Cursor;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rodio::source::UniformSourceIterator;
+use rodio::Decoder;
+
+// The binary crate has no lib target, so pull in the audio modules directly.
+#[allow(dead_code)]
+#[path = "../src/audio/loader.rs"]
+mod loader;
+#[allow(dead_code)]
+#[path = "../src/audio/mixer.rs"]
+mod mixer;
+// mixer.rs refers to `crate::audio::loader`
+mod audio {
+    pub(crate) use crate::loader;
+}
+
+use mixer::{Mixer, MixerControls, Voice};
+
+const RATE: u32 = 48_000;
+/// Roughly one audio callback's worth of frames after the hit.
+const FRAMES: usize = 256;
+
+fn trigger(c: &mut Criterion) {
+    let bytes = loader::load_file_bytes("sounds/hat.wav").unwrap();
+    let pcm = loader::decode_bytes(bytes.clone(), RATE).unwrap();
+
+    let mut group = c.benchmark_group("trigger");
+
+    // before: clone the file bytes and decode + resample on every hit
+    group.bench_function("decode_per_hit", |b| {
+        b.iter(|| {
+            let decoder = Decoder::new(Cursor::new(bytes.clone())).unwrap();
+            let voice = UniformSourceIterator::<_, f32>::new(decoder, 2, RATE);
+            black_box(voice.take(FRAMES * 2).sum::<f32>())
+        })
+    });
+
+    // after: a hit is an Arc clone and a read position, mixed like the live output
+    group.bench_function("predecoded", |b| {
+        b.iter(|| {
+            let mut mixer = Mixer::new(MixerControls::default(), RATE);
+            mixer.add_voice(0, Voice::new(pcm.clone(), 1.0));
+            let mut sum = 0.0;
+            for _ in 0..FRAMES {
+                sum += mixer.next_frame()[0];
+            }
+            black_box(sum)
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, trigger);
+criterion_main!(benches);
@@ -1,120 +1,222 @@
-use std::sync::mpsc::{self, Receiver, Sender};
-use std::thread;
+use std::path::PathBuf;
 
 use anyhow::Result;
 
-use crate::audio::player::Player;
-use crate::sequencer::engine::{SequencerCommand, SequencerEvent, SequencerEngine};
+use crate::sequencer::engine::{SequencerCommand, SequencerEvent};
+use crate::session::{Session, SessionOptions};
+use crate::ui::layout::Theme;
 
+/// The terminal front end on top of a `Session`.
 pub struct App {
     pub ui: crate::ui::Ui,
-    player: Player,
-    seq_tx: Sender<SequencerCommand>,
-    seq_rx: Receiver<SequencerEvent>,
+    session: Session,
 }
 
 impl App {
-    pub fn new() -> Result<Self> {
-        // UI
-        let ui = crate::ui::Ui::new()?;
+    /// `project_path` is opened if it exists; otherwise a new project will be saved there.
+    /// With `osc_port` the OSC server starts straight away.
+    pub fn new(
+        project_path: Option<PathBuf>,
+        opts: &SessionOptions,
+        osc_port: Option<u16>,
+    ) -> Result<Self> {
+        // Load the project and open the port before touching the terminal so errors print normally
+        let mut session = Session::new(project_path, opts)?;
+        if let Some(port) = osc_port {
+            session.start_osc(port)?;
+        }
 
-        // Audio player (loads sounds from sounds/)
-        let mut player = Player::new()?;
-        // Try load known sample names (will ignore if file missing)
-        player.load("kick", "sounds/kick.wav").ok();
-        player.load("snare", "sounds/snare.wav").ok();
-        player.load("hat", "sounds/hat.wav").ok();
-        player.load("clap", "sounds/clap.wav").ok();
+        // UI
+        let theme = Theme::named(&opts.config.theme).unwrap_or_default();
+        let keymap = opts.config.keymap()?;
+        let ui = crate::ui::Ui::new(theme, keymap)?;
 
-        // Sequencer channels
-        let (cmd_tx, cmd_rx) = mpsc::channel();
-        let (evt_tx, evt_rx) = mpsc::channel();
+        Ok(Self { ui, session })
+    }
 
-        // Create and run sequencer engine in another thread
-        let mut engine = SequencerEngine::new(evt_tx)?;
-        thread::spawn(move || {
-            engine.run(cmd_rx);
-        });
+    /// Show the outcome of a session action in the footer.
+    fn report(&mut self, result: Result<String>) {
+        match result {
+            Ok(message) => self.ui.set_message(message),
+            Err(e) => self.ui.set_message(format!("{:#}", e)),
+        }
+    }
 
-        Ok(Self {
-            ui,
-            player,
-            seq_tx: cmd_tx,
-            seq_rx: evt_rx,
-        })
+    /// Tell the UI about the project's current tracks and mixer.
+    fn sync_tracks(&mut self) {
+        self.ui.set_tracks(
+            self.session.track_names(),
+            self.session.sample_errors().to_vec(),
+        );
+        self.ui.set_strips(self.session.player().mixer().strips());
     }
 
     pub fn run(&mut self) -> Result<()> {
-        // Initial app state: create a default pattern
-        // Pattern of 4 tracks with 8 steps
-        let tracks = vec!["kick", "snare", "hat", "clap"];
-        let mut pattern = vec![vec![false; 8]; tracks.len()];
-
-        // Example default pattern
-        pattern[0][0] = true; // kick on step 0
-        pattern[0][2] = true;
-        pattern[1][1] = true; // snare on step 1
-        for i in 0..8 {
-            pattern[2][i] = i % 2 == 0; // hi-hat on even steps
+        // Initial app state comes from the project
+        self.session.apply_project();
+        self.sync_tracks();
+        if let Some(warning) = self.session.warning() {
+            self.ui.set_message(warning.to_string());
+        } else if let Some(missing) = self.session.missing_samples() {
+            self.ui.set_message(missing);
         }
 
-        // Set sequencer initial config
-        self.seq_tx
-            .send(SequencerCommand::Configure { bpm: 120, steps: 8 })
-            .ok();
-        self.seq_tx
-            .send(SequencerCommand::SetPattern { pattern: pattern.clone() })
-            .ok();
-
         // Ask UI to enter main loop. UI will return user actions which we forward to sequencer/player.
         loop {
+            // Handle incoming sequencer events first so the frame drawn below is current
+            // (audio is already scheduled by the engine)
+            for ev in self.session.poll_events() {
+                match ev {
+                    SequencerEvent::Step { step, hits } => self.ui.set_playhead(step, hits),
+                    SequencerEvent::PatternSwitched { slot } => self.ui.pattern_switched(slot),
+                    SequencerEvent::State(state) => self.ui.set_state(state),
+                    // the playing slot comes with the next state
+                    SequencerEvent::Bank(_) => {}
+                    // the session has already put the project's tracks right
+                    SequencerEvent::TrackInserted { .. } | SequencerEvent::TrackRemoved { .. } => {
+                        self.sync_tracks()
+                    }
+                }
+            }
+            for error in self.session.take_errors() {
+                self.ui.set_message(error);
+            }
+
             // Poll UI for next user action (blocking until available or UI requests exit)
             match self.ui.tick()? {
                 crate::ui::UiEvent::Exit => {
                     // Stop sequencer cleanly
-                    self.seq_tx.send(SequencerCommand::Stop).ok();
+                    self.session.stop();
                     break;
                 }
                 crate::ui::UiEvent::PlayToggle => {
-                    self.seq_tx.send(SequencerCommand::TogglePlay).ok();
+                    self.session.send(SequencerCommand::TogglePlay);
                 }
                 crate::ui::UiEvent::BpmChange(delta) => {
-                    self.seq_tx.send(SequencerCommand::AdjustBpm(delta)).ok();
+                    self.session.send(SequencerCommand::AdjustBpm(delta));
+                }
+                crate::ui::UiEvent::SetBpm(bpm) => {
+                    self.session.send(SequencerCommand::SetBpm(bpm));
+                }
+                crate::ui::UiEvent::SetSwing(swing) => {
+                    self.session.send(SequencerCommand::SetSwing(swing));
                 }
                 crate::ui::UiEvent::ToggleStep(track_idx, step) => {
-                    // forward to engine
-                    self.seq_tx
-                        .send(SequencerCommand::ToggleStep { track: track_idx, step })
-                        .ok();
-                }
-                crate::ui::UiEvent::ManualHit(opt_name) => {
-                    if let Some(name) = opt_name {
-                        self.player.play(&name);
-                    }
+                    self.session.send(SequencerCommand::ToggleStep {
+                        track: track_idx,
+                        step,
+                    });
                 }
-                crate::ui::UiEvent::Noop => {}
-            }
-
-            // Handle incoming sequencer events: play sounds when engine says to
-            while let Ok(ev) = self.seq_rx.try_recv() {
-                match ev {
-                    SequencerEvent::Step { step, hits } => {
-                        // hits: Vec<Option<String>> or bool per track
-                        for (maybe_name, should_play) in hits.iter().enumerate() {
-                            let should_play = should_play;
-                            if *should_play {
-                                if let Some(name) = tracks.get(maybe_name) {
-                                    self.player.play(name);
-                                }
-                            }
-                        }
-                        // Tell UI to update playhead
-                        self.ui.set_playhead(step);
+                crate::ui::UiEvent::SetVelocity(track, step, velocity) => {
+                    self.session.send(SequencerCommand::SetVelocity {
+                        track,
+                        step,
+                        velocity,
+                    });
+                }
+                crate::ui::UiEvent::SetProbability(track, step, probability) => {
+                    self.session.send(SequencerCommand::SetProbability {
+                        track,
+                        step,
+                        probability,
+                    });
+                }
+                crate::ui::UiEvent::SetCondition(track, step, condition) => {
+                    self.session.send(SequencerCommand::SetCondition {
+                        track,
+                        step,
+                        condition,
+                    });
+                }
+                crate::ui::UiEvent::SetSteps(cells) => {
+                    self.session.send(SequencerCommand::SetSteps(cells));
+                }
+                crate::ui::UiEvent::Undo => self.session.send(SequencerCommand::Undo),
+                crate::ui::UiEvent::Redo => self.session.send(SequencerCommand::Redo),
+                crate::ui::UiEvent::SetFill(fill) => {
+                    self.session.send(SequencerCommand::SetFill(fill));
+                }
+                crate::ui::UiEvent::QueuePattern(slot, after) => {
+                    self.session
+                        .send(SequencerCommand::QueuePattern { slot, after });
+                }
+                crate::ui::UiEvent::SetSong(song) => {
+                    self.session.send(SequencerCommand::SetSong(song));
+                }
+                crate::ui::UiEvent::SetSongMode(on) => {
+                    self.session.send(SequencerCommand::SetSongMode(on));
+                }
+                crate::ui::UiEvent::ManualHit(track) => self.session.hit(track),
+                crate::ui::UiEvent::Record(on) => self.session.send(SequencerCommand::Record(on)),
+                crate::ui::UiEvent::SetRecordSettings(settings) => {
+                    self.session
+                        .send(SequencerCommand::SetRecordSettings(settings));
+                }
+                crate::ui::UiEvent::ToggleMute(track) => {
+                    self.session.player().mixer().toggle_mute(track);
+                    self.ui.set_strips(self.session.player().mixer().strips());
+                }
+                crate::ui::UiEvent::ToggleSolo(track) => {
+                    self.session.player().mixer().toggle_solo(track);
+                    self.ui.set_strips(self.session.player().mixer().strips());
+                }
+                crate::ui::UiEvent::SaveProject(path) => {
+                    let result = self.session.save_project(path);
+                    self.report(result);
+                }
+                crate::ui::UiEvent::OpenProject(path) => {
+                    let result = self.session.open_project(path);
+                    self.sync_tracks();
+                    self.report(result);
+                }
+                crate::ui::UiEvent::Bounce(path, bars) => {
+                    let result = self.session.bounce(path, bars);
+                    self.report(result);
+                }
+                crate::ui::UiEvent::ExportMidi(path, song) => {
+                    let result = self.session.export_midi(path, song);
+                    self.report(result);
+                }
+                crate::ui::UiEvent::ImportMidi(path) => {
+                    let result = self.session.import_midi(path);
+                    self.report(result);
+                }
+                crate::ui::UiEvent::StartOsc(port) => {
+                    let result = self.session.start_osc(port);
+                    self.report(result.map(|addr| format!("OSC listening on {}", addr)));
+                }
+                crate::ui::UiEvent::BrowseKits => {
+                    let found = self.session.discover_kits();
+                    // broken manifests are listed nowhere else, so say so
+                    if let Some(e) = found.errors.first() {
+                        self.ui.set_message(format!(
+                            "{} kit(s) could not be read: {:#}",
+                            found.errors.len(),
+                            e
+                        ));
                     }
-                    SequencerEvent::PlaybackState(_running) => {
-                        // could reflect to UI
+                    self.ui.show_kits(found.kits);
+                }
+                crate::ui::UiEvent::LoadKit(name) => {
+                    let result = self.session.load_kit(&name);
+                    self.sync_tracks();
+                    self.report(result);
+                }
+                crate::ui::UiEvent::AddTrack(name) => {
+                    if let Err(e) = self.session.add_track(name) {
+                        self.ui.set_message(format!("{:#}", e));
                     }
+                    self.sync_tracks();
+                }
+                crate::ui::UiEvent::RemoveTrack(track) => {
+                    self.session.remove_track(track);
+                    self.sync_tracks();
                 }
+                crate::ui::UiEvent::ResizePattern(steps) => {
+                    self.session.resize_pattern(steps);
+                    self.sync_tracks();
+                }
+                crate::ui::UiEvent::Noop => {}
             }
         }
 
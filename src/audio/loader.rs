@@ -1,8 +1,30 @@
 // Load WAV samples
 use anyhow::{Context, Result};
+use rodio::source::UniformSourceIterator;
+use rodio::Decoder;
 use std::fs;
+use std::io::Cursor;
+use std::sync::Arc;
+
+/// A decoded sample: interleaved stereo f32 at the output rate, shared between voices.
+pub type Pcm = Arc<[f32]>;
 
 pub fn load_file_bytes(path: &str) -> Result<Vec<u8>> {
     let b = fs::read(path).with_context(|| format!("Failed to read sound file: {}", path))?;
     Ok(b)
 }
+
+/// Read and decode a sound file once, so triggering it later is just a buffer copy.
+pub fn decode_file(path: &str, sample_rate: u32) -> Result<Pcm> {
+    let bytes = load_file_bytes(path)?;
+    decode_bytes(bytes, sample_rate)
+        .with_context(|| format!("Failed to decode sound file: {}", path))
+}
+
+/// Decode an in-memory wav/mp3 file to stereo (the mixer works in stereo),
+/// resampled to `sample_rate`.
+pub fn decode_bytes(bytes: Vec<u8>, sample_rate: u32) -> Result<Pcm> {
+    let decoder = Decoder::new(Cursor::new(bytes))?;
+    let pcm: Vec<f32> = UniformSourceIterator::<_, f32>::new(decoder, 2, sample_rate).collect();
+    Ok(pcm.into())
+}
@@ -1 +1,285 @@
-// Optional mixer
+// Software mixer
+// One channel strip per sequencer track, summed into a limited stereo master bus.
+use std::sync::{Arc, Mutex};
+
+use crate::audio::loader::Pcm;
+
+/// Track number of metronome clicks, which have no channel strip.
+pub const CLICK_TRACK: usize = usize::MAX;
+
+/// One playing hit: a read position into a shared, pre-decoded sample.
+pub struct Voice {
+    pcm: Pcm,
+    pos: usize,
+    // per-hit level (from step velocity), applied before the channel strip
+    gain: f32,
+}
+
+impl Voice {
+    pub fn new(pcm: Pcm, gain: f32) -> Self {
+        Self { pcm, pos: 0, gain }
+    }
+
+    fn next_frame(&mut self) -> Option<[f32; 2]> {
+        let frame = self.pcm.get(self.pos..self.pos + 2)?;
+        self.pos += 2;
+        Some([frame[0] * self.gain, frame[1] * self.gain])
+    }
+}
+
+/// Per-track level, balance and routing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelStrip {
+    /// Linear gain, 1.0 = unity
+    pub gain: f32,
+    /// -1.0 hard left, 0.0 centre, 1.0 hard right
+    pub pan: f32,
+    pub mute: bool,
+    pub solo: bool,
+    /// Hits cut off earlier hits on other tracks in the same group
+    pub choke: Option<u8>,
+}
+
+impl Default for ChannelStrip {
+    fn default() -> Self {
+        Self {
+            gain: 1.0,
+            pan: 0.0,
+            mute: false,
+            solo: false,
+            choke: None,
+        }
+    }
+}
+
+impl ChannelStrip {
+    /// Left/right gains for this strip. Balance law: centre is unity on both sides.
+    fn stereo_gains(&self) -> (f32, f32) {
+        let pan = self.pan.clamp(-1.0, 1.0);
+        (
+            self.gain * (1.0 - pan).min(1.0),
+            self.gain * (1.0 + pan).min(1.0),
+        )
+    }
+}
+
+/// Channel strip settings shared between the UI and the audio thread.
+/// Cheap to clone; clones control the same mixer.
+#[derive(Debug, Clone, Default)]
+pub struct MixerControls {
+    strips: Arc<Mutex<Vec<ChannelStrip>>>,
+}
+
+impl MixerControls {
+    pub fn new(strips: Vec<ChannelStrip>) -> Self {
+        Self {
+            strips: Arc::new(Mutex::new(strips)),
+        }
+    }
+
+    /// Current settings for every track that has a strip.
+    pub fn strips(&self) -> Vec<ChannelStrip> {
+        self.strips.lock().unwrap().clone()
+    }
+
+    /// Replace every strip, keeping mute and solo where a track already had them.
+    pub fn set_strips(&self, mut new: Vec<ChannelStrip>) {
+        let mut strips = self.strips.lock().unwrap();
+        for (strip, old) in new.iter_mut().zip(strips.iter()) {
+            strip.mute = old.mute;
+            strip.solo = old.solo;
+        }
+        *strips = new;
+    }
+
+    pub fn toggle_mute(&self, track: usize) {
+        self.update(track, |s| s.mute = !s.mute);
+    }
+
+    pub fn toggle_solo(&self, track: usize) {
+        self.update(track, |s| s.solo = !s.solo);
+    }
+
+    /// Drop a track's strip so later tracks keep their settings when they move up.
+    pub fn remove_track(&self, track: usize) {
+        let mut strips = self.strips.lock().unwrap();
+        if track < strips.len() {
+            strips.remove(track);
+        }
+    }
+
+    /// Give a track put back at `track` its strip, moving later ones down.
+    pub fn insert_track(&self, track: usize, strip: ChannelStrip) {
+        let mut strips = self.strips.lock().unwrap();
+        if track <= strips.len() {
+            strips.insert(track, strip);
+        }
+    }
+
+    fn update(&self, track: usize, f: impl FnOnce(&mut ChannelStrip)) {
+        let mut strips = self.strips.lock().unwrap();
+        if strips.len() <= track {
+            strips.resize(track + 1, ChannelStrip::default());
+        }
+        f(&mut strips[track]);
+    }
+
+    /// Non-blocking refresh for the audio thread: copies the settings into
+    /// `strips`, reusing its buffer, unless the UI holds the lock.
+    fn try_copy_strips(&self, strips: &mut Vec<ChannelStrip>) {
+        if let Ok(current) = self.strips.try_lock() {
+            strips.clone_from(&current);
+        }
+    }
+}
+
+/// Peak limiter on the master bus: instant attack, exponential release.
+struct Limiter {
+    ceiling: f32,
+    release: f32,
+    gain: f32,
+}
+
+impl Limiter {
+    fn new(sample_rate: u32) -> Self {
+        // ~100 ms release
+        let release_frames = sample_rate as f32 * 0.1;
+        Self {
+            ceiling: 0.95,
+            release: 1.0 / release_frames.max(1.0),
+            gain: 1.0,
+        }
+    }
+
+    fn process(&mut self, frame: [f32; 2]) -> [f32; 2] {
+        let peak = frame[0].abs().max(frame[1].abs());
+        let target = if peak > self.ceiling {
+            self.ceiling / peak
+        } else {
+            1.0
+        };
+        if target < self.gain {
+            self.gain = target;
+        } else {
+            self.gain += (target - self.gain) * self.release;
+        }
+        [frame[0] * self.gain, frame[1] * self.gain]
+    }
+}
+
+/// How often (in frames) the audio thread picks up strip changes.
+const CONTROL_INTERVAL: u32 = 64;
+/// Hits sounding at once; a new hit past this cuts off the oldest.
+pub const MAX_VOICES: usize = 64;
+/// Strips reserved up front, one per track at the grid's track limit.
+const RESERVED_STRIPS: usize = 64;
+
+/// The audio-thread side of the mixer: owns the playing voices.
+pub struct Mixer {
+    controls: MixerControls,
+    strips: Vec<ChannelStrip>,
+    voices: Vec<(usize, Voice)>,
+    limiter: Limiter,
+    frames_until_refresh: u32,
+}
+
+impl Mixer {
+    pub fn new(controls: MixerControls, sample_rate: u32) -> Self {
+        let mut strips = controls.strips();
+        strips.reserve(RESERVED_STRIPS);
+        Self {
+            controls,
+            strips,
+            voices: Vec::with_capacity(MAX_VOICES),
+            limiter: Limiter::new(sample_rate),
+            frames_until_refresh: CONTROL_INTERVAL,
+        }
+    }
+
+    /// Start playing `voice` through `track`'s channel strip, stealing the
+    /// oldest voice if `MAX_VOICES` are already sounding.
+    pub fn add_voice(&mut self, track: usize, voice: Voice) {
+        if let Some(group) = self.strips.get(track).and_then(|s| s.choke) {
+            let strips = &self.strips;
+            self.voices.retain(|(other, _)| {
+                *other == track || strips.get(*other).and_then(|s| s.choke) != Some(group)
+            });
+        }
+        if self.voices.len() >= MAX_VOICES {
+            self.voices.remove(0);
+        }
+        self.voices.push((track, voice));
+    }
+
+    /// Mix one stereo frame of every playing voice through its strip and the master bus.
+    pub fn next_frame(&mut self) -> [f32; 2] {
+        self.frames_until_refresh -= 1;
+        if self.frames_until_refresh == 0 {
+            self.frames_until_refresh = CONTROL_INTERVAL;
+            self.controls.try_copy_strips(&mut self.strips);
+        }
+
+        let any_solo = self.strips.iter().any(|s| s.solo);
+        let strips = &self.strips;
+        let mut bus = [0.0f32; 2];
+        self.voices.retain_mut(|(track, voice)| {
+            let Some([l, r]) = voice.next_frame() else {
+                return false;
+            };
+            let strip = strips.get(*track).copied().unwrap_or_default();
+            // the count-in click isn't a track, so mute and solo leave it alone
+            let silenced = *track != CLICK_TRACK && (strip.mute || (any_solo && !strip.solo));
+            if silenced {
+                // keep advancing so unmuting doesn't resume a stale hit
+                return true;
+            }
+            let (gl, gr) = strip.stereo_gains();
+            bus[0] += l * gl;
+            bus[1] += r * gr;
+            true
+        });
+
+        self.limiter.process(bus)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strip(choke: Option<u8>) -> ChannelStrip {
+        ChannelStrip {
+            choke,
+            ..ChannelStrip::default()
+        }
+    }
+
+    #[test]
+    fn hits_cut_off_other_tracks_in_their_choke_group() {
+        // open hat, closed hat (same group), kick (no group)
+        let controls = MixerControls::new(vec![strip(Some(1)), strip(Some(1)), strip(None)]);
+        let mut mixer = Mixer::new(controls, 44_100);
+        let pcm: Pcm = vec![0.5; 64].into();
+        mixer.add_voice(0, Voice::new(pcm.clone(), 1.0));
+        mixer.add_voice(2, Voice::new(pcm.clone(), 1.0));
+        mixer.add_voice(0, Voice::new(pcm.clone(), 1.0));
+        assert_eq!(mixer.voices.len(), 3);
+
+        mixer.add_voice(1, Voice::new(pcm, 1.0));
+        let tracks: Vec<usize> = mixer.voices.iter().map(|(t, _)| *t).collect();
+        assert_eq!(tracks, [2, 1]);
+    }
+
+    #[test]
+    fn voices_past_the_limit_steal_the_oldest_without_growing() {
+        let mut mixer = Mixer::new(MixerControls::default(), 44_100);
+        let capacity = mixer.voices.capacity();
+        let pcm: Pcm = vec![0.5; 64].into();
+        for track in 0..MAX_VOICES + 3 {
+            mixer.add_voice(track, Voice::new(pcm.clone(), 1.0));
+        }
+        assert_eq!(mixer.voices.len(), MAX_VOICES);
+        assert_eq!(mixer.voices[0].0, 3);
+        assert_eq!(mixer.voices.capacity(), capacity);
+    }
+}
@@ -2,3 +2,4 @@
 pub mod loader;
 pub mod mixer;
 pub mod player;
+pub mod scheduler;
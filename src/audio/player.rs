@@ -1,47 +1,336 @@
 // Play sounds
+use anyhow::{anyhow, bail, Result};
+use rodio::cpal::traits::{HostTrait, StreamTrait};
+use rodio::cpal::{
+    self, BufferSize, FromSample, SampleFormat, SizedSample, StreamConfig, SupportedBufferSize,
+};
+use rodio::DeviceTrait;
 use std::collections::HashMap;
-use std::io::Cursor;
-use anyhow::Result;
-use rodio::{Decoder, OutputStream, OutputStreamHandle, Sink};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError};
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-/// Very small player that holds loaded sample bytes in memory by name.
-/// Each play clones the bytes into a Cursor and decodes.
+use crate::audio::loader::{decode_file, Pcm};
+use crate::audio::mixer::MixerControls;
+use crate::audio::scheduler::{FrameClock, ScheduledSource, Trigger};
+
+/// Each track's sample, in track order; `None` where nothing is loaded.
+pub type TrackSamples = Arc<Vec<Option<Pcm>>>;
+
+/// Replaced copies the audio thread can hand back before the next change.
+const RETIRED: usize = 8;
+
+/// Decoded samples by name, plus which sample each sequencer track plays.
+/// Cheap to clone; clones share the same storage.
+#[derive(Clone)]
+pub struct SampleBank {
+    samples: Arc<Mutex<HashMap<String, Pcm>>>,
+    tracks: Arc<Mutex<Vec<String>>>,
+    // the two above resolved per track, rebuilt on every change so the
+    // audio thread only ever swaps in a finished copy
+    resolved: Arc<Mutex<TrackSamples>>,
+    // copies the audio thread has swapped out, freed here rather than in
+    // the callback
+    retired_tx: SyncSender<TrackSamples>,
+    retired_rx: Arc<Mutex<Receiver<TrackSamples>>>,
+}
+
+impl Default for SampleBank {
+    fn default() -> Self {
+        let (retired_tx, retired_rx) = mpsc::sync_channel(RETIRED);
+        Self {
+            samples: Arc::default(),
+            tracks: Arc::default(),
+            resolved: Arc::default(),
+            retired_tx,
+            retired_rx: Arc::new(Mutex::new(retired_rx)),
+        }
+    }
+}
+
+impl SampleBank {
+    pub fn insert(&self, name: &str, pcm: Pcm) {
+        self.samples.lock().unwrap().insert(name.to_string(), pcm);
+        self.resolve();
+    }
+
+    pub fn set_tracks(&self, names: &[&str]) {
+        *self.tracks.lock().unwrap() = names.iter().map(|n| n.to_string()).collect();
+        self.resolve();
+    }
+
+    fn resolve(&self) {
+        self.retired_rx.lock().unwrap().try_iter().for_each(drop);
+        let samples = self.samples.lock().unwrap();
+        let resolved = self
+            .tracks
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|name| samples.get(name).cloned())
+            .collect();
+        *self.resolved.lock().unwrap() = Arc::new(resolved);
+    }
+
+    /// The latest samples per track, or `None` if the UI thread is updating
+    /// them right now; the audio thread then keeps playing its last copy
+    /// rather than wait. Only clones an `Arc`.
+    pub fn try_track_samples(&self) -> Option<TrackSamples> {
+        self.resolved
+            .try_lock()
+            .ok()
+            .map(|resolved| resolved.clone())
+    }
+
+    /// Hand a swapped-out copy back to be freed off the audio thread. Gives
+    /// it back if the queue is full, which the caller should keep it.
+    pub fn retire(&self, samples: TrackSamples) -> Result<(), TrackSamples> {
+        self.retired_tx.try_send(samples).map_err(|e| match e {
+            TrySendError::Full(samples) | TrySendError::Disconnected(samples) => samples,
+        })
+    }
+}
+
+/// Stands in for an output device: pulls the source at real-time pace and
+/// discards it, so the sequencer keeps time on machines without sound.
+struct NullOutput {
+    running: Arc<AtomicBool>,
+}
+
+impl NullOutput {
+    /// Sample rate used when there is no device to ask.
+    const SAMPLE_RATE: u32 = 44_100;
+    const CHANNELS: u16 = 2;
+
+    fn start(mut source: ScheduledSource) -> Self {
+        let running = Arc::new(AtomicBool::new(true));
+        let flag = running.clone();
+        thread::spawn(move || {
+            let started = Instant::now();
+            let mut pulled = 0u64;
+            while flag.load(Ordering::Relaxed) {
+                let due =
+                    started.elapsed().as_micros() as u64 * Self::SAMPLE_RATE as u64 / 1_000_000;
+                let samples = (due.saturating_sub(pulled)) as usize * Self::CHANNELS as usize;
+                source.by_ref().take(samples).for_each(drop);
+                pulled = due;
+                thread::sleep(Duration::from_millis(5));
+            }
+        });
+        Self { running }
+    }
+}
+
+impl Drop for NullOutput {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Play `source` on `device`, converting to the device's sample format.
+/// Opened on cpal directly (not through rodio) so the buffer size can be set.
+fn output_stream<T>(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    mut source: ScheduledSource,
+    errors: Sender<String>,
+) -> Result<cpal::Stream>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let stream = device.build_output_stream(
+        config,
+        move |data: &mut [T], _| {
+            for out in data.iter_mut() {
+                *out = T::from_sample(source.next().unwrap_or(0.0));
+            }
+        },
+        // printing would draw over the TUI; the session reports these instead
+        move |e| {
+            errors.send(format!("audio output error: {}", e)).ok();
+        },
+        None,
+    )?;
+    Ok(stream)
+}
+
+/// Very small player that holds decoded samples in memory by name.
+/// All playback, sequenced or manual, goes through one sample-clocked output
+/// source and the mixer's channel strips.
 pub struct Player {
-    _stream: OutputStream,
-    handle: OutputStreamHandle,
-    samples: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    // kept alive for as long as the player; one or the other
+    _stream: Option<cpal::Stream>,
+    _null: Option<NullOutput>,
+    bank: SampleBank,
+    mixer: MixerControls,
+    clock: FrameClock,
+    triggers: Sender<Trigger>,
+    sample_rate: u32,
+    errors: Receiver<String>,
 }
 
 impl Player {
-    pub fn new() -> Result<Self> {
-        let (stream, handle) = OutputStream::try_default()?;
+    /// Open the named output device, or the system default. `buffer_frames`
+    /// asks for a fixed device buffer; otherwise the device picks one.
+    pub fn new(device_name: Option<&str>, buffer_frames: Option<u32>) -> Result<Self> {
+        let host = rodio::cpal::default_host();
+        let device = match device_name {
+            None => host
+                .default_output_device()
+                .ok_or_else(|| anyhow!("No audio output device available"))?,
+            Some(name) => {
+                let mut names = Vec::new();
+                let mut found = None;
+                for device in host.output_devices()? {
+                    let device_name = device.name().unwrap_or_default();
+                    if device_name == name {
+                        found = Some(device);
+                        break;
+                    }
+                    names.push(device_name);
+                }
+                found.ok_or_else(|| {
+                    anyhow!(
+                        "No output device named {:?} (available: {})",
+                        name,
+                        if names.is_empty() {
+                            "none".to_string()
+                        } else {
+                            names.join(", ")
+                        }
+                    )
+                })?
+            }
+        };
+        let supported = device.default_output_config()?;
+        let channels = supported.channels();
+        let sample_rate = supported.sample_rate().0;
+        let mut config = supported.config();
+        if let Some(frames) = buffer_frames {
+            if let SupportedBufferSize::Range { min, max } = supported.buffer_size() {
+                if !(*min..=*max).contains(&frames) {
+                    bail!(
+                        "Buffer size {} is not supported by this device ({}-{} frames)",
+                        frames,
+                        min,
+                        max
+                    );
+                }
+            }
+            config.buffer_size = BufferSize::Fixed(frames);
+        }
+
+        // One long-running source does all playback
+        let bank = SampleBank::default();
+        let mixer = MixerControls::default();
+        let clock = FrameClock::new();
+        let (trig_tx, trig_rx) = mpsc::channel();
+        let source = ScheduledSource::new(
+            trig_rx,
+            bank.clone(),
+            mixer.clone(),
+            channels,
+            sample_rate,
+            clock.clone(),
+        );
+        let (err_tx, errors) = mpsc::channel();
+        let stream = match supported.sample_format() {
+            SampleFormat::F32 => output_stream::<f32>(&device, &config, source, err_tx)?,
+            SampleFormat::I16 => output_stream::<i16>(&device, &config, source, err_tx)?,
+            SampleFormat::U16 => output_stream::<u16>(&device, &config, source, err_tx)?,
+            SampleFormat::I32 => output_stream::<i32>(&device, &config, source, err_tx)?,
+            format => bail!("Unsupported output sample format {}", format),
+        };
+        stream.play()?;
+
         Ok(Self {
-            _stream: stream,
-            handle,
-            samples: Arc::new(Mutex::new(HashMap::new())),
+            _stream: Some(stream),
+            _null: None,
+            bank,
+            mixer,
+            clock,
+            triggers: trig_tx,
+            sample_rate,
+            errors,
         })
     }
 
-    /// Load a sample into memory. Path should point to a wav/mp3 file.
+    /// A player with no output device. Everything runs and keeps time as
+    /// usual, but nothing is heard.
+    pub fn silent() -> Self {
+        let sample_rate = NullOutput::SAMPLE_RATE;
+        let bank = SampleBank::default();
+        let mixer = MixerControls::default();
+        let clock = FrameClock::new();
+        let (trig_tx, trig_rx) = mpsc::channel();
+        let source = ScheduledSource::new(
+            trig_rx,
+            bank.clone(),
+            mixer.clone(),
+            NullOutput::CHANNELS,
+            sample_rate,
+            clock.clone(),
+        );
+
+        Self {
+            _stream: None,
+            _null: Some(NullOutput::start(source)),
+            bank,
+            mixer,
+            clock,
+            triggers: trig_tx,
+            sample_rate,
+            // nothing to go wrong without a device
+            errors: mpsc::channel().1,
+        }
+    }
+
+    /// Errors the output stream reported since the last call, e.g. a
+    /// device that went away.
+    pub fn take_errors(&self) -> Vec<String> {
+        self.errors.try_iter().collect()
+    }
+
+    /// Load a sample into memory, decoded at the output rate. Path should point to a wav/mp3 file.
     pub fn load(&mut self, name: &str, path: &str) -> Result<()> {
-        let bytes = crate::audio::loader::load_file_bytes(path)?;
-        self.samples.lock().unwrap().insert(name.to_string(), bytes);
+        let pcm = decode_file(path, self.sample_rate)?;
+        self.bank.insert(name, pcm);
         Ok(())
     }
 
-    /// Play a named sample (non-blocking). If sample not found, returns silently.
-    pub fn play(&self, name: &str) {
-        if let Some(bytes) = self.samples.lock().unwrap().get(name).cloned() {
-            // create a new sink for short-lived playback
-            if let Ok(sink) = Sink::try_new(&self.handle) {
-                let cursor = Cursor::new(bytes);
-                // Attempt to decode; if it fails, ignore
-                if let Ok(decoder) = Decoder::new(cursor) {
-                    sink.append(decoder);
-                    sink.detach(); // allow sink to play in background
-                }
-            }
-        }
+    /// Assign loaded samples to sequencer tracks, in track order.
+    pub fn set_tracks(&self, names: &[&str]) {
+        self.bank.set_tracks(names);
+    }
+
+    /// Frame clock of the output stream, for scheduling against.
+    pub fn clock(&self) -> FrameClock {
+        self.clock.clone()
+    }
+
+    /// Sender for hits scheduled at absolute output frames.
+    pub fn triggers(&self) -> Sender<Trigger> {
+        self.triggers.clone()
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Per-track channel strips (gain, pan, mute, solo).
+    pub fn mixer(&self) -> &MixerControls {
+        &self.mixer
+    }
+
+    /// Play a track's sample right away at full level (non-blocking).
+    /// If no sample is loaded, nothing plays.
+    pub fn play(&self, track: usize) {
+        let _ = self.triggers.send(Trigger {
+            frame: self.clock.now(),
+            track,
+            gain: 1.0,
+        });
     }
 }
@@ -0,0 +1,201 @@
+// Sample-clocked output
+// A single output source that starts queued hits at exact frame positions.
+use std::cmp::Reverse;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rodio::Source;
+
+use crate::audio::loader::Pcm;
+use crate::audio::mixer::{Mixer, MixerControls, Voice, CLICK_TRACK};
+use crate::audio::player::{SampleBank, TrackSamples};
+
+/// Triggers held before they are due; more wait in the channel.
+const MAX_PENDING: usize = 1024;
+
+/// Length and pitch of the click.
+const CLICK_MS: u32 = 25;
+const CLICK_HZ: f32 = 1500.0;
+
+/// A hit queued for playback at an absolute frame of the output stream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Trigger {
+    pub frame: u64,
+    pub track: usize,
+    /// Linear level of this hit, 1.0 = as sampled
+    pub gain: f32,
+}
+
+/// Number of frames the output source has rendered so far.
+/// Shared between the audio thread (writer) and the sequencer engine (reader).
+#[derive(Debug, Clone, Default)]
+pub struct FrameClock(Arc<AtomicU64>);
+
+impl FrameClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn now(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn set(&self, frame: u64) {
+        self.0.store(frame, Ordering::Relaxed);
+    }
+}
+
+/// Infinite rodio source that plays triggered samples through the mixer.
+/// Triggers are received ahead of time and started on the exact frame they ask for,
+/// so timing only depends on the sample clock and not on thread wake-ups.
+pub struct ScheduledSource {
+    triggers: Receiver<Trigger>,
+    // pending triggers, sorted by frame (earliest last so we can pop)
+    pending: Vec<Trigger>,
+    mixer: Mixer,
+    bank: SampleBank,
+    // the bank's samples as of the last trigger
+    samples: TrackSamples,
+    click: Pcm,
+    channels: u16,
+    sample_rate: u32,
+    clock: FrameClock,
+    frame: u64,
+    // the current output frame, spread over however many channels the device has
+    out: [f32; 2],
+    channel: u16,
+}
+
+impl ScheduledSource {
+    pub fn new(
+        triggers: Receiver<Trigger>,
+        bank: SampleBank,
+        mixer: MixerControls,
+        channels: u16,
+        sample_rate: u32,
+        clock: FrameClock,
+    ) -> Self {
+        Self {
+            triggers,
+            pending: Vec::with_capacity(MAX_PENDING),
+            mixer: Mixer::new(mixer, sample_rate),
+            samples: bank.try_track_samples().unwrap_or_default(),
+            bank,
+            click: click(sample_rate),
+            channels,
+            sample_rate,
+            clock,
+            frame: 0,
+            out: [0.0; 2],
+            channel: 0,
+        }
+    }
+
+    /// Start every pending trigger that is due on the current frame.
+    /// Late triggers (frame already passed) start immediately. Runs on the
+    /// audio thread, so nothing here allocates or frees memory.
+    fn start_due_voices(&mut self) {
+        let mut received = false;
+        while self.pending.len() < self.pending.capacity() {
+            let Ok(trigger) = self.triggers.try_recv() else {
+                break;
+            };
+            self.pending.push(trigger);
+            received = true;
+        }
+        if received {
+            self.pending.sort_unstable_by_key(|t| Reverse(t.frame));
+        }
+
+        while self.pending.last().is_some_and(|t| t.frame <= self.frame) {
+            let trigger = self.pending.pop().unwrap();
+            let pcm = if trigger.track == CLICK_TRACK {
+                Some(self.click.clone())
+            } else {
+                self.refresh_samples();
+                self.samples.get(trigger.track).cloned().flatten()
+            };
+            if let Some(pcm) = pcm {
+                self.mixer
+                    .add_voice(trigger.track, Voice::new(pcm, trigger.gain));
+            }
+        }
+    }
+
+    /// Pick up changed samples without freeing the old copy here.
+    fn refresh_samples(&mut self) {
+        let Some(latest) = self.bank.try_track_samples() else {
+            return;
+        };
+        if Arc::ptr_eq(&latest, &self.samples) {
+            return;
+        }
+        let old = std::mem::replace(&mut self.samples, latest);
+        if let Err(old) = self.bank.retire(old) {
+            // the queue is only full if the UI hasn't drained it in a while;
+            // keep playing the old copy until it has
+            self.samples = old;
+        }
+    }
+}
+
+/// A short decaying sine blip, stereo interleaved like decoded samples.
+fn click(sample_rate: u32) -> Pcm {
+    let frames = sample_rate * CLICK_MS / 1000;
+    (0..frames)
+        .flat_map(|n| {
+            let t = n as f32 / sample_rate as f32;
+            let envelope = 1.0 - n as f32 / frames as f32;
+            let level = 0.5 * envelope * (std::f32::consts::TAU * CLICK_HZ * t).sin();
+            [level, level]
+        })
+        .collect()
+}
+
+impl Iterator for ScheduledSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.channel == 0 {
+            self.start_due_voices();
+            self.out = self.mixer.next_frame();
+        }
+
+        let sample = match (self.channels, self.channel) {
+            // mono device: fold the stereo bus down
+            (1, _) => (self.out[0] + self.out[1]) * 0.5,
+            (_, 0) => self.out[0],
+            (_, 1) => self.out[1],
+            // surround devices: stereo on the front pair, silence elsewhere
+            _ => 0.0,
+        };
+
+        self.channel += 1;
+        if self.channel == self.channels {
+            self.channel = 0;
+            self.frame += 1;
+            self.clock.set(self.frame);
+        }
+        Some(sample)
+    }
+}
+
+impl Source for ScheduledSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
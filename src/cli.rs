@@ -0,0 +1,481 @@
+// Command line
+// Subcommands for playing, rendering, exporting and creating projects.
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use clap::{Args, Parser, Subcommand};
+
+use crate::config::Config;
+use crate::kit;
+use crate::midi::{export_midi, MidiOptions};
+use crate::project::{Project, DEFAULT_SOUND_DIR};
+use crate::render::{render_to_wav, RenderOptions, MAX_BARS, MAX_TAIL_SECS, SAMPLE_RATES};
+use crate::sequencer::song::BANK_SLOTS;
+use crate::session::SessionOptions;
+
+#[derive(Debug, Parser)]
+#[command(name = "cratebeat", version, about = "Terminal beat maker")]
+pub struct Cli {
+    #[command(flatten)]
+    pub global: GlobalArgs,
+    /// Without a subcommand, `cratebeat [PROJECT]` is `cratebeat play [PROJECT]`
+    #[command(flatten)]
+    pub play: PlayArgs,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Args)]
+pub struct GlobalArgs {
+    /// Directory of WAV samples for new tracks and the starter beat
+    #[arg(long, global = true, value_name = "DIR", default_value = DEFAULT_SOUND_DIR)]
+    pub sound_dir: PathBuf,
+    /// Audio output device, by name (default: from the config, else the system default)
+    #[arg(long, global = true, value_name = "NAME")]
+    pub device: Option<String>,
+    /// Fixed audio buffer size in frames (default: from the config, else the device's)
+    #[arg(long, global = true, value_name = "FRAMES")]
+    pub buffer_size: Option<u32>,
+    /// Config file to use instead of ~/.config/cratebeat/config.toml
+    #[arg(long, global = true, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Open a project in the sequencer (created on first save if missing)
+    Play(PlayArgs),
+    /// Bounce a project to a WAV file
+    Render(RenderArgs),
+    /// Write a project's pattern or arrangement as a Standard MIDI File
+    ExportMidi(ExportMidiArgs),
+    /// Create a new project file from the starter beat
+    New(NewArgs),
+    /// Work with sample kits
+    Kit {
+        #[command(subcommand)]
+        command: KitCommand,
+    },
+    /// Print the key bindings, with the config's `[keys]` applied
+    Keys,
+}
+
+#[derive(Debug, Args)]
+pub struct PlayArgs {
+    /// Project file
+    pub project: Option<PathBuf>,
+    /// Run without the terminal UI, reading commands from stdin
+    #[arg(long)]
+    pub headless: bool,
+    /// Start the OSC remote control server on this UDP port
+    #[arg(long, value_name = "PORT")]
+    pub osc: Option<u16>,
+}
+
+#[derive(Debug, Args)]
+pub struct RenderArgs {
+    /// Project file
+    pub project: PathBuf,
+    /// WAV file to write
+    #[arg(short, long, value_name = "FILE")]
+    pub output: PathBuf,
+    /// Length in 4/4 bars
+    #[arg(long, default_value_t = 1)]
+    pub bars: u32,
+    #[command(flatten)]
+    pub source: SourceArgs,
+    #[arg(long, default_value_t = 44_100)]
+    pub sample_rate: u32,
+    /// 16 or 24 for integer PCM, 32 for float
+    #[arg(long, default_value_t = 16)]
+    pub bits: u16,
+    /// Seconds rendered after the last bar so hits can ring out
+    #[arg(long, value_name = "SECS", default_value_t = 1.0)]
+    pub tail: f32,
+    /// Seed for step probabilities
+    #[arg(long, default_value_t = 0)]
+    pub seed: u64,
+}
+
+#[derive(Debug, Args)]
+pub struct ExportMidiArgs {
+    /// Project file
+    pub project: PathBuf,
+    /// MIDI file to write
+    #[arg(short, long, value_name = "FILE")]
+    pub output: PathBuf,
+    #[command(flatten)]
+    pub source: SourceArgs,
+    /// Note number for a track, on top of the config's `[midi.notes]` (repeatable)
+    #[arg(long = "note", value_name = "NAME=N", value_parser = parse_note)]
+    pub notes: Vec<(String, u8)>,
+}
+
+/// `--note kick=35`
+fn parse_note(arg: &str) -> Result<(String, u8), String> {
+    let (name, note) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=N, not `{}`", arg))?;
+    match note.trim().parse::<u8>() {
+        Ok(note) if note <= 127 && !name.trim().is_empty() => Ok((name.trim().to_string(), note)),
+        _ => Err(format!(
+            "expected a track name and a note 0-127, not `{}`",
+            arg
+        )),
+    }
+}
+
+/// What to play from the project's bank.
+#[derive(Debug, Args)]
+pub struct SourceArgs {
+    /// Pattern slot to use, 1-16
+    #[arg(long, default_value_t = 1, value_name = "N")]
+    pub pattern: usize,
+    /// Use the song arrangement instead of one pattern
+    #[arg(long, conflicts_with = "pattern")]
+    pub song: bool,
+}
+
+impl SourceArgs {
+    /// The 0-based bank slot.
+    fn slot(&self) -> Result<usize> {
+        if !(1..=BANK_SLOTS).contains(&self.pattern) {
+            bail!("--pattern must be 1-{}", BANK_SLOTS);
+        }
+        Ok(self.pattern - 1)
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct NewArgs {
+    /// Project file to create
+    pub path: PathBuf,
+    /// Pattern length (default from the config, else 8)
+    #[arg(long)]
+    pub steps: Option<usize>,
+    /// Tempo, may be fractional (default from the config, else 120)
+    #[arg(long)]
+    pub bpm: Option<f64>,
+    /// Kit to put on the tracks (default from the config, else the sound directory's samples)
+    #[arg(long, value_name = "NAME")]
+    pub kit: Option<String>,
+    /// Overwrite an existing file
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum KitCommand {
+    /// List the kits found next to the working directory and in the user's data directory
+    List,
+}
+
+impl Cli {
+    /// The project file the command works on, if any; its directory may
+    /// hold per-project settings and kits.
+    fn project(&self) -> Option<&Path> {
+        match &self.command {
+            None => self.play.project.as_deref(),
+            Some(Command::Play(args)) => args.project.as_deref(),
+            Some(Command::Render(args)) => Some(&args.project),
+            Some(Command::ExportMidi(args)) => Some(&args.project),
+            Some(Command::New(args)) => Some(&args.path),
+            Some(Command::Kit { .. } | Command::Keys) => None,
+        }
+    }
+}
+
+/// Run the command line; errors come back for `main` to print.
+pub fn run(cli: Cli) -> Result<()> {
+    // clap would let these through and ignore them, so say so instead
+    let bare = &cli.play;
+    if cli.command.is_some() && (bare.project.is_some() || bare.headless || bare.osc.is_some()) {
+        bail!("a project, --headless and --osc go after `play`, not before a subcommand");
+    }
+    // built-in defaults < user config < project overrides < command line
+    let project_dir = cli.project().and_then(Path::parent);
+    let mut config = Config::load(cli.global.config.as_deref(), project_dir)?;
+    if let Some(device) = cli.global.device {
+        config.device = Some(device);
+    }
+    if let Some(frames) = cli.global.buffer_size {
+        config.buffer_size = Some(frames);
+    }
+    if let Some(Command::New(args)) = &cli.command {
+        config.bpm = args.bpm.unwrap_or(config.bpm);
+        config.steps = args.steps.unwrap_or(config.steps);
+        if let Some(kit) = &args.kit {
+            config.kit = Some(kit.clone());
+        }
+    }
+    config.validate()?;
+    let opts = SessionOptions {
+        sound_dir: cli.global.sound_dir,
+        config,
+    };
+    match cli.command {
+        None => play(cli.play, &opts),
+        Some(Command::Play(args)) => play(args, &opts),
+        Some(Command::Render(args)) => render(args),
+        Some(Command::ExportMidi(args)) => export(args, &opts.config),
+        Some(Command::New(args)) => new_project(args, &opts),
+        Some(Command::Kit {
+            command: KitCommand::List,
+        }) => list_kits(),
+        Some(Command::Keys) => list_keys(&opts.config),
+    }
+}
+
+fn play(args: PlayArgs, opts: &SessionOptions) -> Result<()> {
+    if args.headless {
+        return crate::headless::run(args.project, opts, args.osc);
+    }
+    let mut app = crate::app::App::new(args.project, opts, args.osc)?;
+    app.run()
+}
+
+fn render(args: RenderArgs) -> Result<()> {
+    if !(0.0..=MAX_TAIL_SECS).contains(&args.tail) {
+        bail!("--tail must be 0-{} seconds", MAX_TAIL_SECS);
+    }
+    if !(1..=MAX_BARS).contains(&args.bars) {
+        bail!("--bars must be 1-{}", MAX_BARS);
+    }
+    if !SAMPLE_RATES.contains(&args.sample_rate) {
+        bail!(
+            "--sample-rate must be {}-{} Hz",
+            SAMPLE_RATES.start(),
+            SAMPLE_RATES.end()
+        );
+    }
+    let project = Project::load(&args.project)?;
+    let opts = RenderOptions {
+        bars: args.bars,
+        sample_rate: args.sample_rate,
+        bits_per_sample: args.bits,
+        tail_secs: args.tail,
+        seed: args.seed,
+        slot: args.source.slot()?,
+        song: args.source.song,
+        ..RenderOptions::default()
+    };
+    render_to_wav(&project, &opts, &args.output)
+        .with_context(|| format!("Failed to render {}", args.output.display()))?;
+    println!("Bounced {} bar(s) to {}", args.bars, args.output.display());
+    Ok(())
+}
+
+fn export(args: ExportMidiArgs, config: &Config) -> Result<()> {
+    let project = Project::load(&args.project)?;
+    let mut notes = config.note_map();
+    for (name, note) in &args.notes {
+        notes.set(name, *note);
+    }
+    let opts = MidiOptions {
+        slot: args.source.slot()?,
+        song: args.source.song,
+        notes,
+    };
+    export_midi(&project, &opts, &args.output)?;
+    println!("Wrote {}", args.output.display());
+    Ok(())
+}
+
+fn new_project(args: NewArgs, opts: &SessionOptions) -> Result<()> {
+    if args.path.exists() && !args.force {
+        bail!(
+            "{} already exists (use --force to overwrite)",
+            args.path.display()
+        );
+    }
+    // the config already has the command line's --bpm, --steps and --kit;
+    // project-local kits live next to the new file
+    let project = opts
+        .config
+        .new_project(&opts.sound_dir, args.path.parent())?;
+    project.save(&args.path)?;
+    println!("Created {}", args.path.display());
+    Ok(())
+}
+
+fn list_kits() -> Result<()> {
+    let dirs = kit::search_dirs(None);
+    let found = kit::discover(&dirs);
+    for e in &found.errors {
+        eprintln!("warning: {:#}", e);
+    }
+    if found.kits.is_empty() {
+        let dirs: Vec<String> = dirs.iter().map(|d| d.display().to_string()).collect();
+        println!("No kits found in {}", dirs.join(", "));
+    }
+    for kit in &found.kits {
+        println!(
+            "{:<16} {:>2} tracks  {}",
+            kit.name,
+            kit.tracks.len(),
+            kit.dir.display()
+        );
+    }
+    Ok(())
+}
+
+fn list_keys(config: &Config) -> Result<()> {
+    for (_, action, keys) in config.keymap()?.describe() {
+        println!("{:<20} {:<12} {}", action.id(), keys, action.help());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn parse(args: &[&str]) -> Cli {
+        Cli::try_parse_from(std::iter::once("cratebeat").chain(args.iter().copied())).unwrap()
+    }
+
+    #[test]
+    fn bare_project_path_still_opens_the_sequencer() {
+        let cli = parse(&["beat.cbt", "--osc", "9000"]);
+        assert!(cli.command.is_none());
+        assert_eq!(cli.play.project, Some(PathBuf::from("beat.cbt")));
+        assert_eq!(cli.play.osc, Some(9000));
+        assert_eq!(cli.global.sound_dir, PathBuf::from("sounds"));
+    }
+
+    #[test]
+    fn subcommands_take_global_flags_anywhere() {
+        let cli = parse(&[
+            "render",
+            "beat.cbt",
+            "-o",
+            "out.wav",
+            "--bars",
+            "8",
+            "--sound-dir",
+            "kits/808",
+            "--tail",
+            "2.5",
+        ]);
+        let Some(Command::Render(args)) = cli.command else {
+            panic!("not a render: {:?}", cli.command);
+        };
+        assert_eq!(args.bars, 8);
+        assert_eq!(args.tail, 2.5);
+        assert_eq!(args.output, PathBuf::from("out.wav"));
+        assert_eq!(args.source.slot().unwrap(), 0);
+        assert_eq!(cli.global.sound_dir, PathBuf::from("kits/808"));
+
+        let cli = parse(&["--device", "USB", "kit", "list"]);
+        assert!(matches!(cli.command, Some(Command::Kit { .. })));
+        assert_eq!(cli.global.device.as_deref(), Some("USB"));
+
+        let err = Cli::try_parse_from(["cratebeat", "render", "beat.cbt"]).unwrap_err();
+        assert!(err.to_string().contains("--output"));
+        let err = run(parse(&["--headless", "kit", "list"])).unwrap_err();
+        assert!(err.to_string().contains("after `play`"));
+
+        // an empty config and a project in its own directory, so neither the
+        // user's config nor one in the working directory gets read
+        let dir = std::env::temp_dir().join(format!("cratebeat-cli-render-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("config.toml");
+        fs::write(&config, "").unwrap();
+        let project = dir.join("beat.cbt");
+        let render = |flag: &str, value: &str| {
+            let (project, config) = (project.to_str().unwrap(), config.to_str().unwrap());
+            let args = [
+                "render", project, "-o", "x.wav", "--config", config, flag, value,
+            ];
+            run(parse(&args)).unwrap_err().to_string()
+        };
+        assert_eq!(render("--tail", "90"), "--tail must be 0-60 seconds");
+        assert_eq!(render("--bars", "0"), "--bars must be 1-999");
+        assert_eq!(
+            render("--sample-rate", "0"),
+            "--sample-rate must be 8000-192000 Hz"
+        );
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn new_layers_config_files_and_flags_and_refuses_to_clobber() {
+        let dir = std::env::temp_dir().join(format!("cratebeat-cli-new-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let user = dir.join("user.toml");
+        fs::write(&user, "bpm = 100\nsteps = 16\n").unwrap();
+        fs::write(dir.join(crate::config::PROJECT_FILE), "steps = 12\n").unwrap();
+        let path = dir.join("beat.cbt");
+        let new = |extra: &[&str]| {
+            let mut args = vec!["new", path.to_str().unwrap(), "--bpm", "90"];
+            args.extend(["--sound-dir", "kit", "--config", user.to_str().unwrap()]);
+            args.extend(extra);
+            run(parse(&args))
+        };
+
+        new(&[]).unwrap();
+        let project = Project::load(&path).unwrap();
+        // --bpm beats the user config; the project's overrides beat it too
+        assert_eq!((project.bpm, project.steps), (90.0, 12));
+        assert_eq!(project.tracks[0].sample, "kit/kick.wav");
+
+        let err = new(&[]).unwrap_err();
+        assert!(format!("{:#}", err).contains("already exists"));
+        new(&["--force"]).unwrap();
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn export_uses_the_configured_note_map() {
+        let dir = std::env::temp_dir().join(format!("cratebeat-cli-export-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let user = dir.join("user.toml");
+        fs::write(&user, "[midi.notes]\nkick = 35\nsnare = 37\n").unwrap();
+        let path = dir.join("beat.cbt");
+        let out = dir.join("beat.mid");
+        Project::default().save(&path).unwrap();
+        let args = [
+            "export-midi",
+            path.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+            "--note",
+            "snare=40",
+            "--config",
+            user.to_str().unwrap(),
+        ];
+        run(parse(&args)).unwrap();
+
+        // the first note-on of each sequencer track
+        let bytes = fs::read(&out).unwrap();
+        let smf = midly::Smf::parse(&bytes).unwrap();
+        let keys: Vec<Option<u8>> = smf.tracks[1..]
+            .iter()
+            .map(|track| {
+                track.iter().find_map(|event| match event.kind {
+                    midly::TrackEventKind::Midi {
+                        message: midly::MidiMessage::NoteOn { key, .. },
+                        ..
+                    } => Some(key.as_int()),
+                    _ => None,
+                })
+            })
+            .collect();
+        // kick from the config, snare from the flag, the hat from the GM map
+        assert_eq!(keys[..3], [Some(35), Some(40), Some(42)]);
+        assert!(Cli::try_parse_from([
+            "cratebeat",
+            "export-midi",
+            "b",
+            "-o",
+            "x",
+            "--note",
+            "kick"
+        ])
+        .is_err());
+        fs::remove_dir_all(dir).ok();
+    }
+}
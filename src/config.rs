@@ -1,14 +1,415 @@
 // Config handling
-// Simple placeholder for future config handling.
-// For the v1 starter we keep defaults in code.
+// User settings from `~/.config/cratebeat/config.toml`, overridden by a
+// `cratebeat.toml` next to the project and then by command-line flags.
+use std::collections::BTreeMap;
+use std::fs;
+use std::ops::{Range, RangeInclusive};
+use std::path::{Path, PathBuf};
 
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use toml::Spanned;
+
+use crate::kit;
+use crate::midi::NoteMap;
+use crate::project::Project;
+use crate::sequencer::clock::{MAX_BPM, MIN_BPM};
+use crate::sequencer::steps::MAX_STEPS;
+use crate::ui::input::{parse_binding, Conflict, Keymap};
+use crate::ui::layout::Theme;
+
+/// Per-project overrides, looked for in the project's directory.
+pub const PROJECT_FILE: &str = "cratebeat.toml";
+/// Device buffer sizes accepted, in frames.
+const BUFFER_FRAMES: RangeInclusive<u32> = 16..=16_384;
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Config {
-    pub bpm: u32,
+    /// Tempo of new projects; may be fractional
+    pub bpm: f64,
+    /// Pattern length of new projects
     pub steps: usize,
+    /// Kit new projects start on, instead of the sound directory's samples
+    pub kit: Option<String>,
+    /// Output device name; the system default if unset
+    pub device: Option<String>,
+    /// Fixed device buffer in frames; the device's choice if unset
+    pub buffer_size: Option<u32>,
+    /// Colour scheme of the TUI
+    pub theme: String,
+    /// Rebound actions: action id (`grid.toggle`) to all of its keys
+    pub keys: BTreeMap<String, Vec<String>>,
+    /// MIDI note per track name for export and import, on top of the GM
+    /// drum map
+    pub midi_notes: BTreeMap<String, u8>,
 }
 
 impl Default for Config {
     fn default() -> Self {
-        Self { bpm: 120, steps: 8 }
+        Self {
+            bpm: 120.0,
+            steps: 8,
+            kit: None,
+            device: None,
+            buffer_size: None,
+            theme: Theme::DEFAULT.to_string(),
+            keys: BTreeMap::new(),
+            midi_notes: BTreeMap::new(),
+        }
+    }
+}
+
+/// One config file as written: every setting optional, with where it was
+/// written so errors can point at the line.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Layer {
+    bpm: Option<Spanned<f64>>,
+    steps: Option<Spanned<usize>>,
+    kit: Option<Spanned<String>>,
+    device: Option<Spanned<String>>,
+    buffer_size: Option<Spanned<u32>>,
+    theme: Option<Spanned<String>>,
+    /// `[keys.grid]` and so on: action name to one key or a list of them
+    keys: Option<BTreeMap<String, BTreeMap<String, Spanned<KeyList>>>>,
+    midi: Option<MidiLayer>,
+}
+
+/// `[midi]`: for now just `[midi.notes]`, track name to note number.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MidiLayer {
+    notes: Option<BTreeMap<String, Spanned<i64>>>,
+}
+
+/// `toggle = "x"` or `toggle = ["x", "g t"]`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum KeyList {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl KeyList {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            KeyList::One(key) => vec![key],
+            KeyList::Many(keys) => keys,
+        }
+    }
+}
+
+/// `$XDG_CONFIG_HOME/cratebeat/config.toml`, falling back to `~/.config`.
+pub fn user_path() -> Option<PathBuf> {
+    let xdg = std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from);
+    let dir = match xdg {
+        Some(dir) if dir.is_absolute() => dir,
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("cratebeat").join("config.toml"))
+}
+
+impl Config {
+    /// Defaults, then the user config (`path`, which must exist, or the
+    /// usual location if there is one), then the project's overrides.
+    pub fn load(path: Option<&Path>, project_dir: Option<&Path>) -> Result<Self> {
+        let mut config = Self::default();
+        match path {
+            Some(path) => config.merge_file(path)?,
+            None => {
+                if let Some(path) = user_path().filter(|p| p.is_file()) {
+                    config.merge_file(&path)?;
+                }
+            }
+        }
+        let project_dir = project_dir
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let overrides = project_dir.join(PROJECT_FILE);
+        if overrides.is_file() {
+            config.merge_file(&overrides)?;
+        }
+        Ok(config)
+    }
+
+    /// Apply the settings in the file at `path` on top of these.
+    pub fn merge_file(&mut self, path: &Path) -> Result<()> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config: {}", path.display()))?;
+        self.merge_str(&text, path)
+    }
+
+    /// Apply `text`, read from `path`; nothing changes if any of it is invalid.
+    fn merge_str(&mut self, text: &str, path: &Path) -> Result<()> {
+        // `file:line: message`, the way compilers point at a mistake
+        let located = |span: Option<Range<usize>>, message: &dyn std::fmt::Display| {
+            let (line, col) = span.map_or((1, 1), |span| line_col(text, span.start));
+            anyhow!("{}:{}:{}: {}", path.display(), line, col, message)
+        };
+        let layer: Layer = toml::from_str(text).map_err(|e| located(e.span(), &e.message()))?;
+        let check = |span: Range<usize>, result: Result<()>| {
+            result.map_err(|e| located(Some(span), &format!("{:#}", e)))
+        };
+
+        let mut config = self.clone();
+        if let Some(bpm) = layer.bpm {
+            check(bpm.span(), check_bpm(*bpm.get_ref()))?;
+            config.bpm = bpm.into_inner();
+        }
+        if let Some(steps) = layer.steps {
+            check(steps.span(), check_steps(*steps.get_ref()))?;
+            config.steps = steps.into_inner();
+        }
+        if let Some(kit) = layer.kit {
+            check(kit.span(), check_name("kit", kit.get_ref()))?;
+            config.kit = Some(kit.into_inner());
+        }
+        if let Some(device) = layer.device {
+            check(device.span(), check_name("device", device.get_ref()))?;
+            config.device = Some(device.into_inner());
+        }
+        if let Some(frames) = layer.buffer_size {
+            check(frames.span(), check_buffer_size(*frames.get_ref()))?;
+            config.buffer_size = Some(frames.into_inner());
+        }
+        if let Some(theme) = layer.theme {
+            check(theme.span(), check_theme(theme.get_ref()))?;
+            config.theme = theme.into_inner();
+        }
+        if let Some(tables) = layer.keys {
+            // each action's keys replace the earlier layers' for that action only
+            let mut spans = BTreeMap::new();
+            for (context, actions) in tables {
+                for (action, keys) in actions {
+                    let id = format!("{}.{}", context, action);
+                    let span = keys.span();
+                    let keys = keys.into_inner().into_vec();
+                    check(span.clone(), parse_binding(&id, &keys).map(drop))?;
+                    config.keys.insert(id.clone(), keys);
+                    spans.insert(id, span);
+                }
+            }
+            if let Err(e) = config.keymap() {
+                // point at whichever of the clashing bindings this file wrote
+                let span = e.downcast_ref::<Conflict>().and_then(|c| {
+                    [&c.second.0, &c.first.0]
+                        .into_iter()
+                        .find_map(|action| spans.get(&action.id()).cloned())
+                });
+                return Err(located(span, &e));
+            }
+        }
+        if let Some(notes) = layer.midi.and_then(|midi| midi.notes) {
+            for (name, note) in notes {
+                let span = note.span();
+                check(span.clone(), check_name("track name", &name))?;
+                let note = *note.get_ref();
+                check(span, check_note(&name, note))?;
+                config.midi_notes.insert(name.to_lowercase(), note as u8);
+            }
+        }
+        *self = config;
+        Ok(())
+    }
+
+    /// Check settings that didn't come from a file, such as command-line flags.
+    pub fn validate(&self) -> Result<()> {
+        check_bpm(self.bpm)?;
+        check_steps(self.steps)?;
+        if let Some(kit) = &self.kit {
+            check_name("kit", kit)?;
+        }
+        if let Some(device) = &self.device {
+            check_name("device", device)?;
+        }
+        if let Some(frames) = self.buffer_size {
+            check_buffer_size(frames)?;
+        }
+        check_theme(&self.theme)?;
+        for (name, &note) in &self.midi_notes {
+            check_note(name, note as i64)?;
+        }
+        self.keymap().map(drop)
+    }
+
+    /// The GM drum map with this config's `[midi.notes]` on top.
+    pub fn note_map(&self) -> NoteMap {
+        let mut notes = NoteMap::default();
+        for (name, &note) in &self.midi_notes {
+            notes.set(name, note);
+        }
+        notes
+    }
+
+    /// The default key bindings with this config's `[keys]` applied.
+    pub fn keymap(&self) -> Result<Keymap> {
+        Keymap::with_overrides(&self.keys)
+    }
+
+    /// The starter beat at this config's tempo and length, on its kit if it
+    /// has one. Project-local kits are looked for in `project_dir`.
+    pub fn new_project(&self, sound_dir: &Path, project_dir: Option<&Path>) -> Result<Project> {
+        let mut project = Project::starter(sound_dir);
+        project.bpm = self.bpm;
+        project.resize(self.steps);
+        if let Some(name) = &self.kit {
+            project.set_kit(&kit::find(&kit::search_dirs(project_dir), name)?);
+        }
+        Ok(project)
+    }
+}
+
+fn check_bpm(bpm: f64) -> Result<()> {
+    if !(MIN_BPM..=MAX_BPM).contains(&bpm) {
+        bail!("bpm must be {}-{}, not {}", MIN_BPM, MAX_BPM, bpm);
+    }
+    Ok(())
+}
+
+fn check_steps(steps: usize) -> Result<()> {
+    if !(1..=MAX_STEPS).contains(&steps) {
+        bail!("steps must be 1-{}, not {}", MAX_STEPS, steps);
+    }
+    Ok(())
+}
+
+fn check_buffer_size(frames: u32) -> Result<()> {
+    if !BUFFER_FRAMES.contains(&frames) {
+        bail!(
+            "buffer_size must be {}-{} frames, not {}",
+            BUFFER_FRAMES.start(),
+            BUFFER_FRAMES.end(),
+            frames
+        );
+    }
+    Ok(())
+}
+
+fn check_name(what: &str, name: &str) -> Result<()> {
+    if name.trim().is_empty() {
+        bail!("{} can't be empty", what);
+    }
+    Ok(())
+}
+
+fn check_note(name: &str, note: i64) -> Result<()> {
+    if !(0..=127).contains(&note) {
+        bail!("MIDI note for `{}` must be 0-127, not {}", name, note);
+    }
+    Ok(())
+}
+
+fn check_theme(name: &str) -> Result<()> {
+    if Theme::named(name).is_none() {
+        bail!("unknown theme `{}` (try {})", name, Theme::NAMES.join(", "));
+    }
+    Ok(())
+}
+
+/// 1-based line and column of byte `offset` in `text`.
+pub(crate) fn line_col(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let col = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+    (line, col)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merged(files: &[&str]) -> Result<Config> {
+        let mut config = Config::default();
+        for (i, text) in files.iter().enumerate() {
+            config.merge_str(text, Path::new(&format!("{}.toml", i)))?;
+        }
+        Ok(config)
+    }
+
+    #[test]
+    fn later_files_override_earlier_ones_setting_by_setting() {
+        let user = "bpm = 90\nsteps = 16\ndevice = \"USB\"\ntheme = \"mono\"\n";
+        let project = "bpm = 140.5\nkit = \"808\"\n";
+        let config = merged(&[user, project]).unwrap();
+        assert_eq!(
+            config,
+            Config {
+                bpm: 140.5,
+                steps: 16,
+                kit: Some("808".to_string()),
+                device: Some("USB".to_string()),
+                buffer_size: None,
+                theme: "mono".to_string(),
+                keys: BTreeMap::new(),
+                midi_notes: BTreeMap::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn errors_point_at_the_offending_line() {
+        let err = |text| format!("{:#}", merged(&[text]).unwrap_err());
+        assert_eq!(
+            err("steps = 16\n\nbpm = 999\n"),
+            "0.toml:3:7: bpm must be 20-300, not 999"
+        );
+        assert_eq!(
+            err("bpm = 100\ntempo = 100\n"),
+            "0.toml:2:1: unknown field `tempo`, expected one of `bpm`, `steps`, `kit`, `device`, `buffer_size`, `theme`, `keys`, `midi`"
+        );
+        assert!(err("steps = \"eight\"").starts_with("0.toml:1:9: invalid type"));
+        assert!(err("theme = \"neon\"").starts_with("0.toml:1:9: unknown theme `neon`"));
+
+        // a bad file leaves the settings as they were
+        let mut config = Config::default();
+        assert!(config
+            .merge_str("bpm = 100\nsteps = 0\n", Path::new("x"))
+            .is_err());
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn keys_are_rebound_per_action_and_checked() {
+        let user = "[keys.global]\nplay = \"space\"\n\n[keys.grid]\ntoggle = [\"x\", \"g t\"]\n";
+        let project = "[keys.grid]\ntoggle = \"t\"\n";
+        let config = merged(&[user, project]).unwrap();
+        assert_eq!(config.keys["global.play"], ["space"]);
+        assert_eq!(config.keys["grid.toggle"], ["t"]);
+
+        let err = |text| format!("{:#}", merged(&[text]).unwrap_err());
+        assert_eq!(
+            err("[keys.grid]\nmute = \"p\"\n"),
+            "0.toml:2:8: `p` is bound to both global.play and grid.mute"
+        );
+        assert_eq!(
+            err("[keys.grid]\njump = \"j\"\n"),
+            "0.toml:2:8: unknown action `jump` in [keys.grid]"
+        );
+        assert_eq!(
+            err("[keys.mixer]\nmute = \"m\"\n"),
+            "0.toml:2:8: unknown key table [keys.mixer]"
+        );
+        assert_eq!(
+            err("[keys.grid]\nmute = \"hyper-m\"\n"),
+            "0.toml:2:8: unknown key `hyper-m`"
+        );
+    }
+
+    #[test]
+    fn midi_notes_extend_the_drum_map() {
+        let user = "[midi.notes]\nkick = 35\nCowbell = 56\n";
+        let project = "[midi.notes]\nkick = 36\n";
+        let config = merged(&[user, project]).unwrap();
+        let notes = config.note_map();
+        assert_eq!(notes.note("kick", 0), 36);
+        assert_eq!(notes.note("cowbell", 4), 56);
+        assert_eq!(notes.note("snare", 1), 38);
+
+        let err = |text| format!("{:#}", merged(&[text]).unwrap_err());
+        assert_eq!(
+            err("[midi.notes]\nkick = 128\n"),
+            "0.toml:2:8: MIDI note for `kick` must be 0-127, not 128"
+        );
+        assert!(err("[midi]\nchannel = 10\n").starts_with("0.toml:2:1: unknown field `channel`"));
     }
 }
@@ -0,0 +1,362 @@
+// Headless mode
+// Runs a session without the TUI, driven by commands on stdin and/or the OSC
+// control socket, for servers, background panes and scripted tests.
+use std::io::BufRead;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::render::parse_bounce_args;
+use crate::sequencer::clock::{MAX_SWING, MIN_SWING};
+use crate::sequencer::engine::{SequencerCommand, SequencerEvent};
+use crate::sequencer::song::{slot_name, BANK_SLOTS};
+use crate::session::{Session, SessionOptions};
+
+/// How often engine events are drained while waiting for input.
+const POLL: Duration = Duration::from_millis(16);
+
+/// One line of input.
+#[derive(Debug)]
+enum Control {
+    Engine(SequencerCommand),
+    Save(Option<String>),
+    Open(String),
+    Bounce(String, u32),
+    ExportMidi(String, bool),
+    ImportMidi(String),
+    Osc(u16),
+    Kits,
+    Kit(String),
+    Quit,
+}
+
+/// Run until `quit`, or until stdin closes while no control socket is open.
+pub fn run(project: Option<PathBuf>, opts: &SessionOptions, osc_port: Option<u16>) -> Result<()> {
+    let mut session = Session::new(project, opts)?;
+    if let Some(warning) = session.warning() {
+        eprintln!("warning: {}", warning);
+    }
+    session.apply_project();
+    print_sample_warnings(&session);
+    if let Some(port) = osc_port {
+        println!("OSC listening on {}", session.start_osc(port)?);
+    }
+
+    let mut input = Some(read_lines());
+    // last transport line printed, to only report changes
+    let mut transport = String::new();
+    loop {
+        for ev in session.poll_events() {
+            match ev {
+                SequencerEvent::State(state) => {
+                    let status = if state.playing { "playing" } else { "stopped" };
+                    let line = format!("{} {} BPM {}", status, state.bpm, slot_name(state.slot));
+                    if line != transport {
+                        println!("{}", line);
+                        transport = line;
+                    }
+                }
+                SequencerEvent::PatternSwitched { slot } => {
+                    println!("now playing {}", slot_name(slot));
+                }
+                SequencerEvent::Step { .. }
+                | SequencerEvent::Bank(_)
+                | SequencerEvent::TrackInserted { .. }
+                | SequencerEvent::TrackRemoved { .. } => {}
+            }
+        }
+        for error in session.take_errors() {
+            eprintln!("error: {}", error);
+        }
+
+        let Some(lines) = &input else {
+            // stdin is gone; the OSC socket keeps the session alive
+            thread::sleep(POLL);
+            continue;
+        };
+        let line = match lines.recv_timeout(POLL) {
+            Ok(line) => line,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => {
+                if !session.has_osc() {
+                    break;
+                }
+                input = None;
+                continue;
+            }
+        };
+        let result = match parse_line(&line) {
+            Ok(Some(Control::Quit)) => break,
+            Ok(Some(control)) => execute(&mut session, control),
+            Ok(None) => Ok(None),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(Some(message)) => println!("{}", message),
+            Ok(None) => {}
+            Err(e) => eprintln!("error: {:#}", e),
+        }
+    }
+
+    session.stop();
+    Ok(())
+}
+
+/// Lines from stdin on a channel that closes at end of input.
+fn read_lines() -> Receiver<String> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// Every sample that failed to load, one line each.
+fn print_sample_warnings(session: &Session) {
+    for warning in session.sample_warnings() {
+        eprintln!("warning: {}", warning);
+    }
+}
+
+fn execute(session: &mut Session, control: Control) -> Result<Option<String>> {
+    let message = match control {
+        Control::Engine(cmd) => {
+            session.send(cmd);
+            return Ok(None);
+        }
+        Control::Save(path) => session.save_project(path)?,
+        Control::Open(path) => {
+            let message = session.open_project(path)?;
+            print_sample_warnings(session);
+            message
+        }
+        Control::Bounce(path, bars) => session.bounce(path, bars)?,
+        Control::ExportMidi(path, song) => session.export_midi(path, song)?,
+        Control::ImportMidi(path) => session.import_midi(path)?,
+        Control::Osc(port) => format!("OSC listening on {}", session.start_osc(port)?),
+        Control::Kits => {
+            let found = session.discover_kits();
+            for e in &found.errors {
+                eprintln!("warning: {:#}", e);
+            }
+            if found.kits.is_empty() {
+                "No kits found".to_string()
+            } else {
+                let lines: Vec<String> = found
+                    .kits
+                    .iter()
+                    .map(|kit| format!("{} ({} tracks)", kit.name, kit.tracks.len()))
+                    .collect();
+                lines.join("\n")
+            }
+        }
+        Control::Kit(name) => {
+            let message = session.load_kit(&name)?;
+            print_sample_warnings(session);
+            message
+        }
+        Control::Quit => return Ok(None),
+    };
+    Ok(Some(message))
+}
+
+/// Parse one command line. The `:` commands of the TUI work too, with or
+/// without the colon; tracks, steps and patterns count from 1 like on screen.
+/// Blank lines and `#` comments do nothing.
+fn parse_line(line: &str) -> Result<Option<Control>> {
+    let line = line.trim();
+    let line = line.strip_prefix(':').unwrap_or(line);
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let mut words = line.split_whitespace();
+    let name = words.next().unwrap_or_default();
+    let args: Vec<&str> = words.collect();
+    // the rest of the line after the command, for paths with spaces
+    let rest = line[name.len()..].trim();
+
+    let arg = |i: usize, what: &str| -> Result<&str> {
+        args.get(i)
+            .copied()
+            .ok_or_else(|| anyhow!("{} needs a {}", name, what))
+    };
+    let number = |i: usize, what: &str| -> Result<u32> {
+        let arg = arg(i, what)?;
+        arg.parse()
+            .with_context(|| format!("{}: bad {} {:?}", name, what, arg))
+    };
+    // a 1-based position on screen, as a 0-based index
+    let position = |i: usize, what: &str| -> Result<usize> {
+        match number(i, what)? {
+            0 => bail!("{}: {} count from 1", name, what),
+            n => Ok(n as usize - 1),
+        }
+    };
+    let on_off = |what: &str| -> Result<bool> {
+        match args.first().copied() {
+            Some("on") => Ok(true),
+            Some("off") => Ok(false),
+            _ => bail!("usage: {} on|off", what),
+        }
+    };
+    let path = || -> Result<String> {
+        if rest.is_empty() {
+            bail!("{} needs a file name", name);
+        }
+        Ok(rest.to_string())
+    };
+
+    let engine = |cmd| Ok(Some(Control::Engine(cmd)));
+    match name {
+        "play" => engine(SequencerCommand::Play),
+        "stop" => engine(SequencerCommand::Stop),
+        "toggle" | "p" => engine(SequencerCommand::TogglePlay),
+        "bpm" => {
+            // tempos can be fractional, unlike the other numbers
+            let tempo = arg(0, "tempo")?;
+            let bpm = tempo
+                .parse()
+                .with_context(|| format!("{}: bad tempo {:?}", name, tempo))?;
+            engine(SequencerCommand::SetBpm(bpm))
+        }
+        "swing" => {
+            let swing = number(0, "percentage")?;
+            if !(MIN_SWING as u32..=MAX_SWING as u32).contains(&swing) {
+                bail!("Swing must be {}-{}%", MIN_SWING, MAX_SWING);
+            }
+            engine(SequencerCommand::SetSwing(swing as u8))
+        }
+        // track, step, velocity (0 clears the step)
+        "step" => engine(SequencerCommand::SetVelocity {
+            track: position(0, "track")?,
+            step: position(1, "step")?,
+            velocity: number(2, "velocity")?.min(127) as u8,
+        }),
+        "pat" => {
+            let slot = position(0, "pattern")?;
+            if slot >= BANK_SLOTS {
+                bail!("Patterns are 1-{}", BANK_SLOTS);
+            }
+            let after = match args.len() {
+                1 => None,
+                _ => Some(number(1, "step count")?.max(1) as usize),
+            };
+            engine(SequencerCommand::QueuePattern { slot, after })
+        }
+        "song" => engine(SequencerCommand::SetSongMode(on_off("song")?)),
+        "fill" => engine(SequencerCommand::SetFill(on_off("fill")?)),
+        "undo" | "u" => engine(SequencerCommand::Undo),
+        "redo" => engine(SequencerCommand::Redo),
+        "w" => Ok(Some(Control::Save(
+            (!rest.is_empty()).then(|| rest.to_string()),
+        ))),
+        "e" => Ok(Some(Control::Open(path()?))),
+        "bounce" => {
+            path()?;
+            let (file, bars) = parse_bounce_args(rest).map_err(|e| anyhow!("{}: {}", name, e))?;
+            Ok(Some(Control::Bounce(file, bars)))
+        }
+        "midi" => match rest.rsplit_once(char::is_whitespace) {
+            Some((file, "song")) => Ok(Some(Control::ExportMidi(file.trim().to_string(), true))),
+            _ => Ok(Some(Control::ExportMidi(path()?, false))),
+        },
+        "import" => Ok(Some(Control::ImportMidi(path()?))),
+        "kits" => Ok(Some(Control::Kits)),
+        "kit" => match rest {
+            "" => Ok(Some(Control::Kits)),
+            name => Ok(Some(Control::Kit(name.to_string()))),
+        },
+        "osc" => match args.first() {
+            None => Ok(Some(Control::Osc(crate::osc::DEFAULT_PORT))),
+            Some(port) => match port.parse() {
+                Ok(port) => Ok(Some(Control::Osc(port))),
+                Err(_) => bail!("osc: port must be 0-{}, not {:?}", u16::MAX, port),
+            },
+        },
+        "q" | "quit" => Ok(Some(Control::Quit)),
+        _ => bail!("unknown command: {}", name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn engine(line: &str) -> SequencerCommand {
+        match parse_line(line) {
+            Ok(Some(Control::Engine(cmd))) => cmd,
+            other => panic!("{:?} parsed as {:?}", line, other),
+        }
+    }
+
+    #[test]
+    fn lines_map_onto_engine_commands() {
+        assert!(matches!(engine("play"), SequencerCommand::Play));
+        assert!(matches!(engine(":bpm 96"), SequencerCommand::SetBpm(bpm) if bpm == 96.0));
+        assert!(matches!(engine("bpm 92.5"), SequencerCommand::SetBpm(bpm) if bpm == 92.5));
+        assert!(matches!(
+            engine("step 2 5 127"),
+            SequencerCommand::SetVelocity {
+                track: 1,
+                step: 4,
+                velocity: 127
+            }
+        ));
+        assert!(matches!(
+            engine("pat 3 8"),
+            SequencerCommand::QueuePattern {
+                slot: 2,
+                after: Some(8)
+            }
+        ));
+        assert!(matches!(
+            engine("song on"),
+            SequencerCommand::SetSongMode(true)
+        ));
+        assert!(matches!(engine(":u"), SequencerCommand::Undo));
+    }
+
+    #[test]
+    fn file_commands_keep_spaces_in_paths() {
+        assert!(matches!(
+            parse_line("bounce my loop.wav 4"),
+            Ok(Some(Control::Bounce(path, 4))) if path == "my loop.wav"
+        ));
+        assert!(parse_line("bounce loop.wav 0").is_err());
+        assert!(parse_line("bounce").is_err());
+        assert!(matches!(
+            parse_line("midi out.mid song"),
+            Ok(Some(Control::ExportMidi(path, true))) if path == "out.mid"
+        ));
+        assert!(matches!(parse_line("w"), Ok(Some(Control::Save(None)))));
+        assert!(matches!(parse_line("  # comment"), Ok(None)));
+        assert!(matches!(parse_line("quit"), Ok(Some(Control::Quit))));
+    }
+
+    #[test]
+    fn bad_lines_explain_themselves() {
+        let err = |line| format!("{:#}", parse_line(line).unwrap_err());
+        assert_eq!(err("step 0 1 100"), "step: track count from 1");
+        assert_eq!(
+            err("bpm fast"),
+            "bpm: bad tempo \"fast\": invalid float literal"
+        );
+        assert_eq!(err("pat 17"), "Patterns are 1-16");
+        assert_eq!(err("e"), "e needs a file name");
+        assert_eq!(err("dance"), "unknown command: dance");
+        // ports past 65535 are refused rather than wrapped
+        assert_eq!(err("osc 70000"), "osc: port must be 0-65535, not \"70000\"");
+        assert!(matches!(
+            parse_line("osc 9001"),
+            Ok(Some(Control::Osc(9001)))
+        ));
+    }
+}
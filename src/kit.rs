@@ -0,0 +1,269 @@
+// Sample kits
+// Named sets of samples described by a `kit.toml` manifest, found next to the
+// project and in the user's data directory.
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::sequencer::steps::MAX_TRACKS;
+
+/// File that marks a directory as a kit.
+pub const MANIFEST: &str = "kit.toml";
+/// Loudest default gain a manifest may ask for (about +12 dB).
+const MAX_GAIN: f64 = 4.0;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    /// Defaults to the kit's directory name
+    name: Option<String>,
+    tracks: Vec<KitTrack>,
+}
+
+/// One sample in a kit, with the mixer settings it starts with.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KitTrack {
+    pub name: String,
+    /// Relative to the kit directory, or absolute
+    pub sample: PathBuf,
+    #[serde(default = "unity")]
+    pub gain: f64,
+    #[serde(default)]
+    pub pan: f64,
+    /// Tracks in the same choke group cut each other off, like open and closed hats
+    #[serde(default)]
+    pub choke: Option<u8>,
+}
+
+fn unity() -> f64 {
+    1.0
+}
+
+#[derive(Debug, Clone)]
+pub struct Kit {
+    pub name: String,
+    /// Directory holding the manifest; sample paths are relative to it
+    pub dir: PathBuf,
+    pub tracks: Vec<KitTrack>,
+}
+
+impl Kit {
+    /// Read the manifest in `dir`.
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join(MANIFEST);
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read kit: {}", path.display()))?;
+        let manifest: Manifest =
+            toml::from_str(&text).with_context(|| format!("Invalid kit: {}", path.display()))?;
+        let name = match manifest.name {
+            Some(name) => name,
+            None => dir
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        };
+        let kit = Self {
+            name,
+            dir: dir.to_path_buf(),
+            tracks: manifest.tracks,
+        };
+        kit.validate()
+            .with_context(|| format!("Invalid kit: {}", path.display()))?;
+        Ok(kit)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.name.trim().is_empty() {
+            bail!("kit needs a name");
+        }
+        if !(1..=MAX_TRACKS).contains(&self.tracks.len()) {
+            bail!(
+                "a kit needs 1-{} tracks, got {}",
+                MAX_TRACKS,
+                self.tracks.len()
+            );
+        }
+        for track in &self.tracks {
+            if !(0.0..=MAX_GAIN).contains(&track.gain) {
+                bail!("track `{}`: gain must be 0-{}", track.name, MAX_GAIN);
+            }
+            if !(-1.0..=1.0).contains(&track.pan) {
+                bail!("track `{}`: pan must be -1 to 1", track.name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Where `track`'s sample lives, as stored in a project.
+    pub fn sample_path(&self, track: &KitTrack) -> String {
+        tidy(&self.dir.join(&track.sample))
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+/// `path` without `.` components, and with `dir/..` pairs folded away,
+/// so project files get `sounds/kick.wav` rather than `./kits/x/../../sounds/kick.wav`.
+fn tidy(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for part in path.components() {
+        match part {
+            Component::CurDir => {}
+            Component::ParentDir
+                if matches!(out.components().next_back(), Some(Component::Normal(_))) =>
+            {
+                out.pop();
+            }
+            part => out.push(part),
+        }
+    }
+    out
+}
+
+/// Directories searched for kits, most local first: `kits/` next to the
+/// project (or in the working directory), then the user's data directory.
+pub fn search_dirs(project_dir: Option<&Path>) -> Vec<PathBuf> {
+    let local = project_dir
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let mut dirs = vec![local.join("kits")];
+    if let Some(data) = data_dir() {
+        dirs.push(data.join("cratebeat").join("kits"));
+    }
+    dirs
+}
+
+/// `$XDG_DATA_HOME`, falling back to `~/.local/share`.
+fn data_dir() -> Option<PathBuf> {
+    let xdg = std::env::var_os("XDG_DATA_HOME").map(PathBuf::from);
+    match xdg {
+        Some(dir) if dir.is_absolute() => Some(dir),
+        _ => std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")),
+    }
+}
+
+/// What turned up in the kit directories.
+#[derive(Debug, Default)]
+pub struct Discovery {
+    /// Sorted by name; a kit in an earlier directory hides one of the same name later on
+    pub kits: Vec<Kit>,
+    /// Manifests that could not be read, so they can be reported rather than skipped
+    pub errors: Vec<anyhow::Error>,
+}
+
+/// Every kit under `dirs`, one per subdirectory with a manifest.
+/// Directories that don't exist are skipped.
+pub fn discover(dirs: &[PathBuf]) -> Discovery {
+    let mut found = Discovery::default();
+    for dir in dirs {
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue;
+        };
+        let mut kit_dirs: Vec<PathBuf> = entries
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|path| path.join(MANIFEST).is_file())
+            .collect();
+        kit_dirs.sort();
+        for kit_dir in kit_dirs {
+            match Kit::load(&kit_dir) {
+                Ok(kit) if found.kits.iter().any(|k| k.name == kit.name) => {}
+                Ok(kit) => found.kits.push(kit),
+                Err(e) => found.errors.push(e),
+            }
+        }
+    }
+    found.kits.sort_by(|a, b| a.name.cmp(&b.name));
+    found
+}
+
+/// The kit called `name` in `dirs`.
+pub fn find(dirs: &[PathBuf], name: &str) -> Result<Kit> {
+    let found = discover(dirs);
+    if let Some(kit) = found.kits.into_iter().find(|k| k.name == name) {
+        return Ok(kit);
+    }
+    if let Some(e) = found.errors.into_iter().next() {
+        // a broken manifest may well be the one that was meant
+        return Err(e.context(format!("No kit named `{}`", name)));
+    }
+    bail!("No kit named `{}`", name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_kit(root: &Path, dir: &str, manifest: &str) {
+        let dir = root.join(dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(MANIFEST), manifest).unwrap();
+    }
+
+    #[test]
+    fn local_kits_hide_user_kits_and_bad_manifests_are_reported() {
+        let root =
+            std::env::temp_dir().join(format!("cratebeat-kit-discover-{}", std::process::id()));
+        fs::remove_dir_all(&root).ok();
+        let (local, user) = (root.join("local"), root.join("user"));
+        write_kit(
+            &local,
+            "808",
+            r#"
+                [[tracks]]
+                name = "kick"
+                sample = "bd.wav"
+                gain = 0.8
+
+                [[tracks]]
+                name = "ohat"
+                sample = "/samples/oh.wav"
+                pan = 0.3
+                choke = 1
+            "#,
+        );
+        write_kit(
+            &user,
+            "other-808",
+            "name = \"808\"\n[[tracks]]\nname = \"kick\"\nsample = \"x.wav\"\n",
+        );
+        write_kit(&user, "acoustic", "[[tracks]]\nname = \"kick\"\n");
+        write_kit(
+            &user,
+            "loud",
+            "[[tracks]]\nname = \"kick\"\nsample = \"k.wav\"\ngain = 9.0\n",
+        );
+
+        let found = discover(&[local.clone(), user.clone(), root.join("missing")]);
+        assert_eq!(found.kits.len(), 1);
+        let kit = &found.kits[0];
+        assert_eq!(kit.name, "808");
+        assert_eq!(kit.dir, local.join("808"));
+        assert_eq!(kit.tracks[0].gain, 0.8);
+        assert_eq!(kit.tracks[1].choke, Some(1));
+        assert_eq!(
+            kit.sample_path(&kit.tracks[0]),
+            local.join("808/bd.wav").to_string_lossy()
+        );
+        assert_eq!(kit.sample_path(&kit.tracks[1]), "/samples/oh.wav");
+        assert_eq!(
+            tidy(Path::new("./kits/std/../../sounds/kick.wav")),
+            Path::new("sounds/kick.wav")
+        );
+        assert_eq!(tidy(Path::new("../kits/x.wav")), Path::new("../kits/x.wav"));
+
+        let errors: Vec<String> = found.errors.iter().map(|e| format!("{:#}", e)).collect();
+        assert_eq!(errors.len(), 2);
+        assert!(
+            errors[0].contains("missing field `sample`"),
+            "{}",
+            errors[0]
+        );
+        assert!(errors[1].ends_with("track `kick`: gain must be 0-4"));
+
+        assert!(find(&[local, user], "acoustic").is_err());
+        fs::remove_dir_all(root).ok();
+    }
+}
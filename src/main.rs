@@ -3,17 +3,30 @@ mod audio;
 mod ui;
 mod sequencer;
 mod config;
+mod project;
+mod render;
+mod midi;
+mod osc;
+mod session;
+mod headless;
+mod cli;
+mod kit;
 
-use anyhow::Result;
+use clap::Parser;
+use std::process::ExitCode;
 
-fn main() -> Result<()> {
+fn main() -> ExitCode {
     // Initialize logger-friendly panic hook
     std::panic::set_hook(Box::new(|info| {
         eprintln!("panic: {}", info);
     }));
 
-    // Run the app (blocking until exit)
-    let mut app = app::App::new()?;
-    app.run()?;
-    Ok(())
+    // Run the command (blocking until exit); errors print as one readable chain
+    match cli::run(cli::Cli::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {:#}", e);
+            ExitCode::FAILURE
+        }
+    }
 }
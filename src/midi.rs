@@ -0,0 +1,551 @@
+// MIDI export and import
+// Writes the pattern bank as a Standard MIDI File so ideas can move into a DAW,
+// and reads drum files back onto the step grid.
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use midly::num::{u15, u24, u28, u4, u7};
+use midly::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+
+use crate::project::Project;
+use crate::sequencer::clock::{MAX_SWING, MIN_SWING};
+use crate::sequencer::record::snaps_forward;
+use crate::sequencer::steps::{Pattern, Step};
+
+/// Ticks per quarter note; divisible by every common steps-per-beat value.
+pub const TICKS_PER_BEAT: u16 = 480;
+/// General MIDI percussion channel (channel 10, zero-based 9).
+const DRUM_CHANNEL: u8 = 9;
+/// First note for tracks the map doesn't know, counting up per track.
+const FALLBACK_NOTE: u8 = 60;
+/// Note-ons further than this from the step they quantize to, as a fraction
+/// of a step, are reported as off the grid.
+const OFF_GRID: f64 = 0.25;
+
+/// Note number for each track, looked up by track name.
+#[derive(Debug, Clone)]
+pub struct NoteMap {
+    notes: HashMap<String, u8>,
+}
+
+impl NoteMap {
+    /// No names mapped; every track gets a fallback note.
+    pub fn empty() -> Self {
+        Self {
+            notes: HashMap::new(),
+        }
+    }
+
+    /// Map (or remap) a track name to a note; notes are capped at 127.
+    pub fn set(&mut self, name: &str, note: u8) {
+        self.notes.insert(name.to_lowercase(), note.min(127));
+    }
+
+    /// The note for `name`, or one above middle C per track index if unmapped.
+    pub fn note(&self, name: &str, track: usize) -> u8 {
+        self.notes
+            .get(&name.to_lowercase())
+            .copied()
+            .unwrap_or_else(|| (FALLBACK_NOTE as usize + track).min(127) as u8)
+    }
+}
+
+impl Default for NoteMap {
+    /// The General MIDI drum map for the starter kit.
+    fn default() -> Self {
+        let mut map = Self::empty();
+        map.set("kick", 36);
+        map.set("snare", 38);
+        map.set("clap", 39);
+        map.set("hat", 42);
+        map
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MidiOptions {
+    /// Bank slot to write, unless `song` is set
+    pub slot: usize,
+    /// Write the whole arrangement instead of one pattern
+    pub song: bool,
+    pub notes: NoteMap,
+}
+
+/// One note on the output timeline, in ticks from the start.
+#[derive(Debug, Clone, Copy)]
+struct Note {
+    tick: u64,
+    track: usize,
+    velocity: u8,
+}
+
+/// Bank slots in play order: one pass of `opts.slot`, or every repeat of
+/// every arrangement entry.
+fn play_order(project: &Project, opts: &MidiOptions) -> Vec<usize> {
+    if !opts.song || project.song.is_empty() {
+        return vec![opts.slot];
+    }
+    project
+        .song
+        .iter()
+        .flat_map(|entry| std::iter::repeat_n(entry.pattern, entry.repeats as usize))
+        .collect()
+}
+
+/// Every hit in the export, with swing applied to the off-beats. Probability
+/// and trig conditions are ignored: the DAW gets every written step.
+fn collect_notes(project: &Project, opts: &MidiOptions) -> (Vec<Note>, u64) {
+    let steps_per_beat = project.steps_per_beat.max(1) as u64;
+    let step_ticks = |n: u64| n * TICKS_PER_BEAT as u64 / steps_per_beat;
+    let swing = project.swing.clamp(MIN_SWING, MAX_SWING) as u64 * 2 - 100;
+    let swing_ticks = step_ticks(1) * swing / 100;
+
+    let mut notes = Vec::new();
+    let mut step = 0;
+    for slot in play_order(project, opts) {
+        let pattern = project.pattern(slot);
+        for index in 0..project.steps {
+            let mut tick = step_ticks(step);
+            if index % 2 == 1 {
+                tick += swing_ticks;
+            }
+            for (track, row) in pattern.iter().enumerate() {
+                let cell = row.get(index).copied().unwrap_or_default();
+                if cell.is_on() {
+                    notes.push(Note {
+                        tick,
+                        track,
+                        velocity: cell.velocity.min(127),
+                    });
+                }
+            }
+            step += 1;
+        }
+    }
+    (notes, step_ticks(step))
+}
+
+/// Turn absolute-tick events into a track, closing it at `end`.
+fn to_track<'a>(mut events: Vec<(u64, TrackEventKind<'a>)>, end: u64) -> Vec<TrackEvent<'a>> {
+    // stable sort keeps note-offs ahead of note-ons on the same tick
+    events.sort_by_key(|(tick, _)| *tick);
+    events.push((end, TrackEventKind::Meta(MetaMessage::EndOfTrack)));
+    let mut last = 0;
+    events
+        .into_iter()
+        .map(|(tick, kind)| {
+            let tick = tick.max(last);
+            let delta = u28::new((tick - last) as u32);
+            last = tick;
+            TrackEvent { delta, kind }
+        })
+        .collect()
+}
+
+/// Build a Type 1 file: a tempo track followed by one track per sequencer track.
+pub fn to_smf<'a>(project: &'a Project, opts: &MidiOptions) -> Smf<'a> {
+    let (notes, end) = collect_notes(project, opts);
+    let gate = (TICKS_PER_BEAT as u64 / project.steps_per_beat.max(1) as u64 / 2).max(1);
+
+    let header = Header::new(Format::Parallel, Timing::Metrical(u15::new(TICKS_PER_BEAT)));
+    let mut smf = Smf::new(header);
+
+    let tempo = (60_000_000.0 / project.bpm.max(1.0)).round() as u32;
+    smf.tracks.push(to_track(
+        vec![
+            (
+                0,
+                TrackEventKind::Meta(MetaMessage::TrackName(b"CrateBeat")),
+            ),
+            (
+                0,
+                TrackEventKind::Meta(MetaMessage::TimeSignature(4, 2, 24, 8)),
+            ),
+            (0, TrackEventKind::Meta(MetaMessage::Tempo(u24::new(tempo)))),
+        ],
+        end,
+    ));
+
+    let channel = u4::new(DRUM_CHANNEL);
+    let midi = |message| TrackEventKind::Midi { channel, message };
+    for (index, track) in project.tracks.iter().enumerate() {
+        let key = u7::new(opts.notes.note(&track.name, index));
+        let mut events = vec![(
+            0,
+            TrackEventKind::Meta(MetaMessage::TrackName(track.name.as_bytes())),
+        )];
+        for note in notes.iter().filter(|n| n.track == index) {
+            let on = MidiMessage::NoteOn {
+                key,
+                vel: u7::new(note.velocity),
+            };
+            let off = MidiMessage::NoteOff {
+                key,
+                vel: u7::new(0),
+            };
+            events.push((note.tick + gate, midi(off)));
+            events.push((note.tick, midi(on)));
+        }
+        smf.tracks.push(to_track(events, end));
+    }
+    smf
+}
+
+/// Write the project (one pattern or the arrangement) to `path` as a `.mid` file.
+pub fn export_midi(project: &Project, opts: &MidiOptions, path: &Path) -> Result<()> {
+    to_smf(project, opts)
+        .save(path)
+        .with_context(|| format!("could not write {}", path.display()))
+}
+
+/// A drum file quantized onto the project's grid, and what didn't fit.
+#[derive(Debug, Clone)]
+pub struct Import {
+    pub pattern: Pattern,
+    /// Note-ons written into the pattern
+    pub placed: usize,
+    /// Note numbers no track maps to, with how often each was dropped
+    pub unmapped: Vec<(u8, usize)>,
+    /// Note-ons that quantized past the last step
+    pub past_end: usize,
+    /// Placed note-ons that were more than a quarter step from their step
+    pub off_grid: usize,
+    /// Note-ons that landed on a step another note had already filled
+    pub collisions: usize,
+}
+
+impl Import {
+    /// One line for the status bar, e.g. `12 notes imported, 2 off the grid;
+    /// dropped 3 unmapped (49, 51)`.
+    pub fn summary(&self) -> String {
+        let mut text = format!("{} notes imported", self.placed);
+        if self.off_grid > 0 {
+            text += &format!(", {} off the grid", self.off_grid);
+        }
+        if self.collisions > 0 {
+            text += &format!(", {} on a filled step", self.collisions);
+        }
+        let mut dropped = vec![];
+        if !self.unmapped.is_empty() {
+            let count: usize = self.unmapped.iter().map(|(_, n)| n).sum();
+            let notes: Vec<String> = self.unmapped.iter().map(|(k, _)| k.to_string()).collect();
+            dropped.push(format!("{} unmapped ({})", count, notes.join(", ")));
+        }
+        if self.past_end > 0 {
+            dropped.push(format!(
+                "{} past step {}",
+                self.past_end,
+                self.pattern_steps()
+            ));
+        }
+        if !dropped.is_empty() {
+            text += &format!("; dropped {}", dropped.join(", "));
+        }
+        text
+    }
+
+    fn pattern_steps(&self) -> usize {
+        self.pattern.first().map_or(0, Vec::len)
+    }
+}
+
+/// Quantize the note-ons in `smf` to the nearest step of a `project`-sized
+/// pattern, taking the project's swing into account. Tracks are matched by
+/// note number through `notes`; when several notes land on one step the
+/// loudest wins.
+pub fn import_smf(smf: &Smf, project: &Project, notes: &NoteMap) -> Result<Import> {
+    let ticks_per_beat = match smf.header.timing {
+        Timing::Metrical(ticks) => ticks.as_int() as u64,
+        Timing::Timecode(..) => bail!("SMPTE-timed MIDI files are not supported"),
+    };
+    let steps_per_beat = project.steps_per_beat.max(1) as u64;
+    let swing = (project.swing.clamp(MIN_SWING, MAX_SWING) as f64 * 2.0 - 100.0) / 100.0;
+    let keys: Vec<u8> = project
+        .tracks
+        .iter()
+        .enumerate()
+        .map(|(index, track)| notes.note(&track.name, index))
+        .collect();
+
+    let mut import = Import {
+        pattern: vec![vec![Step::REST; project.steps]; project.tracks.len()],
+        placed: 0,
+        unmapped: vec![],
+        past_end: 0,
+        off_grid: 0,
+        collisions: 0,
+    };
+    let mut unmapped: HashMap<u8, usize> = HashMap::new();
+    for track in &smf.tracks {
+        let mut tick = 0;
+        for event in track {
+            tick += event.delta.as_int() as u64;
+            let TrackEventKind::Midi {
+                message: MidiMessage::NoteOn { key, vel },
+                ..
+            } = event.kind
+            else {
+                continue;
+            };
+            // a note-on at velocity 0 is a note-off
+            if vel == 0 {
+                continue;
+            }
+            let Some(row) = keys.iter().position(|&k| k == key.as_int()) else {
+                *unmapped.entry(key.as_int()).or_default() += 1;
+                continue;
+            };
+            let at = (tick * steps_per_beat) as f64 / ticks_per_beat as f64;
+            let (step, distance) = nearest_step(at, swing);
+            let Some(cell) = import.pattern[row].get_mut(step) else {
+                import.past_end += 1;
+                continue;
+            };
+            if cell.is_on() {
+                import.collisions += 1;
+            } else {
+                import.placed += 1;
+                if distance > OFF_GRID {
+                    import.off_grid += 1;
+                }
+            }
+            cell.velocity = cell.velocity.max(vel.as_int());
+        }
+    }
+    let order: BTreeSet<u8> = unmapped.keys().copied().collect();
+    import.unmapped = order.into_iter().map(|k| (k, unmapped[&k])).collect();
+    Ok(import)
+}
+
+/// The step whose onset is nearest `at` (in steps from the start), with odd
+/// steps delayed by `swing` of a step as they are on export, and how many
+/// steps `at` is from that onset.
+fn nearest_step(at: f64, swing: f64) -> (usize, f64) {
+    let onset = |n: i64| n as f64 + if n % 2 == 1 { swing } else { 0.0 };
+    let mut before = at.floor() as i64;
+    while before > 0 && onset(before) > at {
+        before -= 1;
+    }
+    let fraction = (at - onset(before)) / (onset(before + 1) - onset(before));
+    let step = if snaps_forward(fraction, 100) {
+        before + 1
+    } else {
+        before
+    };
+    (step as usize, (at - onset(step)).abs())
+}
+
+/// Read a `.mid` file and quantize it onto `project`'s grid.
+pub fn import_midi(path: &Path, project: &Project, notes: &NoteMap) -> Result<Import> {
+    let bytes =
+        std::fs::read(path).with_context(|| format!("could not read {}", path.display()))?;
+    let smf =
+        Smf::parse(&bytes).with_context(|| format!("{} is not a MIDI file", path.display()))?;
+    import_smf(&smf, project, notes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sequencer::song::SongEntry;
+
+    /// (tick, key, velocity) of every note-on, per file track
+    type NoteOns = Vec<Vec<(u64, u8, u8)>>;
+
+    /// Parse `bytes` and list each track's note-ons.
+    fn note_ons(bytes: &[u8]) -> (Smf<'_>, NoteOns) {
+        let smf = Smf::parse(bytes).unwrap();
+        let tracks = smf
+            .tracks
+            .iter()
+            .map(|track| {
+                let mut tick = 0;
+                let mut notes = vec![];
+                for event in track {
+                    tick += event.delta.as_int() as u64;
+                    if let TrackEventKind::Midi {
+                        message: MidiMessage::NoteOn { key, vel },
+                        ..
+                    } = event.kind
+                    {
+                        notes.push((tick, key.as_int(), vel.as_int()));
+                    }
+                }
+                notes
+            })
+            .collect();
+        (smf, tracks)
+    }
+
+    fn write(project: &Project, opts: &MidiOptions) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        to_smf(project, opts).write_std(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn pattern_round_trips_through_a_midi_file() {
+        let mut project = Project {
+            bpm: 90.0,
+            ..Project::default()
+        };
+        let mut pattern = project.pattern(0);
+        pattern[1][4] = Step::hit(40);
+
+        project.set_pattern(0, &pattern);
+        let path =
+            std::env::temp_dir().join(format!("cratebeat-export-test-{}.mid", std::process::id()));
+        export_midi(&project, &MidiOptions::default(), &path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(path).ok();
+        let (smf, tracks) = note_ons(&bytes);
+
+        assert_eq!(smf.header.format, Format::Parallel);
+        assert_eq!(
+            smf.header.timing,
+            Timing::Metrical(u15::new(TICKS_PER_BEAT))
+        );
+        assert_eq!(smf.tracks.len(), 1 + project.tracks.len());
+        let tempo = smf.tracks[0].iter().find_map(|e| match e.kind {
+            TrackEventKind::Meta(MetaMessage::Tempo(t)) => Some(t.as_int()),
+            _ => None,
+        });
+        assert_eq!(tempo, Some(666_667));
+
+        // each sequencer track comes back with its GM note, steps and velocities
+        let step = TICKS_PER_BEAT as u64 / 4;
+        for (index, track) in project.tracks.iter().enumerate() {
+            let expected: Vec<_> = pattern[index]
+                .iter()
+                .enumerate()
+                .filter(|(_, cell)| cell.is_on())
+                .map(|(i, cell)| {
+                    let key = NoteMap::default().note(&track.name, index);
+                    (i as u64 * step, key, cell.velocity)
+                })
+                .collect();
+            assert_eq!(tracks[index + 1], expected, "track {}", track.name);
+        }
+        assert_eq!(tracks[1][0].1, 36);
+        assert!(tracks[2].contains(&(4 * step, 38, 40)));
+    }
+
+    #[test]
+    fn song_export_follows_the_arrangement() {
+        let mut project = Project::default();
+        let silent = vec![vec![Step::REST; project.steps]; project.tracks.len()];
+        project.set_pattern(1, &silent);
+        project.song = vec![
+            SongEntry::new(0, 2),
+            SongEntry::new(1, 1),
+            SongEntry::new(0, 1),
+        ];
+        let opts = MidiOptions {
+            song: true,
+            ..MidiOptions::default()
+        };
+        let bytes = write(&project, &opts);
+        let (smf, tracks) = note_ons(&bytes);
+
+        // four passes in all; the silent one leaves a gap in the kicks
+        let pass = project.steps as u64 * TICKS_PER_BEAT as u64 / 4;
+        let kicks = project.pattern(0)[0].iter().filter(|c| c.is_on()).count();
+        assert_eq!(tracks[1].len(), 3 * kicks);
+        assert!(tracks[1]
+            .iter()
+            .all(|(tick, ..)| !(2 * pass..3 * pass).contains(tick)));
+        let length: u64 = smf.tracks[0].iter().map(|e| e.delta.as_int() as u64).sum();
+        assert_eq!(length, 4 * pass);
+    }
+
+    #[test]
+    fn unmapped_tracks_get_distinct_notes() {
+        let mut notes = NoteMap::default();
+        assert_eq!(notes.note("Kick", 0), 36);
+        assert_eq!(notes.note("cowbell", 4), 64);
+        notes.set("cowbell", 56);
+        assert_eq!(notes.note("cowbell", 4), 56);
+    }
+
+    #[test]
+    fn exported_patterns_import_unchanged() {
+        let project = Project {
+            swing: 60,
+            ..Project::default()
+        };
+        let bytes = write(&project, &MidiOptions::default());
+        let smf = Smf::parse(&bytes).unwrap();
+        let import = import_smf(&smf, &project, &NoteMap::default()).unwrap();
+        assert_eq!(import.pattern, project.pattern(0));
+        assert!(import.unmapped.is_empty());
+        assert_eq!(import.past_end, 0);
+    }
+
+    #[test]
+    fn swung_patterns_import_unchanged() {
+        let mut project = Project {
+            swing: MAX_SWING,
+            ..Project::default()
+        };
+        // off-beats on every track, the last one right before the loop point
+        let mut pattern = project.pattern(0);
+        pattern[3][3] = Step::hit(100);
+        pattern[3][7] = Step::hit(100);
+        project.set_pattern(0, &pattern);
+        let bytes = write(&project, &MidiOptions::default());
+        let smf = Smf::parse(&bytes).unwrap();
+        let import = import_smf(&smf, &project, &NoteMap::default()).unwrap();
+        assert_eq!(import.pattern, project.pattern(0));
+        assert_eq!(import.past_end, 0);
+    }
+
+    #[test]
+    fn import_quantizes_and_reports_dropped_notes() {
+        let project = Project::default();
+        let step = TICKS_PER_BEAT as u32 / 4;
+        let note = |delta, key, vel| TrackEvent {
+            delta: u28::new(delta),
+            kind: TrackEventKind::Midi {
+                channel: u4::new(DRUM_CHANNEL),
+                message: MidiMessage::NoteOn {
+                    key: u7::new(key),
+                    vel: u7::new(vel),
+                },
+            },
+        };
+        let mut smf = Smf::new(Header::new(
+            Format::SingleTrack,
+            Timing::Metrical(u15::new(TICKS_PER_BEAT)),
+        ));
+        smf.tracks.push(vec![
+            // a late kick snaps back to step 1, an early snare forward to step 3
+            note(10, 36, 90),
+            note(2 * step - 20, 38, 50),
+            // unmapped cowbell and ride
+            note(0, 56, 100),
+            note(0, 51, 100),
+            note(step, 56, 100),
+            // a kick well after step 4, then another on top of it
+            note(45, 36, 70),
+            note(0, 36, 60),
+            // a hat beyond the 8-step pattern
+            note(10 * step - 45, 42, 100),
+        ]);
+
+        let import = import_smf(&smf, &project, &NoteMap::default()).unwrap();
+        assert_eq!(import.pattern[0][0], Step::hit(90));
+        assert_eq!(import.pattern[1][2], Step::hit(50));
+        assert_eq!(import.pattern[0][3], Step::hit(70));
+        assert_eq!(import.placed, 3);
+        assert_eq!(import.off_grid, 1);
+        assert_eq!(import.collisions, 1);
+        assert_eq!(import.unmapped, vec![(51, 1), (56, 2)]);
+        assert_eq!(import.past_end, 1);
+        assert_eq!(
+            import.summary(),
+            "3 notes imported, 1 off the grid, 1 on a filled step; \
+             dropped 3 unmapped (51, 56), 1 past step 8"
+        );
+    }
+}
@@ -0,0 +1,202 @@
+// OSC 1.0 wire format
+// Just enough of the spec for remote control: int, float, string and
+// boolean arguments, and bundles (whose time tags are ignored).
+use anyhow::{bail, Context, Result};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Float(f32),
+    Str(String),
+}
+
+impl OscArg {
+    /// Numbers as an int, truncating floats, so `/bpm 120` and `/bpm 120.0` both work.
+    pub fn as_int(&self) -> Option<i32> {
+        match *self {
+            OscArg::Int(i) => Some(i),
+            OscArg::Float(f) => Some(f as i32),
+            OscArg::Str(_) => None,
+        }
+    }
+
+    pub fn as_float(&self) -> Option<f32> {
+        match *self {
+            OscArg::Int(i) => Some(i as f32),
+            OscArg::Float(f) => Some(f),
+            OscArg::Str(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OscMessage {
+    pub addr: String,
+    pub args: Vec<OscArg>,
+}
+
+impl OscMessage {
+    pub fn new(addr: &str, args: Vec<OscArg>) -> Self {
+        Self {
+            addr: addr.to_string(),
+            args,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_str(&mut out, &self.addr);
+        let tags: String = std::iter::once(',')
+            .chain(self.args.iter().map(|arg| match arg {
+                OscArg::Int(_) => 'i',
+                OscArg::Float(_) => 'f',
+                OscArg::Str(_) => 's',
+            }))
+            .collect();
+        write_str(&mut out, &tags);
+        for arg in &self.args {
+            match arg {
+                OscArg::Int(i) => out.extend_from_slice(&i.to_be_bytes()),
+                OscArg::Float(f) => out.extend_from_slice(&f.to_be_bytes()),
+                OscArg::Str(s) => write_str(&mut out, s),
+            }
+        }
+        out
+    }
+}
+
+/// Decode one packet: a single message, or every message in a bundle.
+pub fn decode_packet(bytes: &[u8]) -> Result<Vec<OscMessage>> {
+    let mut reader = Reader { bytes, pos: 0 };
+    if bytes.starts_with(b"#bundle\0") {
+        reader.pos = 16; // "#bundle\0" and the time tag
+        let mut messages = Vec::new();
+        while reader.pos < bytes.len() {
+            let size = reader.int()? as u32 as usize;
+            if size > bytes.len() - reader.pos {
+                bail!("OSC bundle element of {} bytes overruns the packet", size);
+            }
+            let element = reader.take(size)?;
+            messages.extend(decode_packet(element)?);
+        }
+        return Ok(messages);
+    }
+
+    let addr = reader.string().context("bad OSC address")?;
+    if !addr.starts_with('/') {
+        bail!("OSC address must start with '/': {:?}", addr);
+    }
+    // a missing type tag string means no arguments (old senders omit it)
+    let tags = if reader.pos < bytes.len() {
+        reader.string().context("bad OSC type tags")?
+    } else {
+        ",".to_string()
+    };
+    let Some(tags) = tags.strip_prefix(',') else {
+        bail!("OSC type tags must start with ',': {:?}", tags);
+    };
+    let mut args = Vec::new();
+    for tag in tags.chars() {
+        args.push(match tag {
+            'i' => OscArg::Int(reader.int()?),
+            'f' => OscArg::Float(f32::from_bits(reader.int()? as u32)),
+            's' => OscArg::Str(reader.string()?),
+            'T' => OscArg::Int(1),
+            'F' => OscArg::Int(0),
+            other => bail!("unsupported OSC argument type '{}' in {}", other, addr),
+        });
+    }
+    Ok(vec![OscMessage { addr, args }])
+}
+
+/// A string, NUL-terminated and padded to a multiple of 4 bytes.
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(s.as_bytes());
+    let pad = 4 - s.len() % 4;
+    out.extend(std::iter::repeat_n(0, pad));
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(len);
+        let Some(chunk) = end.and_then(|end| self.bytes.get(self.pos..end)) else {
+            bail!("OSC packet ends early");
+        };
+        self.pos += len;
+        Ok(chunk)
+    }
+
+    fn int(&mut self) -> Result<i32> {
+        let chunk = self.take(4)?;
+        Ok(i32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let rest = &self.bytes[self.pos.min(self.bytes.len())..];
+        let Some(len) = rest.iter().position(|&b| b == 0) else {
+            bail!("OSC string is not terminated");
+        };
+        let s = std::str::from_utf8(&rest[..len]).context("OSC string is not UTF-8")?;
+        self.take((len / 4 + 1) * 4)?;
+        Ok(s.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_round_trip_with_padding() {
+        let msg = OscMessage::new(
+            "/cratebeat/step",
+            vec![
+                OscArg::Int(1),
+                OscArg::Float(0.5),
+                OscArg::Str("kick".into()),
+            ],
+        );
+        let bytes = msg.encode();
+        assert_eq!(bytes.len() % 4, 0);
+        // "/cratebeat/step" is 15 bytes, so one NUL pads it to 16
+        assert_eq!(&bytes[..16], b"/cratebeat/step\0");
+        assert_eq!(&bytes[16..24], b",ifs\0\0\0\0");
+        assert_eq!(decode_packet(&bytes).unwrap(), vec![msg]);
+    }
+
+    #[test]
+    fn bundles_are_flattened() {
+        let a = OscMessage::new("/cratebeat/play", vec![]);
+        let b = OscMessage::new("/cratebeat/bpm", vec![OscArg::Float(98.0)]);
+        let mut bundle = b"#bundle\0".to_vec();
+        bundle.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]); // "immediately"
+        for msg in [&a, &b] {
+            let bytes = msg.encode();
+            bundle.extend_from_slice(&(bytes.len() as i32).to_be_bytes());
+            bundle.extend_from_slice(&bytes);
+        }
+        assert_eq!(decode_packet(&bundle).unwrap(), vec![a, b]);
+    }
+
+    #[test]
+    fn malformed_packets_are_rejected() {
+        assert!(decode_packet(b"play\0\0\0\0").is_err());
+        assert!(decode_packet(b"/bpm\0\0\0\0,f\0\0").is_err());
+        assert!(decode_packet(b"/bpm\0\0\0\0,d\0\0\0\0\0\0\0\0\0\0").is_err());
+        // bundle elements sized negative or past the end of the packet
+        let bundle = |size: i32| {
+            let mut bytes = b"#bundle\0\0\0\0\0\0\0\0\x01".to_vec();
+            bytes.extend_from_slice(&size.to_be_bytes());
+            bytes.extend_from_slice(b"/play\0\0\0");
+            decode_packet(&bytes)
+        };
+        assert_eq!(bundle(8).unwrap()[0].addr, "/play");
+        assert!(bundle(-1).is_err());
+        assert!(bundle(9).is_err());
+    }
+}
@@ -0,0 +1,6 @@
+// OSC module
+pub mod codec;
+pub mod server;
+
+/// UDP port `:osc` listens on when none is given.
+pub const DEFAULT_PORT: u16 = 9000;
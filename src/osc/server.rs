@@ -0,0 +1,340 @@
+// OSC server
+// Receives control messages over UDP on its own thread and forwards them to
+// the engine; engine events are broadcast back to subscribed clients.
+use std::io::ErrorKind;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+
+use super::codec::{decode_packet, OscArg, OscMessage};
+use crate::sequencer::clock::round_bpm;
+use crate::sequencer::engine::{SequencerCommand, SequencerEvent};
+
+/// Address prefix for everything CrateBeat sends and receives.
+const PREFIX: &str = "/cratebeat/";
+/// Largest datagram read; control messages are far smaller.
+const MAX_PACKET: usize = 4096;
+/// Clients that can subscribe to events at once.
+const MAX_SUBSCRIBERS: usize = 16;
+/// How often the receive thread checks whether the server was dropped.
+const POLL: Duration = Duration::from_millis(100);
+
+/// What an incoming message asks for.
+#[derive(Debug)]
+enum Request {
+    Subscribe,
+    Unsubscribe,
+    Command(SequencerCommand),
+}
+
+pub struct OscServer {
+    socket: UdpSocket,
+    subscribers: Arc<Mutex<Vec<SocketAddr>>>,
+    running: Arc<AtomicBool>,
+    errors: Receiver<String>,
+}
+
+impl OscServer {
+    /// Bind `addr` and start handling messages; commands go to `cmd_tx`.
+    pub fn start(addr: impl ToSocketAddrs, cmd_tx: Sender<SequencerCommand>) -> Result<Self> {
+        let socket = UdpSocket::bind(addr).context("could not open OSC port")?;
+        let subscribers = Arc::new(Mutex::new(Vec::new()));
+
+        let running = Arc::new(AtomicBool::new(true));
+
+        let rx_socket = socket.try_clone()?;
+        rx_socket.set_read_timeout(Some(POLL))?;
+        let rx_subscribers = subscribers.clone();
+        let flag = running.clone();
+        let (err_tx, errors) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0; MAX_PACKET];
+            // runs until the server is dropped; a failed read (e.g. an ICMP
+            // error left by a subscriber that went away) doesn't stop it
+            while flag.load(Ordering::Relaxed) {
+                let (len, from) = match rx_socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                        continue
+                    }
+                    Err(e) => {
+                        // not printed: that would draw over the TUI
+                        err_tx.send(format!("OSC receive error: {}", e)).ok();
+                        continue;
+                    }
+                };
+                let handled = decode_packet(&buf[..len]).and_then(|messages| {
+                    for msg in messages {
+                        match parse_request(&msg)? {
+                            Request::Subscribe => {
+                                let mut subs = rx_subscribers.lock().unwrap();
+                                if !subs.contains(&from) {
+                                    if subs.len() >= MAX_SUBSCRIBERS {
+                                        bail!("already {} subscribers", MAX_SUBSCRIBERS);
+                                    }
+                                    subs.push(from);
+                                }
+                            }
+                            Request::Unsubscribe => {
+                                rx_subscribers.lock().unwrap().retain(|&s| s != from);
+                            }
+                            Request::Command(cmd) => {
+                                cmd_tx.send(cmd).ok();
+                            }
+                        }
+                    }
+                    Ok(())
+                });
+                // tell the sender what was wrong rather than dropping it silently
+                if let Err(e) = handled {
+                    let reply = message("error", vec![OscArg::Str(format!("{:#}", e))]);
+                    rx_socket.send_to(&reply.encode(), from).ok();
+                }
+            }
+        });
+
+        Ok(Self {
+            socket,
+            subscribers,
+            running,
+            errors,
+        })
+    }
+
+    /// Receive errors since the last call; the server carries on after them.
+    pub fn take_errors(&self) -> Vec<String> {
+        self.errors.try_iter().collect()
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Send an engine event to every subscriber.
+    pub fn broadcast(&self, event: &SequencerEvent) {
+        let msg = match event {
+            SequencerEvent::Step { step, hits } => {
+                let args = std::iter::once(*step as i32)
+                    .chain(hits.iter().map(|&v| v as i32))
+                    .map(OscArg::Int)
+                    .collect();
+                message("playhead", args)
+            }
+            SequencerEvent::State(state) => message(
+                "transport",
+                vec![
+                    OscArg::Int(state.playing as i32),
+                    OscArg::Float(state.bpm as f32),
+                    OscArg::Int(state.slot as i32),
+                ],
+            ),
+            SequencerEvent::PatternSwitched { slot } => {
+                message("pattern", vec![OscArg::Int(*slot as i32)])
+            }
+            // the grid isn't broadcast
+            SequencerEvent::Bank(_)
+            | SequencerEvent::TrackInserted { .. }
+            | SequencerEvent::TrackRemoved { .. } => return,
+        };
+        let bytes = msg.encode();
+        // a subscriber that can't be sent to is gone
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|addr| self.socket.send_to(&bytes, addr).is_ok());
+    }
+}
+
+impl Drop for OscServer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+}
+
+fn message(name: &str, args: Vec<OscArg>) -> OscMessage {
+    OscMessage::new(&format!("{}{}", PREFIX, name), args)
+}
+
+/// Map an incoming message onto an engine command.
+fn parse_request(msg: &OscMessage) -> Result<Request> {
+    let Some(name) = msg.addr.strip_prefix(PREFIX) else {
+        bail!("unknown OSC address {}", msg.addr);
+    };
+    // non-negative integer argument `i`
+    let index = |i: usize| -> Result<usize> {
+        match msg.args.get(i).and_then(OscArg::as_int) {
+            Some(n) if n >= 0 => Ok(n as usize),
+            _ => bail!(
+                "{} expects a non-negative number as argument {}",
+                msg.addr,
+                i + 1
+            ),
+        }
+    };
+    let cmd = match name {
+        "subscribe" => return Ok(Request::Subscribe),
+        "unsubscribe" => return Ok(Request::Unsubscribe),
+        "play" => SequencerCommand::Play,
+        "stop" => SequencerCommand::Stop,
+        "toggle" => SequencerCommand::TogglePlay,
+        "bpm" => match msg.args.first().and_then(OscArg::as_float) {
+            Some(bpm) if bpm > 0.0 => SequencerCommand::SetBpm(round_bpm(bpm as f64)),
+            _ => bail!("{} expects a tempo", msg.addr),
+        },
+        // track, step, velocity (0 clears the step)
+        "step" => SequencerCommand::SetVelocity {
+            track: index(0)?,
+            step: index(1)?,
+            velocity: index(2)?.min(127) as u8,
+        },
+        // bank slot, then optionally the steps to wait instead of a bar
+        "pattern" => SequencerCommand::QueuePattern {
+            slot: index(0)?,
+            after: if msg.args.len() > 1 {
+                Some(index(1)?)
+            } else {
+                None
+            },
+        },
+        "fill" => SequencerCommand::SetFill(index(0)? != 0),
+        _ => bail!("unknown OSC address {}", msg.addr),
+    };
+    Ok(Request::Command(cmd))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sequencer::engine::Snapshot;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    fn send(client: &UdpSocket, server: SocketAddr, addr: &str, args: Vec<OscArg>) {
+        let bytes = OscMessage::new(addr, args).encode();
+        client.send_to(&bytes, server).unwrap();
+    }
+
+    fn receive(client: &UdpSocket) -> OscMessage {
+        let mut buf = [0; MAX_PACKET];
+        let len = client.recv(&mut buf).unwrap();
+        decode_packet(&buf[..len]).unwrap().remove(0)
+    }
+
+    #[test]
+    fn loopback_client_drives_the_engine_and_hears_back() {
+        let (cmd_tx, cmd_rx) = mpsc::channel();
+        let server = OscServer::start("127.0.0.1:0", cmd_tx).unwrap();
+        let to = server.local_addr().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(TIMEOUT)).unwrap();
+
+        send(&client, to, "/cratebeat/subscribe", vec![]);
+        send(&client, to, "/cratebeat/play", vec![]);
+        send(&client, to, "/cratebeat/bpm", vec![OscArg::Float(97.6)]);
+        let step = vec![OscArg::Int(1), OscArg::Int(6), OscArg::Int(127)];
+        send(&client, to, "/cratebeat/step", step);
+
+        let cmds: Vec<_> = (0..3)
+            .map(|_| cmd_rx.recv_timeout(TIMEOUT).unwrap())
+            .collect();
+        assert!(matches!(cmds[0], SequencerCommand::Play));
+        assert!(matches!(cmds[1], SequencerCommand::SetBpm(bpm) if bpm == 97.6));
+        assert!(matches!(
+            cmds[2],
+            SequencerCommand::SetVelocity {
+                track: 1,
+                step: 6,
+                velocity: 127
+            }
+        ));
+
+        // the client subscribed before sending commands, so it gets events now
+        server.broadcast(&SequencerEvent::Step {
+            step: 3,
+            hits: vec![100, 0],
+        });
+        let playhead = receive(&client);
+        assert_eq!(playhead.addr, "/cratebeat/playhead");
+        assert_eq!(
+            playhead.args,
+            vec![OscArg::Int(3), OscArg::Int(100), OscArg::Int(0)]
+        );
+        let state = Snapshot {
+            playing: true,
+            bpm: 98.5,
+            ..Snapshot::default()
+        };
+        server.broadcast(&SequencerEvent::State(state));
+        let transport = receive(&client);
+        assert_eq!(transport.addr, "/cratebeat/transport");
+        assert_eq!(transport.args[..2], [OscArg::Int(1), OscArg::Float(98.5)]);
+    }
+
+    #[test]
+    fn bad_messages_get_an_error_reply() {
+        let (cmd_tx, cmd_rx) = mpsc::channel();
+        let server = OscServer::start("127.0.0.1:0", cmd_tx).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(TIMEOUT)).unwrap();
+
+        let to = server.local_addr().unwrap();
+        send(&client, to, "/cratebeat/step", vec![OscArg::Int(-1)]);
+        let reply = receive(&client);
+        assert_eq!(reply.addr, "/cratebeat/error");
+        assert!(matches!(&reply.args[0], OscArg::Str(e) if e.contains("argument 1")));
+        assert!(cmd_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn subscribers_are_capped_and_departed_ones_dont_stop_the_server() {
+        let (cmd_tx, cmd_rx) = mpsc::channel();
+        let server = OscServer::start("127.0.0.1:0", cmd_tx).unwrap();
+        let to = server.local_addr().unwrap();
+        let clients: Vec<UdpSocket> = (0..=MAX_SUBSCRIBERS)
+            .map(|_| {
+                let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+                client.set_read_timeout(Some(TIMEOUT)).unwrap();
+                client
+            })
+            .collect();
+
+        // everyone but the last gets in; each hears the broadcast once it's in
+        for client in &clients[..MAX_SUBSCRIBERS] {
+            send(client, to, "/cratebeat/subscribe", vec![]);
+        }
+        let deadline = std::time::Instant::now() + TIMEOUT;
+        while server.subscribers.lock().unwrap().len() < MAX_SUBSCRIBERS {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "subscriptions not seen"
+            );
+            thread::sleep(Duration::from_millis(5));
+        }
+        server.broadcast(&SequencerEvent::PatternSwitched { slot: 2 });
+        for client in &clients[..MAX_SUBSCRIBERS] {
+            assert_eq!(receive(client).addr, "/cratebeat/pattern");
+        }
+        let last = &clients[MAX_SUBSCRIBERS];
+        send(last, to, "/cratebeat/subscribe", vec![]);
+        let reply = receive(last);
+        assert_eq!(reply.addr, "/cratebeat/error");
+        assert_eq!(server.subscribers.lock().unwrap().len(), MAX_SUBSCRIBERS);
+
+        // a subscriber closing its port leaves the server listening
+        drop(clients);
+        server.broadcast(&SequencerEvent::PatternSwitched { slot: 3 });
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        send(&client, to, "/cratebeat/play", vec![]);
+        assert!(matches!(
+            cmd_rx.recv_timeout(TIMEOUT),
+            Ok(SequencerCommand::Play)
+        ));
+    }
+}
@@ -0,0 +1,615 @@
+// Project files
+// A project is a TOML document (conventionally `*.cbt`) holding tempo, kit and pattern.
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use toml::Spanned;
+
+use crate::audio::mixer::ChannelStrip;
+use crate::config::line_col;
+use crate::kit::Kit;
+use crate::sequencer::clock::{MAX_BPM, MAX_STEPS_PER_BEAT, MAX_SWING, MIN_BPM, MIN_SWING};
+use crate::sequencer::song::{SongEntry, BANK_SLOTS};
+use crate::sequencer::steps::{
+    empty_pattern, Condition, Pattern, Step, DEFAULT_VELOCITY, MAX_STEPS, MAX_TRACKS,
+};
+
+/// Version written by this build.
+pub const CURRENT_VERSION: u32 = 6;
+
+/// Upgrades from older file versions, indexed by the version they upgrade from:
+/// `MIGRATIONS[0]` turns a v1 document into v2, and so on.
+/// Append one whenever `CURRENT_VERSION` is bumped.
+const MIGRATIONS: &[fn(&mut toml::Table) -> Result<()>] = &[
+    v1_add_velocity,
+    v2_add_swing,
+    v3_add_trig_conditions,
+    v4_move_steps_to_bank,
+    v5_fractional_bpm,
+];
+
+/// v2 added per-step velocity; v1 hits all played at the same level.
+fn v1_add_velocity(doc: &mut toml::Table) -> Result<()> {
+    let Some(tracks) = doc.get_mut("tracks").and_then(|t| t.as_array_mut()) else {
+        return Ok(());
+    };
+    for track in tracks.iter_mut().filter_map(|t| t.as_table_mut()) {
+        let pattern = track.get("pattern").and_then(|p| p.as_str()).unwrap_or("");
+        let velocity: toml::value::Array = pattern
+            .chars()
+            .map(|c| if c == 'x' { DEFAULT_VELOCITY as i64 } else { 0 }.into())
+            .collect();
+        track.insert("velocity".into(), velocity.into());
+    }
+    Ok(())
+}
+
+/// v3 added swing; earlier projects played straight.
+fn v2_add_swing(doc: &mut toml::Table) -> Result<()> {
+    doc.insert("swing".into(), (MIN_SWING as i64).into());
+    Ok(())
+}
+
+/// v4 added per-step probability and conditions. Both are optional and
+/// default to "always plays", so there is nothing to fill in.
+fn v3_add_trig_conditions(_doc: &mut toml::Table) -> Result<()> {
+    Ok(())
+}
+
+/// v5 added the pattern bank: each track's steps move into the first slot.
+fn v4_move_steps_to_bank(doc: &mut toml::Table) -> Result<()> {
+    let mut rows = toml::value::Array::new();
+    if let Some(tracks) = doc.get_mut("tracks").and_then(|t| t.as_array_mut()) {
+        for track in tracks.iter_mut().filter_map(|t| t.as_table_mut()) {
+            let mut row = toml::Table::new();
+            for key in ["pattern", "velocity", "probability", "condition"] {
+                if let Some(value) = track.remove(key) {
+                    row.insert(key.into(), value);
+                }
+            }
+            rows.push(row.into());
+        }
+    }
+    let mut slot = toml::Table::new();
+    slot.insert("rows".into(), rows.into());
+    doc.insert("patterns".into(), toml::Value::Array(vec![slot.into()]));
+    Ok(())
+}
+
+/// v6 allows a fractional tempo, so it is written as a float.
+fn v5_fractional_bpm(doc: &mut toml::Table) -> Result<()> {
+    if let Some(bpm) = doc.get("bpm").and_then(|b| b.as_integer()) {
+        doc.insert("bpm".into(), (bpm as f64).into());
+    }
+    Ok(())
+}
+
+/// Where the top-level settings sit in a project file, so a bad value can
+/// be pointed at. Settings a migration filled in have no place.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct SettingSpans {
+    bpm: Option<Spanned<toml::Value>>,
+    steps: Option<Spanned<toml::Value>>,
+    steps_per_beat: Option<Spanned<toml::Value>>,
+    swing: Option<Spanned<toml::Value>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Project {
+    pub version: u32,
+    pub bpm: f64,
+    pub steps: usize,
+    pub steps_per_beat: u32,
+    /// Off-beat delay, 50 (straight) to 75 percent
+    pub swing: u8,
+    pub tracks: Vec<Track>,
+    /// Pattern bank, from slot 0; trailing empty slots are left out
+    pub patterns: Vec<BankSlot>,
+    /// Arrangement for song mode
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub song: Vec<SongEntry>,
+}
+
+/// One sequencer track: a display name, the sample it plays and its mixer defaults.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Track {
+    pub name: String,
+    pub sample: String,
+    /// Linear gain, left out at unity
+    #[serde(default = "unity", skip_serializing_if = "is_unity")]
+    pub gain: f64,
+    /// -1.0 hard left to 1.0 hard right, left out when centred
+    #[serde(default, skip_serializing_if = "is_centre")]
+    pub pan: f64,
+    /// Choke group shared with other tracks, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub choke: Option<u8>,
+}
+
+impl Track {
+    pub fn new(name: &str, sample: String) -> Self {
+        Self {
+            name: name.to_string(),
+            sample,
+            gain: 1.0,
+            pan: 0.0,
+            choke: None,
+        }
+    }
+
+    /// The channel strip this track starts with.
+    pub fn strip(&self) -> ChannelStrip {
+        ChannelStrip {
+            gain: self.gain as f32,
+            pan: self.pan as f32,
+            choke: self.choke,
+            ..ChannelStrip::default()
+        }
+    }
+}
+
+fn unity() -> f64 {
+    1.0
+}
+
+fn is_unity(gain: &f64) -> bool {
+    *gain == 1.0
+}
+
+fn is_centre(pan: &f64) -> bool {
+    *pan == 0.0
+}
+
+/// One pattern in the bank: a row of steps per track, in track order.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BankSlot {
+    pub rows: Vec<Row>,
+}
+
+/// One track's steps within a pattern.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Row {
+    /// One character per step: `x` for a hit, `.` for a rest
+    pub pattern: String,
+    /// Velocity (1-127) of each step; hits past the end use the default
+    #[serde(default)]
+    pub velocity: Vec<u8>,
+    /// Trigger probability (0-100) of each step; left out when every step always plays
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub probability: Vec<u8>,
+    /// Trig condition of each step (`1:2`, `fill`, `!pre`, ...), `""` for none;
+    /// left out when no step has one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub condition: Vec<String>,
+}
+
+impl Row {
+    fn from_steps(steps: &[Step]) -> Self {
+        Self {
+            pattern: steps
+                .iter()
+                .map(|s| if s.is_on() { 'x' } else { '.' })
+                .collect(),
+            velocity: steps.iter().map(|s| s.velocity).collect(),
+            probability: if steps.iter().any(|s| s.probability < 100) {
+                steps.iter().map(|s| s.probability).collect()
+            } else {
+                Vec::new()
+            },
+            condition: if steps.iter().any(|s| s.condition.is_some()) {
+                steps
+                    .iter()
+                    .map(|s| s.condition.map(|c| c.to_string()).unwrap_or_default())
+                    .collect()
+            } else {
+                Vec::new()
+            },
+        }
+    }
+
+    /// Fill `steps` from this row; steps past its end are left as they are.
+    fn to_steps(&self, steps: &mut [Step]) {
+        for (i, (cell, c)) in steps.iter_mut().zip(self.pattern.chars()).enumerate() {
+            if c == 'x' {
+                let velocity = self.velocity.get(i).copied().unwrap_or(0);
+                *cell = Step::hit(if velocity == 0 {
+                    DEFAULT_VELOCITY
+                } else {
+                    velocity
+                });
+            }
+            if let Some(&p) = self.probability.get(i) {
+                cell.probability = p.min(100);
+            }
+            cell.condition = self.condition.get(i).and_then(|c| c.parse().ok());
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if let Some(p) = self.probability.iter().find(|&&p| p > 100) {
+            bail!("probability must be 0-100, got {}", p);
+        }
+        for c in self.condition.iter().filter(|c| !c.is_empty()) {
+            c.parse::<Condition>()?;
+        }
+        Ok(())
+    }
+}
+
+/// Directory the bundled kit lives in, relative to where CrateBeat runs.
+pub const DEFAULT_SOUND_DIR: &str = "sounds";
+
+/// Sample path of the track `name` in `sound_dir`: `<sound_dir>/<name>.wav`.
+pub fn sample_path(sound_dir: &Path, name: &str) -> String {
+    sound_dir
+        .join(format!("{}.wav", name))
+        .to_string_lossy()
+        .into_owned()
+}
+
+impl Default for Project {
+    /// The built-in starter beat on the bundled kit.
+    fn default() -> Self {
+        Self::starter(Path::new(DEFAULT_SOUND_DIR))
+    }
+}
+
+impl Project {
+    /// The starter beat, with samples from `sound_dir`.
+    pub fn starter(sound_dir: &Path) -> Self {
+        let track = |name: &str| Track::new(name, sample_path(sound_dir, name));
+        let mut project = Self {
+            version: CURRENT_VERSION,
+            bpm: 120.0,
+            steps: 8,
+            steps_per_beat: 4,
+            swing: MIN_SWING,
+            tracks: vec![track("kick"), track("snare"), track("hat"), track("clap")],
+            patterns: Vec::new(),
+            song: Vec::new(),
+        };
+        let mut pattern = project.pattern(0);
+        for (row, hits) in pattern
+            .iter_mut()
+            .zip(["x.x.....", ".x......", "x.x.x.x.", "........"])
+        {
+            for (cell, c) in row.iter_mut().zip(hits.chars()) {
+                if c == 'x' {
+                    *cell = Step::hit(DEFAULT_VELOCITY);
+                }
+            }
+        }
+        project.set_pattern(0, &pattern);
+        project
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read project: {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Invalid project: {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        // not `to_string_pretty`: that puts every velocity on its own line
+        let text = toml::to_string(self)?;
+        fs::write(path, text)
+            .with_context(|| format!("Failed to write project: {}", path.display()))
+    }
+
+    fn parse(text: &str) -> Result<Self> {
+        let mut doc: toml::Table = text.parse()?;
+        let version = doc
+            .get("version")
+            .and_then(|v| v.as_integer())
+            .ok_or_else(|| anyhow!("missing `version`"))? as u32;
+        if version == 0 || version > CURRENT_VERSION {
+            bail!(
+                "unsupported project version {} (this build reads up to {})",
+                version,
+                CURRENT_VERSION
+            );
+        }
+
+        for from in version..CURRENT_VERSION {
+            MIGRATIONS[from as usize - 1](&mut doc)
+                .with_context(|| format!("migrating from version {}", from))?;
+        }
+        doc.insert("version".into(), (CURRENT_VERSION as i64).into());
+
+        let project: Self = toml::Value::Table(doc).try_into()?;
+        let spans: SettingSpans = toml::from_str(text).unwrap_or_default();
+        // `line N: message` for a setting written in the file
+        let located = |span: &Option<Spanned<toml::Value>>, message: String| match span {
+            Some(value) => anyhow!("line {}: {}", line_col(text, value.span().start).0, message),
+            None => anyhow!(message),
+        };
+        if !(MIN_BPM..=MAX_BPM).contains(&project.bpm) {
+            return Err(located(
+                &spans.bpm,
+                format!("`bpm` must be {}-{}, got {}", MIN_BPM, MAX_BPM, project.bpm),
+            ));
+        }
+        if !(1..=MAX_STEPS).contains(&project.steps) {
+            return Err(located(
+                &spans.steps,
+                format!("`steps` must be 1-{}, got {}", MAX_STEPS, project.steps),
+            ));
+        }
+        if !(1..=MAX_STEPS_PER_BEAT).contains(&project.steps_per_beat) {
+            return Err(located(
+                &spans.steps_per_beat,
+                format!(
+                    "`steps_per_beat` must be 1-{}, got {}",
+                    MAX_STEPS_PER_BEAT, project.steps_per_beat
+                ),
+            ));
+        }
+        if !(MIN_SWING..=MAX_SWING).contains(&project.swing) {
+            return Err(located(
+                &spans.swing,
+                format!(
+                    "`swing` must be {}-{}, got {}",
+                    MIN_SWING, MAX_SWING, project.swing
+                ),
+            ));
+        }
+        if !(1..=MAX_TRACKS).contains(&project.tracks.len()) {
+            bail!(
+                "a project needs 1-{} tracks, got {}",
+                MAX_TRACKS,
+                project.tracks.len()
+            );
+        }
+        if project.patterns.len() > BANK_SLOTS {
+            bail!(
+                "the bank holds {} patterns, got {}",
+                BANK_SLOTS,
+                project.patterns.len()
+            );
+        }
+        for (slot, pattern) in project.patterns.iter().enumerate() {
+            for (row, track) in pattern.rows.iter().zip(&project.tracks) {
+                row.validate()
+                    .with_context(|| format!("pattern {}, track `{}`", slot + 1, track.name))?;
+            }
+        }
+        if let Some(entry) = project.song.iter().find(|e| e.pattern >= BANK_SLOTS) {
+            bail!(
+                "song entry uses pattern {}, the bank has {}",
+                entry.pattern + 1,
+                BANK_SLOTS
+            );
+        }
+        Ok(project)
+    }
+
+    /// The step grid in bank `slot`, sized to the project's tracks and steps.
+    pub fn pattern(&self, slot: usize) -> Pattern {
+        let mut pattern = empty_pattern(self.tracks.len(), self.steps);
+        if let Some(stored) = self.patterns.get(slot) {
+            for (steps, row) in pattern.iter_mut().zip(&stored.rows) {
+                row.to_steps(steps);
+            }
+        }
+        pattern
+    }
+
+    /// Every stored bank slot, in order.
+    pub fn bank(&self) -> Vec<Pattern> {
+        (0..self.patterns.len().max(1))
+            .map(|slot| self.pattern(slot))
+            .collect()
+    }
+
+    pub fn set_pattern(&mut self, slot: usize, pattern: &Pattern) {
+        if self.patterns.len() <= slot {
+            self.patterns.resize(slot + 1, BankSlot::default());
+        }
+        self.patterns[slot].rows = pattern.iter().map(|row| Row::from_steps(row)).collect();
+        // keep files small: don't store blank slots at the end of the bank
+        while self.patterns.len() > 1
+            && self
+                .pattern(self.patterns.len() - 1)
+                .iter()
+                .flatten()
+                .all(|s| *s == Step::REST)
+        {
+            self.patterns.pop();
+        }
+    }
+
+    /// Append an empty track playing `sample`. Returns false if the grid is full.
+    pub fn add_track(&mut self, name: &str, sample: String) -> bool {
+        if self.tracks.len() >= MAX_TRACKS {
+            return false;
+        }
+        self.tracks.push(Track::new(name, sample));
+        true
+    }
+
+    /// Put `kit` on the tracks in order, adding tracks for any extra kit
+    /// sounds (up to the limit). Tracks past the end of the kit keep their
+    /// samples, and every pattern stays as it is. Returns how many tracks were added.
+    pub fn set_kit(&mut self, kit: &Kit) -> usize {
+        let before = self.tracks.len();
+        for (i, sound) in kit.tracks.iter().enumerate().take(MAX_TRACKS) {
+            let track = Track {
+                name: sound.name.clone(),
+                sample: kit.sample_path(sound),
+                gain: sound.gain,
+                pan: sound.pan,
+                choke: sound.choke,
+            };
+            match self.tracks.get_mut(i) {
+                Some(existing) => *existing = track,
+                None => self.tracks.push(track),
+            }
+        }
+        self.tracks.len() - before
+    }
+
+    /// Each track's starting channel strip, in track order.
+    pub fn strips(&self) -> Vec<ChannelStrip> {
+        self.tracks.iter().map(Track::strip).collect()
+    }
+
+    /// Remove a track, always leaving at least one. Returns false if nothing was removed.
+    pub fn remove_track(&mut self, track: usize) -> bool {
+        if track >= self.tracks.len() || self.tracks.len() == 1 {
+            return false;
+        }
+        self.tracks.remove(track);
+        for slot in &mut self.patterns {
+            if track < slot.rows.len() {
+                slot.rows.remove(track);
+            }
+        }
+        true
+    }
+
+    /// Put a track back at `track`, with empty rows; an undone removal
+    /// fills them in afterwards.
+    pub fn insert_track(&mut self, track: usize, settings: Track) {
+        let track = track.min(self.tracks.len());
+        self.tracks.insert(track, settings);
+        for slot in &mut self.patterns {
+            if track <= slot.rows.len() {
+                slot.rows.insert(track, Row::default());
+            }
+        }
+    }
+
+    pub fn resize(&mut self, steps: usize) {
+        // rewrite the rows so steps cut off now don't come back if it grows again
+        self.steps = steps.clamp(1, MAX_STEPS);
+        for (slot, pattern) in self.bank().iter().enumerate() {
+            self.set_pattern(slot, pattern);
+        }
+    }
+
+    /// Display names, in track order.
+    pub fn track_names(&self) -> Vec<String> {
+        self.tracks.iter().map(|t| t.name.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn v1_files_are_upgraded_and_round_trip() {
+        let v1 = r#"
+            version = 1
+            bpm = 90
+            steps = 4
+            steps_per_beat = 4
+
+            [[tracks]]
+            name = "kick"
+            sample = "sounds/kick.wav"
+            pattern = "x..x"
+        "#;
+        let project = Project::parse(v1).unwrap();
+        assert_eq!(project.version, CURRENT_VERSION);
+        assert_eq!(project.bpm, 90.0);
+        assert_eq!(project.swing, MIN_SWING);
+        assert_eq!(
+            project.patterns[0].rows[0].velocity,
+            vec![DEFAULT_VELOCITY, 0, 0, DEFAULT_VELOCITY]
+        );
+
+        let mut pattern = project.pattern(0);
+        assert_eq!(pattern[0][3], Step::hit(DEFAULT_VELOCITY));
+        pattern[0][3] = Step::hit(40);
+        pattern[0][3].probability = 50;
+        pattern[0][0].condition = Some(Condition::Ratio { a: 1, b: 2 });
+        let mut saved = project.clone();
+        saved.set_pattern(0, &pattern);
+        saved.set_pattern(2, &pattern);
+        saved.song = vec![SongEntry::new(0, 2), SongEntry::new(2, 1)];
+        let reloaded = Project::parse(&toml::to_string(&saved).unwrap()).unwrap();
+        assert_eq!(
+            reloaded.bank(),
+            vec![pattern.clone(), empty_pattern(1, 4), pattern]
+        );
+        assert_eq!(reloaded.song, saved.song);
+        assert_eq!(reloaded.bpm, 90.0);
+    }
+
+    #[test]
+    fn kits_replace_samples_in_order_and_keep_patterns() {
+        let mut project = Project::starter(Path::new("sounds"));
+        let pattern = project.pattern(0);
+        let sound = |name: &str, choke| crate::kit::KitTrack {
+            name: name.to_string(),
+            sample: format!("{}.wav", name).into(),
+            gain: 0.5,
+            pan: -0.25,
+            choke,
+        };
+        let kit = Kit {
+            name: "808".to_string(),
+            dir: "kits/808".into(),
+            tracks: ["bd", "sd", "ch", "cp", "oh"]
+                .iter()
+                .map(|name| sound(name, name.ends_with('h').then_some(1)))
+                .collect(),
+        };
+
+        assert_eq!(project.set_kit(&kit), 1);
+        assert_eq!(project.track_names(), ["bd", "sd", "ch", "cp", "oh"]);
+        assert_eq!(
+            project.tracks[4].sample,
+            Path::new("kits/808/oh.wav").to_string_lossy()
+        );
+        assert_eq!(project.strips()[2].choke, Some(1));
+        assert_eq!(project.pattern(0)[..4], pattern[..]);
+
+        let reloaded = Project::parse(&toml::to_string(&project).unwrap()).unwrap();
+        assert_eq!(reloaded.tracks[4].gain, 0.5);
+        assert_eq!(reloaded.tracks[4].pan, -0.25);
+        assert_eq!(reloaded.tracks[1].choke, None);
+        // unity gain and centre pan are left out of the file
+        assert!(!toml::to_string(&Project::default())
+            .unwrap()
+            .contains("gain"));
+    }
+
+    #[test]
+    fn bad_settings_are_reported_with_their_line() {
+        let text = |bpm: &str, steps_per_beat: &str| {
+            format!(
+                "version = 6\nbpm = {}\nsteps = 4\nsteps_per_beat = {}\nswing = 50\n\
+                 patterns = []\n\n[[tracks]]\nname = \"kick\"\nsample = \"kick.wav\"\n",
+                bpm, steps_per_beat
+            )
+        };
+        let err = |text: &str| format!("{:#}", Project::parse(text).unwrap_err());
+        assert!(Project::parse(&text("96.5", "4")).is_ok());
+        assert_eq!(
+            err(&text("500.0", "4")),
+            "line 2: `bpm` must be 20-300, got 500"
+        );
+        assert_eq!(
+            err(&text("0.0", "4")),
+            "line 2: `bpm` must be 20-300, got 0"
+        );
+        assert_eq!(
+            err(&text("120.0", "0")),
+            "line 4: `steps_per_beat` must be 1-16, got 0"
+        );
+        // a grid this fine would leave steps zero frames long
+        assert_eq!(
+            err(&text("120.0", "1000000")),
+            "line 4: `steps_per_beat` must be 1-16, got 1000000"
+        );
+        assert!(Project::parse(&text("120.0", "16")).is_ok());
+        // an integer tempo from before v6 is checked after its upgrade
+        let v5 = text("5", "4").replace("version = 6", "version = 5");
+        assert_eq!(err(&v5), "line 2: `bpm` must be 20-300, got 5");
+    }
+}
@@ -0,0 +1,249 @@
+// Offline rendering
+// Runs the sequencer and output source without an audio device, e.g. to bounce a loop to WAV.
+use std::path::Path;
+use std::sync::mpsc;
+
+use anyhow::{bail, Result};
+
+use crate::audio::mixer::{ChannelStrip, MixerControls};
+use crate::audio::player::SampleBank;
+use crate::audio::scheduler::{FrameClock, ScheduledSource};
+use crate::project::Project;
+use crate::sequencer::clock::StepClock;
+use crate::sequencer::engine::{SequencerCommand, SequencerEngine};
+
+/// Channels in a bounce; the live output may differ.
+const CHANNELS: u16 = 2;
+/// Frames rendered per scheduling pass, like one audio callback.
+const BLOCK_FRAMES: u64 = 512;
+
+/// Longest tail a render takes, in seconds.
+pub const MAX_TAIL_SECS: f32 = 60.0;
+/// Most bars a render takes; the whole bounce is held in memory.
+pub const MAX_BARS: u32 = 999;
+/// Sample rates a render accepts, in Hz.
+pub const SAMPLE_RATES: std::ops::RangeInclusive<u32> = 8_000..=192_000;
+
+#[derive(Debug, Clone)]
+pub struct RenderOptions {
+    /// Length in 4/4 bars (4 beats each)
+    pub bars: u32,
+    pub sample_rate: u32,
+    /// 16 or 24 for integer PCM, 32 for float
+    pub bits_per_sample: u16,
+    /// Extra time after the last bar so ringing hits aren't cut off
+    pub tail_secs: f32,
+    /// Mixer settings per track; empty uses the project's track settings
+    pub strips: Vec<ChannelStrip>,
+    /// Seed for step probabilities; the same seed renders the same hits
+    pub seed: u64,
+    /// Bank slot to play, unless `song` is set
+    pub slot: usize,
+    /// Play the project's arrangement from the top instead of one slot
+    pub song: bool,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            bars: 1,
+            sample_rate: 44_100,
+            bits_per_sample: 16,
+            tail_secs: 1.0,
+            strips: Vec::new(),
+            seed: 0,
+            slot: 0,
+            song: false,
+        }
+    }
+}
+
+/// Split `path [bars]` as typed after `bounce`; bars default to 1.
+pub fn parse_bounce_args(arg: &str) -> Result<(String, u32)> {
+    let arg = arg.trim();
+    let (path, bars) = match arg.rsplit_once(char::is_whitespace) {
+        Some((path, bars)) if bars.bytes().all(|b| b.is_ascii_digit()) => match bars.parse() {
+            Ok(bars) if (1..=MAX_BARS).contains(&bars) => (path.trim(), bars),
+            _ => bail!("bars must be 1-{}, got {}", MAX_BARS, bars),
+        },
+        _ => (arg, 1),
+    };
+    Ok((path.to_string(), bars))
+}
+
+/// Render `project` into interleaved stereo samples using the samples in `bank`.
+pub fn render_samples(
+    project: &Project,
+    bank: SampleBank,
+    opts: &RenderOptions,
+) -> Result<Vec<f32>> {
+    if !(1..=MAX_BARS).contains(&opts.bars) {
+        bail!("bars must be 1-{}, got {}", MAX_BARS, opts.bars);
+    }
+    let tempo = StepClock::new(project.bpm, project.steps_per_beat, opts.sample_rate);
+    let end = tempo.step_offset(opts.bars as u64 * 4 * project.steps_per_beat as u64);
+    let total = end + (opts.tail_secs.max(0.0) * opts.sample_rate as f32) as u64;
+
+    let (evt_tx, _evt_rx) = mpsc::channel();
+    let (trig_tx, trig_rx) = mpsc::channel();
+    let clock = FrameClock::new();
+    let strips = if opts.strips.is_empty() {
+        project.strips()
+    } else {
+        opts.strips.clone()
+    };
+    let mixer = MixerControls::new(strips);
+    let mut source = ScheduledSource::new(
+        trig_rx,
+        bank,
+        mixer,
+        CHANNELS,
+        opts.sample_rate,
+        clock.clone(),
+    );
+
+    let mut engine = SequencerEngine::new(evt_tx, opts.sample_rate)?;
+    engine.handle_command(
+        SequencerCommand::Configure {
+            bpm: project.bpm,
+            steps: project.steps,
+            steps_per_beat: project.steps_per_beat,
+        },
+        0,
+    );
+    engine.handle_command(
+        SequencerCommand::SetBank {
+            patterns: project.bank(),
+        },
+        0,
+    );
+    engine.handle_command(
+        SequencerCommand::QueuePattern {
+            slot: opts.slot,
+            after: None,
+        },
+        0,
+    );
+    engine.handle_command(SequencerCommand::SetSong(project.song.clone()), 0);
+    engine.handle_command(SequencerCommand::SetSongMode(opts.song), 0);
+    engine.handle_command(SequencerCommand::SetSwing(project.swing), 0);
+    engine.handle_command(SequencerCommand::SetSeed(opts.seed), 0);
+    engine.handle_command(SequencerCommand::Play, 0);
+
+    let mut out = Vec::with_capacity(total as usize * CHANNELS as usize);
+    while clock.now() < total {
+        // schedule one block ahead; nothing starts after the last bar
+        engine.schedule_until((clock.now() + BLOCK_FRAMES * 2).min(end), &trig_tx);
+        let frames = BLOCK_FRAMES.min(total - clock.now());
+        out.extend(source.by_ref().take(frames as usize * CHANNELS as usize));
+    }
+    Ok(out)
+}
+
+/// Load the project's samples, render it and write the result to `path`.
+pub fn render_to_wav(project: &Project, opts: &RenderOptions, path: &Path) -> Result<()> {
+    let sample_format = match opts.bits_per_sample {
+        16 | 24 => hound::SampleFormat::Int,
+        32 => hound::SampleFormat::Float,
+        bits => bail!("unsupported bit depth {} (use 16, 24 or 32)", bits),
+    };
+
+    let bank = SampleBank::default();
+    for track in &project.tracks {
+        let pcm = crate::audio::loader::decode_file(&track.sample, opts.sample_rate)?;
+        bank.insert(&track.name, pcm);
+    }
+    let names: Vec<&str> = project.tracks.iter().map(|t| t.name.as_str()).collect();
+    bank.set_tracks(&names);
+
+    let samples = render_samples(project, bank, opts)?;
+
+    let spec = hound::WavSpec {
+        channels: CHANNELS,
+        sample_rate: opts.sample_rate,
+        bits_per_sample: opts.bits_per_sample,
+        sample_format,
+    };
+    let mut writer = hound::WavWriter::create(path, spec)?;
+    match sample_format {
+        hound::SampleFormat::Float => {
+            for s in samples {
+                writer.write_sample(s)?;
+            }
+        }
+        hound::SampleFormat::Int => {
+            let scale = (1i32 << (opts.bits_per_sample - 1)) - 1;
+            for s in samples {
+                writer.write_sample((s.clamp(-1.0, 1.0) * scale as f32) as i32)?;
+            }
+        }
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounce_has_expected_length_and_format() {
+        let project = Project::default();
+        let opts = RenderOptions {
+            bars: 2,
+            sample_rate: 48_000,
+            bits_per_sample: 24,
+            tail_secs: 0.5,
+            ..RenderOptions::default()
+        };
+        let path =
+            std::env::temp_dir().join(format!("cratebeat-bounce-test-{}.wav", std::process::id()));
+        render_to_wav(&project, &opts, &path).unwrap();
+
+        let reader = hound::WavReader::open(&path).unwrap();
+        let spec = reader.spec();
+        assert_eq!(spec.channels, 2);
+        assert_eq!(spec.sample_rate, 48_000);
+        assert_eq!(spec.bits_per_sample, 24);
+        // 2 bars at 120 BPM = 4 s, plus 0.5 s tail
+        assert_eq!(reader.duration(), 4 * 48_000 + 24_000);
+
+        // the default beat opens with a kick, so the first frame can't be silent
+        let first: Vec<i32> = reader.into_samples().take(2).map(|s| s.unwrap()).collect();
+        assert!(first.iter().any(|&s| s != 0));
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn bars_are_bounded() {
+        for bars in [0, MAX_BARS + 1] {
+            let opts = RenderOptions {
+                bars,
+                ..RenderOptions::default()
+            };
+            let err = render_samples(&Project::default(), SampleBank::default(), &opts)
+                .unwrap_err()
+                .to_string();
+            assert_eq!(err, format!("bars must be 1-{}, got {}", MAX_BARS, bars));
+        }
+    }
+
+    #[test]
+    fn bounce_args_take_an_optional_bar_count() {
+        assert_eq!(
+            parse_bounce_args("loop.wav").unwrap(),
+            ("loop.wav".into(), 1)
+        );
+        assert_eq!(
+            parse_bounce_args("my loop.wav 4").unwrap(),
+            ("my loop.wav".into(), 4)
+        );
+        assert_eq!(
+            parse_bounce_args("my loop.wav").unwrap(),
+            ("my loop.wav".into(), 1)
+        );
+        assert!(parse_bounce_args("loop.wav 0").is_err());
+        assert!(parse_bounce_args("loop.wav 1000").is_err());
+        assert!(parse_bounce_args("loop.wav 99999999999").is_err());
+    }
+}
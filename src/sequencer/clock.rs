@@ -1,8 +1,111 @@
 // BPM timing clock
-// Simple helpers for BPM -> step duration calculations.
+// Step timing in audio frames, computed exactly with integer math.
 
-pub fn step_duration_millis(bpm: u32, steps_per_beat: u32) -> u64 {
-    // one beat = 60_000 ms. If steps_per_beat is 4 (16th notes), we divide further.
-    let ms_per_beat = 60_000u64 / bpm as u64;
-    ms_per_beat / steps_per_beat as u64
+/// Tempo range accepted from the UI and remote control.
+pub const MIN_BPM: f64 = 20.0;
+pub const MAX_BPM: f64 = 300.0;
+/// Finest grid: 16 steps per beat is a 64th note.
+pub const MAX_STEPS_PER_BEAT: u32 = 16;
+/// Tempos are kept to hundredths of a BPM, so step lengths stay exact fractions.
+const BPM_SCALE: f64 = 100.0;
+
+/// Swing amounts, as the percentage of a step pair taken by its first step.
+/// 50% is straight, ~66% a triplet shuffle, 75% a dotted feel.
+pub const MIN_SWING: u8 = 50;
+pub const MAX_SWING: u8 = 75;
+
+/// Tempo grid for the sequencer.
+///
+/// A step lasts `sample_rate * 60 / (bpm * steps_per_beat)` frames, kept as a
+/// fraction with the tempo in hundredths of a BPM. Onsets are computed from
+/// the cumulative step count rather than by summing per-step durations, so no
+/// rounding error builds up across bars.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StepClock {
+    pub bpm: f64,
+    /// 4 for 16th notes, 3 or 6 for triplet feels, 8 for 32nds
+    pub steps_per_beat: u32,
+    pub sample_rate: u32,
+}
+
+impl StepClock {
+    pub fn new(bpm: f64, steps_per_beat: u32, sample_rate: u32) -> Self {
+        Self {
+            bpm: round_bpm(bpm).max(1.0 / BPM_SCALE),
+            steps_per_beat: steps_per_beat.max(1),
+            sample_rate,
+        }
+    }
+
+    /// Frames per step as an exact `(numerator, denominator)` fraction.
+    pub fn frames_per_step(&self) -> (u64, u64) {
+        (
+            self.sample_rate as u64 * 60 * BPM_SCALE as u64,
+            (self.bpm * BPM_SCALE).round() as u64 * self.steps_per_beat as u64,
+        )
+    }
+
+    /// Frame offset of step `n` from the start of the grid, rounded to the nearest frame.
+    /// `n` is the cumulative step count, not the index within the pattern.
+    pub fn step_offset(&self, n: u64) -> u64 {
+        let (num, den) = self.frames_per_step();
+        ((n as u128 * num as u128 + den as u128 / 2) / den as u128) as u64
+    }
+
+    /// How many frames an off-beat (odd) step is pushed back at `swing` percent.
+    /// At 75% it lands half a step late.
+    pub fn swing_delay(&self, swing: u8) -> u64 {
+        let (num, den) = self.frames_per_step();
+        let amount = swing.clamp(MIN_SWING, MAX_SWING) as u64 * 2 - 100;
+        ((num as u128 * amount as u128 + den as u128 * 50) / (den as u128 * 100)) as u64
+    }
+}
+
+/// `bpm` to the nearest hundredth, the finest tempo the clock keeps.
+pub fn round_bpm(bpm: f64) -> f64 {
+    (bpm * BPM_SCALE).round() / BPM_SCALE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_drift_over_a_minute() {
+        // 133 BPM triplets: 48000 * 60 / 399 = 7218.045.. frames per step
+        let clock = StepClock::new(133.0, 3, 48_000);
+        let steps_per_minute = 133 * 3;
+        assert_eq!(clock.step_offset(steps_per_minute), 60 * 48_000);
+    }
+
+    #[test]
+    fn common_subdivisions_are_exact() {
+        for steps_per_beat in [3, 4, 6, 8] {
+            let clock = StepClock::new(120.0, steps_per_beat, 48_000);
+            // one beat at 120 BPM is exactly half a second
+            assert_eq!(clock.step_offset(steps_per_beat as u64), 24_000);
+        }
+    }
+
+    #[test]
+    fn fractional_tempos_are_exact_to_a_hundredth() {
+        // 120.5 BPM 16ths: 482 steps take exactly a minute
+        let clock = StepClock::new(120.5, 4, 48_000);
+        assert_eq!(clock.step_offset(482), 60 * 48_000);
+        // finer tempos are rounded, not truncated
+        assert_eq!(StepClock::new(99.996, 4, 48_000).bpm, 100.0);
+        assert_eq!(clock, StepClock::new(120.504, 4, 48_000));
+    }
+
+    #[test]
+    fn swing_delay_is_a_fraction_of_the_step() {
+        // 120 BPM 16ths at 48 kHz => 6000 frames per step
+        let clock = StepClock::new(120.0, 4, 48_000);
+        for (swing, delay) in [(50, 0), (54, 480), (60, 1200), (66, 1920), (75, 3000)] {
+            assert_eq!(clock.swing_delay(swing), delay, "swing {}%", swing);
+        }
+        // out-of-range amounts are clamped
+        assert_eq!(clock.swing_delay(90), 3000);
+        assert_eq!(clock.swing_delay(0), 0);
+    }
 }
@@ -1,174 +1,1569 @@
 // Playback engine
-use std::sync::mpsc::{Receiver, Sender};
-use std::time::{Duration, Instant};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
 
-use crate::sequencer::clock::step_duration_millis;
-use crate::sequencer::steps::{empty_pattern, Pattern};
+use crate::audio::mixer::CLICK_TRACK;
+use crate::audio::scheduler::{FrameClock, Trigger};
+use crate::project::Track;
+use crate::sequencer::clock::{StepClock, MAX_BPM, MAX_SWING, MIN_BPM, MIN_SWING};
+use crate::sequencer::history::{CellChange, Change, History, TrackChange};
+use crate::sequencer::record::{snaps_forward, RecordSettings, MAX_COUNT_IN};
+use crate::sequencer::song::{SongEntry, SongPosition, BANK_SLOTS};
+use crate::sequencer::steps::{
+    empty_pattern, resize_pattern, velocity_gain, Condition, Pattern, Step, DEFAULT_VELOCITY,
+    MAX_STEPS, MAX_TRACKS, MAX_VELOCITY,
+};
 use anyhow::Result;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// How far ahead of the output stream hits are queued.
+const LOOKAHEAD_MS: u64 = 50;
+/// How often the engine wakes up to top up the look-ahead window while playing.
+const SCHEDULE_INTERVAL: Duration = Duration::from_millis(5);
 
 /// Commands sent to the engine thread
 #[derive(Debug)]
 pub enum SequencerCommand {
-    Configure { bpm: u32, steps: usize },
-    SetPattern { pattern: Pattern },
-    ToggleStep { track: usize, step: usize },
+    Configure {
+        bpm: f64,
+        steps: usize,
+        steps_per_beat: u32,
+    },
+    /// Replace the pattern in the current bank slot. A pattern of the grid's
+    /// shape is an undoable edit; one that reshapes the grid starts a fresh
+    /// history, as edits before it no longer line up with the tracks.
+    SetPattern {
+        pattern: Pattern,
+    },
+    /// Replace the whole bank, e.g. on opening a project; missing slots are
+    /// left empty. The history starts afresh, so this can't be undone.
+    SetBank {
+        patterns: Vec<Pattern>,
+    },
+    /// Play another bank slot: right away when stopped, otherwise from the
+    /// next bar, or after `after` more steps when given. Leaves song mode.
+    QueuePattern {
+        slot: usize,
+        after: Option<usize>,
+    },
+    /// Replace the arrangement
+    SetSong(Vec<SongEntry>),
+    /// Follow the arrangement instead of looping one pattern
+    SetSongMode(bool),
+    ToggleStep {
+        track: usize,
+        step: usize,
+    },
+    /// Swing percentage, 50 (straight) to 75
+    SetSwing(u8),
+    /// Set a step's velocity; 0 turns it off
+    SetVelocity {
+        track: usize,
+        step: usize,
+        velocity: u8,
+    },
+    /// Set a step's trigger probability, 0-100%
+    SetProbability {
+        track: usize,
+        step: usize,
+        probability: u8,
+    },
+    SetCondition {
+        track: usize,
+        step: usize,
+        condition: Option<Condition>,
+    },
+    /// Write several cells at once: (track, step, contents)
+    SetSteps(Vec<(usize, usize, Step)>),
+    /// Turn fill mode on or off, for `fill` / `!fill` conditions
+    SetFill(bool),
+    /// Seed for probability rolls; the sequence restarts from it on every play
+    SetSeed(u64),
+    /// Append an empty track. Its settings are kept with the undo history
+    /// and handed back if a redo adds it again.
+    AddTrack(Track),
+    /// Append `added` empty tracks for a kit's extra sounds. A kit rewrites
+    /// the track settings that undo entries carry, so history starts afresh.
+    LoadKit {
+        added: usize,
+    },
+    /// Remove a track from every slot; `settings` come back with an undo
+    RemoveTrack {
+        track: usize,
+        settings: Track,
+    },
+    /// Change the step count, keeping steps that still fit
+    ResizePattern {
+        steps: usize,
+    },
     TogglePlay,
     Play,
     Stop,
+    /// Nudge the tempo by whole BPM
     AdjustBpm(i32),
+    /// Set the tempo outright, e.g. from tap tempo; clamped to the supported
+    /// range and kept to hundredths of a BPM
+    SetBpm(f64),
+    /// Revert the latest pattern edit, in whichever slot it was made
+    Undo,
+    /// Make the latest undone edit again
+    Redo,
+    /// Start or stop recording pad hits. Starting from a stop plays the
+    /// count-in first.
+    Record(bool),
+    SetRecordSettings(RecordSettings),
+    /// A pad was hit at `frame` of the output stream; while recording it is
+    /// quantized onto a step of the playing pattern
+    RecordHit {
+        track: usize,
+        velocity: u8,
+        frame: u64,
+    },
 }
 
 /// Events emitted by the engine (for UI / app)
 #[derive(Debug)]
 pub enum SequencerEvent {
-    Step { step: usize, hits: Vec<bool> },
-    PlaybackState(bool),
+    /// A step was queued; `hits` holds each track's velocity on it (0 = silent)
+    Step { step: usize, hits: Vec<u8> },
+    /// The engine's state after a command changed it
+    State(Snapshot),
+    /// A queued pattern or the arrangement took over during playback
+    PatternSwitched { slot: usize },
+    /// The whole bank after an undo or redo, which may have changed slots
+    /// other than the playing one
+    Bank(Vec<Pattern>),
+    /// An undo or redo put a track back at `track`; sent before the bank
+    /// that holds its rows
+    TrackInserted { track: usize, settings: Track },
+    /// An undo or redo took the track at `track` out again
+    TrackRemoved { track: usize },
+}
+
+/// Everything the UI needs to draw the grid, as the engine sees it.
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    /// The pattern in the current slot
+    pub pattern: Pattern,
+    pub slot: usize,
+    /// Slot that takes over at the next bar (or after the requested steps)
+    pub queued: Option<usize>,
+    /// Which bank slots hold any hits
+    pub used: Vec<bool>,
+    pub song: Vec<SongEntry>,
+    pub song_mode: bool,
+    pub song_position: SongPosition,
+    pub steps: usize,
+    pub bpm: f64,
+    pub swing: u8,
+    pub fill: bool,
+    pub playing: bool,
+    /// Edits that can be undone and redone
+    pub history: (usize, usize),
+    pub recording: bool,
+    pub record: RecordSettings,
+    /// Beats of count-in still to go
+    pub count_in: u64,
 }
 
 /// Small sequencer engine that runs in its own thread and responds to commands.
-/// It uses a blocking receive for commands but still maintains timing using precise sleep.
+/// Step onsets are computed in audio frames and queued into the output stream
+/// ahead of time, so hits are locked to the sample clock rather than to thread wake-ups.
 pub struct SequencerEngine {
     evt_tx: Sender<SequencerEvent>,
 
     // internal state
-    bpm: u32,
+    tempo: StepClock,
+    swing: u8,
     steps: usize,
-    pattern: Pattern,
     playing: bool,
+
+    // every slot holds a tracks x steps grid; `slot` is the one playing
+    // and being edited
+    bank: Vec<Pattern>,
+    slot: usize,
+    queued: Option<usize>,
+    // `steps_played` count at which `queued` takes over
+    switch_at: u64,
+    song: Vec<SongEntry>,
+    song_mode: bool,
+    song_position: SongPosition,
+
+    // trig condition state: completed loops of this pattern, fill mode, the
+    // outcome of each track's last conditional step (for `pre`) and the
+    // random source for probabilities, reseeded on play so runs repeat
+    loop_count: u64,
+    fill: bool,
+    last_outcome: Vec<bool>,
+    seed: u64,
+    rng: StdRng,
+
+    // pattern edits, for undo / redo
+    history: History,
+
+    // live recording: count-in steps still to click through before the
+    // pattern starts, and the tracks already hit this take (replace mode
+    // clears a track's row on its first hit)
+    recording: bool,
+    record: RecordSettings,
+    count_in: u64,
+    take: Vec<usize>,
+
+    // scheduling state: onsets are `tempo.step_offset(steps_since_anchor)` frames
+    // after `anchor_frame`, which moves whenever the tempo changes so earlier
+    // steps keep their positions
+    anchor_frame: u64,
+    steps_since_anchor: u64,
+    next_step: usize,
+    // steps played since play, for finding bar lines
+    steps_played: u64,
 }
 
 impl SequencerEngine {
-    pub fn new(evt_tx: Sender<SequencerEvent>) -> Result<Self> {
+    pub fn new(evt_tx: Sender<SequencerEvent>, sample_rate: u32) -> Result<Self> {
         Ok(Self {
             evt_tx,
-            bpm: 120,
+            tempo: StepClock::new(120.0, 4, sample_rate),
+            swing: MIN_SWING,
             steps: 8,
-            pattern: empty_pattern(4, 8),
             playing: false,
+            bank: vec![Pattern::new(); BANK_SLOTS],
+            slot: 0,
+            queued: None,
+            switch_at: 0,
+            song: Vec::new(),
+            song_mode: false,
+            song_position: SongPosition::default(),
+            loop_count: 0,
+            fill: false,
+            last_outcome: Vec::new(),
+            seed: 0,
+            rng: StdRng::seed_from_u64(0),
+            history: History::default(),
+            recording: false,
+            record: RecordSettings::default(),
+            count_in: 0,
+            take: Vec::new(),
+            anchor_frame: 0,
+            steps_since_anchor: 0,
+            next_step: 0,
+            steps_played: 0,
         })
     }
 
-    /// Run the engine. This method blocks. It expects a receiver for commands.
-    /// It contains a loop that checks for commands and when playing, advances steps using precise timing.
-    pub fn run(&mut self, cmd_rx: Receiver<SequencerCommand>) {
-        let mut step_idx = 0usize;
-        // last tick time
-        let mut last_instant = Instant::now();
+    /// Run the engine. This method blocks. It expects a receiver for commands,
+    /// the output stream's frame clock and a sender for hits queued on that clock.
+    pub fn run(
+        &mut self,
+        cmd_rx: Receiver<SequencerCommand>,
+        clock: FrameClock,
+        trig_tx: Sender<Trigger>,
+    ) {
+        let lookahead = self.tempo.sample_rate as u64 * LOOKAHEAD_MS / 1000;
 
         loop {
-            // Calculate step duration in ms (assume 4 steps per beat for 16th notes)
-            let step_ms = step_duration_millis(self.bpm, 4);
-
-            // Non-blocking try_recv to handle commands quickly
-            while let Ok(cmd) = cmd_rx.try_recv() {
-                match cmd {
-                    SequencerCommand::Configure { bpm, steps } => {
-                        self.bpm = bpm;
-                        self.steps = steps;
-                        self.pattern = empty_pattern(4, steps);
-                    }
-                    SequencerCommand::SetPattern { pattern } => {
-                        self.pattern = pattern;
-                        self.steps = self.pattern.get(0).map(|r| r.len()).unwrap_or(self.steps);
-                    }
-                    SequencerCommand::ToggleStep { track, step } => {
-                        if let Some(row) = self.pattern.get_mut(track) {
-                            if step < row.len() {
-                                row[step] = !row[step];
-                            }
-                        }
-                    }
-                    SequencerCommand::TogglePlay => {
-                        self.playing = !self.playing;
-                        let _ = self
-                            .evt_tx
-                            .send(SequencerEvent::PlaybackState(self.playing));
-                    }
-                    SequencerCommand::Play => {
-                        self.playing = true;
-                        let _ = self.evt_tx.send(SequencerEvent::PlaybackState(true));
-                    }
-                    SequencerCommand::Stop => {
-                        self.playing = false;
-                        let _ = self.evt_tx.send(SequencerEvent::PlaybackState(false));
-                    }
-                    SequencerCommand::AdjustBpm(delta) => {
-                        let new_bpm = (self.bpm as i32 + delta).clamp(20, 300) as u32;
-                        self.bpm = new_bpm;
-                    }
+            let cmd = if self.playing {
+                match cmd_rx.recv_timeout(SCHEDULE_INTERVAL) {
+                    Ok(cmd) => Some(cmd),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            } else {
+                // when not playing we block waiting for commands to avoid CPU spin
+                match cmd_rx.recv() {
+                    Ok(cmd) => Some(cmd),
+                    // channel closed => exit thread
+                    Err(_) => break,
+                }
+            };
+
+            if let Some(cmd) = cmd {
+                // playback started now begins one look-ahead window in the future
+                let start = clock.now() + lookahead;
+                self.handle_command(cmd, start);
+                while let Ok(cmd) = cmd_rx.try_recv() {
+                    self.handle_command(cmd, start);
                 }
             }
 
             if self.playing {
-                let now = Instant::now();
-                if now.duration_since(last_instant) >= Duration::from_millis(step_ms) {
-                    // advance step
-                    last_instant = now;
-                    // collect hits for this step
-                    let mut hits = vec![];
-                    for track in 0..self.pattern.len() {
-                        let val = self.pattern[track][step_idx % self.steps];
-                        hits.push(val);
+                self.schedule_until(clock.now() + lookahead, &trig_tx);
+            }
+        }
+    }
+
+    /// Apply a command. `start_frame` is where playback begins if this command starts it.
+    pub fn handle_command(&mut self, cmd: SequencerCommand, start_frame: u64) {
+        match cmd {
+            SequencerCommand::Configure {
+                bpm,
+                steps,
+                steps_per_beat,
+            } => {
+                self.set_tempo(bpm, steps_per_beat);
+                self.steps = steps.clamp(1, MAX_STEPS);
+                let tracks = self.tracks();
+                for pattern in &mut self.bank {
+                    *pattern = empty_pattern(tracks, self.steps);
+                }
+                self.history.clear();
+            }
+            SequencerCommand::SetPattern { pattern } => {
+                let same_shape = pattern.len() == self.tracks()
+                    && pattern.iter().all(|row| row.len() == self.steps);
+                if same_shape {
+                    // e.g. a MIDI import: undoable like any other edit
+                    let cells: Vec<_> = pattern
+                        .iter()
+                        .enumerate()
+                        .flat_map(|(track, row)| {
+                            row.iter()
+                                .enumerate()
+                                .map(move |(step, &cell)| (track, step, cell))
+                        })
+                        .collect();
+                    self.write_cells(cells);
+                } else {
+                    let steps = pattern.first().map_or(self.steps, Vec::len);
+                    let tracks = pattern.len();
+                    self.bank[self.slot] = pattern;
+                    self.conform(tracks, steps);
+                    self.history.clear();
+                }
+            }
+            SequencerCommand::SetBank { mut patterns } => {
+                let tracks = patterns.first().map_or(self.tracks(), |p| p.len());
+                let steps = patterns
+                    .first()
+                    .and_then(|p| p.first())
+                    .map_or(self.steps, Vec::len);
+                patterns.resize(BANK_SLOTS, Pattern::new());
+                patterns.truncate(BANK_SLOTS);
+                self.bank = patterns;
+                self.conform(tracks, steps);
+                self.history.clear();
+            }
+            SequencerCommand::QueuePattern { slot, after } => {
+                self.song_mode = false;
+                let slot = slot.min(BANK_SLOTS - 1);
+                if !self.playing {
+                    self.load_slot(slot);
+                } else if slot != self.slot {
+                    self.queued = Some(slot);
+                    self.switch_at = match after {
+                        Some(steps) => self.steps_played + steps.max(1) as u64,
+                        None => {
+                            let bar = 4 * self.tempo.steps_per_beat as u64;
+                            (self.steps_played / bar + 1) * bar
+                        }
+                    };
+                } else {
+                    self.queued = None;
+                }
+            }
+            SequencerCommand::SetSong(song) => {
+                self.song = song;
+                self.song_mode &= !self.song.is_empty();
+                if self.song_position.entry >= self.song.len() {
+                    self.song_position = SongPosition::default();
+                }
+            }
+            SequencerCommand::SetSongMode(on) => {
+                self.song_mode = on && !self.song.is_empty();
+                self.queued = None;
+                if self.song_mode && !self.playing {
+                    self.song_position = SongPosition::default();
+                    self.load_slot(self.song[0].pattern);
+                }
+            }
+            SequencerCommand::ToggleStep { track, step } => {
+                self.edit_cell(track, step, |cell| {
+                    if cell.is_on() {
+                        Step::REST
+                    } else {
+                        Step::hit(DEFAULT_VELOCITY)
                     }
-                    let _ = self.evt_tx.send(SequencerEvent::Step {
-                        step: step_idx % self.steps,
-                        hits,
+                });
+            }
+            SequencerCommand::SetSwing(swing) => {
+                self.swing = swing.clamp(MIN_SWING, MAX_SWING);
+            }
+            SequencerCommand::SetVelocity {
+                track,
+                step,
+                velocity,
+            } => {
+                self.edit_cell(track, step, |cell| Step {
+                    velocity: velocity.min(MAX_VELOCITY),
+                    ..cell
+                });
+            }
+            SequencerCommand::SetProbability {
+                track,
+                step,
+                probability,
+            } => {
+                self.edit_cell(track, step, |cell| Step {
+                    probability: probability.min(100),
+                    ..cell
+                });
+            }
+            SequencerCommand::SetCondition {
+                track,
+                step,
+                condition,
+            } => {
+                self.edit_cell(track, step, |cell| Step { condition, ..cell });
+            }
+            SequencerCommand::SetSteps(cells) => {
+                let cells = cells.into_iter().map(|(track, step, new)| {
+                    let new = Step {
+                        velocity: new.velocity.min(MAX_VELOCITY),
+                        probability: new.probability.min(100),
+                        ..new
+                    };
+                    (track, step, new)
+                });
+                self.write_cells(cells);
+            }
+            SequencerCommand::SetFill(fill) => self.fill = fill,
+            SequencerCommand::SetSeed(seed) => {
+                self.seed = seed;
+                self.rng = StdRng::seed_from_u64(seed);
+            }
+            SequencerCommand::AddTrack(settings) => {
+                if self.tracks() < MAX_TRACKS {
+                    let track = self.tracks();
+                    let rows = vec![vec![Step::REST; self.steps]; BANK_SLOTS];
+                    self.insert_track(track, &rows);
+                    self.history.record(Change {
+                        track: Some(TrackChange {
+                            track,
+                            settings,
+                            rows,
+                            added: true,
+                        }),
+                        ..Change::default()
                     });
-                    step_idx = (step_idx + 1) % self.steps;
-                } else {
-                    // small sleep to avoid busy loop
-                    std::thread::sleep(Duration::from_millis(1));
                 }
-            } else {
-                // when not playing we block waiting for commands to avoid CPU spin
-                match cmd_rx.recv() {
-                    Ok(cmd) => {
-                        // re-send the command into the regular handling path by pushing to a short buffer:
-                        // we simply handle it right here (duplicate of above)
-                        match cmd {
-                            SequencerCommand::Configure { bpm, steps } => {
-                                self.bpm = bpm;
-                                self.steps = steps;
-                                self.pattern = empty_pattern(4, steps);
-                            }
-                            SequencerCommand::SetPattern { pattern } => {
-                                self.pattern = pattern;
-                                self.steps =
-                                    self.pattern.get(0).map(|r| r.len()).unwrap_or(self.steps);
-                            }
-                            SequencerCommand::ToggleStep { track, step } => {
-                                if let Some(row) = self.pattern.get_mut(track) {
-                                    if step < row.len() {
-                                        row[step] = !row[step];
-                                    }
-                                }
-                            }
-                            SequencerCommand::TogglePlay => {
-                                self.playing = !self.playing;
-                                let _ = self
-                                    .evt_tx
-                                    .send(SequencerEvent::PlaybackState(self.playing));
-                            }
-                            SequencerCommand::Play => {
-                                self.playing = true;
-                                let _ = self.evt_tx.send(SequencerEvent::PlaybackState(true));
-                            }
-                            SequencerCommand::Stop => {
-                                self.playing = false;
-                                let _ = self.evt_tx.send(SequencerEvent::PlaybackState(false));
-                            }
-                            SequencerCommand::AdjustBpm(delta) => {
-                                let new_bpm = (self.bpm as i32 + delta).clamp(20, 300) as u32;
-                                self.bpm = new_bpm;
+            }
+            SequencerCommand::LoadKit { added } => {
+                let rows = vec![vec![Step::REST; self.steps]; BANK_SLOTS];
+                for _ in 0..added.min(MAX_TRACKS.saturating_sub(self.tracks())) {
+                    self.insert_track(self.tracks(), &rows);
+                }
+                self.history.clear();
+            }
+            SequencerCommand::RemoveTrack { track, settings } => {
+                if track < self.tracks() && self.tracks() > 1 {
+                    let rows = self.remove_track(track);
+                    self.history.record(Change {
+                        track: Some(TrackChange {
+                            track,
+                            settings,
+                            rows,
+                            added: false,
+                        }),
+                        ..Change::default()
+                    });
+                }
+            }
+            SequencerCommand::ResizePattern { steps } => {
+                let steps = steps.clamp(1, MAX_STEPS);
+                let mut change = Change {
+                    steps: (steps != self.steps).then_some((self.steps, steps)),
+                    ..Change::default()
+                };
+                // hits a shrink cuts off come back on undo
+                for (slot, pattern) in self.bank.iter().enumerate() {
+                    for (track, row) in pattern.iter().enumerate() {
+                        for (step, &before) in row.iter().enumerate().skip(steps) {
+                            if before != Step::REST {
+                                change.cells.push(CellChange {
+                                    slot,
+                                    track,
+                                    step,
+                                    before,
+                                    after: Step::REST,
+                                });
                             }
                         }
                     }
-                    Err(_) => {
-                        // channel closed => exit thread
-                        break;
+                }
+                self.resize_bank(steps);
+                self.history.record(change);
+            }
+            SequencerCommand::TogglePlay => {
+                if self.playing {
+                    self.stop();
+                } else {
+                    self.start(start_frame);
+                }
+            }
+            SequencerCommand::Play => self.start(start_frame),
+            SequencerCommand::Stop => self.stop(),
+            SequencerCommand::AdjustBpm(delta) => {
+                let bpm = (self.tempo.bpm + delta as f64).clamp(MIN_BPM, MAX_BPM);
+                self.set_tempo(bpm, self.tempo.steps_per_beat);
+            }
+            SequencerCommand::SetBpm(bpm) => {
+                if bpm.is_finite() {
+                    self.set_tempo(bpm.clamp(MIN_BPM, MAX_BPM), self.tempo.steps_per_beat);
+                }
+            }
+            SequencerCommand::Undo => {
+                if let Some(change) = self.history.undo() {
+                    self.revert(&change, false);
+                }
+            }
+            SequencerCommand::Redo => {
+                if let Some(change) = self.history.redo() {
+                    self.revert(&change, true);
+                }
+            }
+            SequencerCommand::Record(on) => {
+                self.take.clear();
+                if on && !self.playing {
+                    self.recording = true;
+                    self.start(start_frame);
+                } else {
+                    self.recording = on;
+                }
+            }
+            SequencerCommand::SetRecordSettings(settings) => {
+                self.record = RecordSettings {
+                    quantize: settings.quantize.min(100),
+                    count_in: settings.count_in.min(MAX_COUNT_IN),
+                    ..settings
+                };
+            }
+            SequencerCommand::RecordHit {
+                track,
+                velocity,
+                frame,
+            } => {
+                // pads are played all the time; only hits in a take change
+                // anything worth reporting
+                if !self.recording || self.count_in > 0 || track >= self.tracks() {
+                    return;
+                }
+                let step = self.hit_step(frame);
+                let mut cells = Vec::new();
+                if self.record.replace && !self.take.contains(&track) {
+                    self.take.push(track);
+                    cells.extend((0..self.steps).map(|step| (track, step, Step::REST)));
+                }
+                cells.push((track, step, Step::hit(velocity.min(MAX_VELOCITY))));
+                self.write_cells(cells);
+            }
+        }
+        let _ = self.evt_tx.send(SequencerEvent::State(self.snapshot()));
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            pattern: self.bank[self.slot].clone(),
+            slot: self.slot,
+            queued: self.queued,
+            used: self
+                .bank
+                .iter()
+                .map(|p| p.iter().flatten().any(Step::is_on))
+                .collect(),
+            song: self.song.clone(),
+            song_mode: self.song_mode,
+            song_position: self.song_position,
+            steps: self.steps,
+            bpm: self.tempo.bpm,
+            swing: self.swing,
+            fill: self.fill,
+            playing: self.playing,
+            history: self.history.depth(),
+            recording: self.recording,
+            record: self.record,
+            count_in: self.count_in.div_ceil(self.tempo.steps_per_beat as u64),
+        }
+    }
+
+    /// Queue every step whose onset falls before `horizon` (exclusive).
+    pub fn schedule_until(&mut self, horizon: u64, trig_tx: &Sender<Trigger>) {
+        if !self.playing || self.steps == 0 {
+            return;
+        }
+
+        loop {
+            let step = self.next_step % self.steps;
+            let mut frame = self.next_step_frame();
+            if step % 2 == 1 {
+                frame += self.tempo.swing_delay(self.swing);
+            }
+            if frame >= horizon {
+                break;
+            }
+
+            if self.count_in > 0 {
+                // count-in steps only click, on the beat and louder on the bar
+                let beat = self.tempo.steps_per_beat as u64;
+                if self.count_in.is_multiple_of(beat) {
+                    let downbeat = self.count_in.is_multiple_of(4 * beat);
+                    let _ = trig_tx.send(Trigger {
+                        frame,
+                        track: CLICK_TRACK,
+                        gain: if downbeat { 1.0 } else { 0.6 },
+                    });
+                }
+                self.count_in -= 1;
+                self.steps_since_anchor += 1;
+                if self.count_in.is_multiple_of(beat) {
+                    let _ = self.evt_tx.send(SequencerEvent::State(self.snapshot()));
+                }
+                continue;
+            }
+
+            self.last_outcome.resize(self.tracks(), false);
+            let mut hits = vec![];
+            for track in 0..self.tracks() {
+                let cell = self.bank[self.slot][track]
+                    .get(step)
+                    .copied()
+                    .unwrap_or_default();
+                let velocity = if cell.is_on() && self.should_play(track, cell) {
+                    let _ = trig_tx.send(Trigger {
+                        frame,
+                        track,
+                        gain: velocity_gain(cell.velocity),
+                    });
+                    cell.velocity
+                } else {
+                    0
+                };
+                hits.push(velocity);
+            }
+            let _ = self.evt_tx.send(SequencerEvent::Step { step, hits });
+
+            self.next_step = (step + 1) % self.steps;
+            self.steps_since_anchor += 1;
+            self.steps_played += 1;
+            if self.next_step == 0 {
+                self.loop_count += 1;
+                if self.song_mode {
+                    if let Some(slot) = self.song_position.advance(&self.song) {
+                        self.switch_to(slot);
                     }
                 }
             }
+            if let Some(slot) = self.queued {
+                if self.steps_played >= self.switch_at {
+                    self.switch_to(slot);
+                }
+            }
+        }
+    }
+
+    /// Make `slot` the playing pattern, from its first step.
+    fn load_slot(&mut self, slot: usize) {
+        self.slot = slot;
+        self.queued = None;
+        self.next_step = 0;
+        self.loop_count = 0;
+        self.last_outcome.clear();
+    }
+
+    /// `load_slot` during playback, where no command is around to report it.
+    fn switch_to(&mut self, slot: usize) {
+        self.load_slot(slot);
+        let _ = self.evt_tx.send(SequencerEvent::PatternSwitched { slot });
+        let _ = self.evt_tx.send(SequencerEvent::State(self.snapshot()));
+    }
+
+    fn tracks(&self) -> usize {
+        self.bank[self.slot].len()
+    }
+
+    /// Pad or trim every slot to `tracks` x `steps`, within the grid limits.
+    fn conform(&mut self, tracks: usize, steps: usize) {
+        let tracks = tracks.min(MAX_TRACKS);
+        self.steps = steps.clamp(1, MAX_STEPS);
+        for pattern in &mut self.bank {
+            pattern.resize(tracks, vec![Step::REST; self.steps]);
+            resize_pattern(pattern, self.steps);
         }
+        self.next_step %= self.steps;
+    }
+
+    /// Check `cell`'s condition and roll for its probability.
+    fn should_play(&mut self, track: usize, cell: Step) -> bool {
+        if !cell.is_conditional() {
+            return true;
+        }
+        let condition_met = match cell.condition {
+            None => true,
+            Some(Condition::Ratio { a, b }) => self.loop_count % b as u64 == a as u64 - 1,
+            Some(Condition::Fill) => self.fill,
+            Some(Condition::NotFill) => !self.fill,
+            Some(Condition::First) => self.loop_count == 0,
+            Some(Condition::NotFirst) => self.loop_count > 0,
+            Some(Condition::Pre) => self.last_outcome[track],
+            Some(Condition::NotPre) => !self.last_outcome[track],
+        };
+        let play = condition_met
+            && (cell.probability >= 100 || self.rng.gen_range(0..100) < cell.probability);
+        // `pre` looks back past other `pre` steps, like on the hardware
+        if !matches!(cell.condition, Some(Condition::Pre | Condition::NotPre)) {
+            self.last_outcome[track] = play;
+        }
+        play
+    }
+
+    /// Rewrite one cell of the playing slot from its current contents.
+    fn edit_cell(&mut self, track: usize, step: usize, f: impl FnOnce(Step) -> Step) {
+        let Some(&cell) = self.bank[self.slot]
+            .get(track)
+            .and_then(|row| row.get(step))
+        else {
+            return;
+        };
+        self.write_cells([(track, step, f(cell))]);
+    }
+
+    /// Write cells of the playing slot as one undoable edit. Cells off the
+    /// grid are ignored.
+    fn write_cells(&mut self, cells: impl IntoIterator<Item = (usize, usize, Step)>) {
+        let slot = self.slot;
+        let mut change = Change::default();
+        for (track, step, after) in cells {
+            let Some(cell) = self.bank[slot]
+                .get_mut(track)
+                .and_then(|row| row.get_mut(step))
+            else {
+                continue;
+            };
+            if *cell != after {
+                change.cells.push(CellChange {
+                    slot,
+                    track,
+                    step,
+                    before: *cell,
+                    after,
+                });
+                *cell = after;
+            }
+        }
+        self.history.record(change);
+    }
+
+    /// Put back the bank as it was before `change`, or after it for a redo,
+    /// and report every slot since any of them may have changed.
+    fn revert(&mut self, change: &Change, redo: bool) {
+        // a track change is only ever an edit of its own
+        if let Some(c) = &change.track {
+            if c.added == redo {
+                self.insert_track(c.track, &c.rows);
+                let _ = self.evt_tx.send(SequencerEvent::TrackInserted {
+                    track: c.track,
+                    settings: c.settings.clone(),
+                });
+            } else {
+                self.remove_track(c.track);
+                let _ = self
+                    .evt_tx
+                    .send(SequencerEvent::TrackRemoved { track: c.track });
+            }
+        }
+        // resize first: an undone shrink regrows before its cells come back,
+        // and a redone one drops them
+        if let Some((before, after)) = change.steps {
+            self.resize_bank(if redo { after } else { before });
+        }
+        // a cell written twice in one edit has to end up at its first `before`
+        let mut cells: Vec<&CellChange> = change.cells.iter().collect();
+        if !redo {
+            cells.reverse();
+        }
+        for c in cells {
+            let cell = self
+                .bank
+                .get_mut(c.slot)
+                .and_then(|pattern| pattern.get_mut(c.track))
+                .and_then(|row| row.get_mut(c.step));
+            if let Some(cell) = cell {
+                *cell = if redo { c.after } else { c.before };
+            }
+        }
+        let _ = self.evt_tx.send(SequencerEvent::Bank(self.bank.clone()));
+    }
+
+    /// Put `rows` (one per slot) in at `track` in every slot.
+    fn insert_track(&mut self, track: usize, rows: &[Vec<Step>]) {
+        for (pattern, row) in self.bank.iter_mut().zip(rows) {
+            let mut row = row.clone();
+            row.resize(self.steps, Step::REST);
+            pattern.insert(track.min(pattern.len()), row);
+        }
+        // per-track bookkeeping moves with the tracks after it
+        if track <= self.last_outcome.len() {
+            self.last_outcome.insert(track, false);
+        }
+        for t in &mut self.take {
+            if *t >= track {
+                *t += 1;
+            }
+        }
+    }
+
+    /// Take `track` out of every slot, returning its rows.
+    fn remove_track(&mut self, track: usize) -> Vec<Vec<Step>> {
+        let rows = self
+            .bank
+            .iter_mut()
+            .map(|pattern| pattern.remove(track))
+            .collect();
+        if track < self.last_outcome.len() {
+            self.last_outcome.remove(track);
+        }
+        self.take.retain(|&t| t != track);
+        for t in &mut self.take {
+            if *t > track {
+                *t -= 1;
+            }
+        }
+        rows
+    }
+
+    fn resize_bank(&mut self, steps: usize) {
+        self.steps = steps;
+        for pattern in &mut self.bank {
+            resize_pattern(pattern, self.steps);
+        }
+        self.next_step %= self.steps;
+    }
+
+    /// The step of the playing pattern a hit at `frame` belongs on: the one
+    /// it fell in, or the next one if it's close enough for the quantize
+    /// strength. Onsets are measured from the next unscheduled step, swing
+    /// included, so hits already in the past and ones ahead both work.
+    fn hit_step(&self, frame: u64) -> usize {
+        let (num, den) = self.tempo.frames_per_step();
+        let step_len = num as f64 / den as f64;
+        let swing = self.tempo.swing_delay(self.swing) as f64;
+        let next = self.next_step as i64;
+        let steps = self.steps as i64;
+        // onset of the step `n` steps on from the next one, relative to it
+        let onset = |n: i64| {
+            let odd = (next + n).rem_euclid(steps) % 2 == 1;
+            n as f64 * step_len + if odd { swing } else { 0.0 }
+        };
+
+        let at = frame as f64 - self.next_step_frame() as f64;
+        let mut before = (at / step_len).floor() as i64;
+        while onset(before) > at {
+            before -= 1;
+        }
+        while onset(before + 1) <= at {
+            before += 1;
+        }
+        let fraction = (at - onset(before)) / (onset(before + 1) - onset(before));
+        let n = if snaps_forward(fraction, self.record.quantize) {
+            before + 1
+        } else {
+            before
+        };
+        (next + n).rem_euclid(steps) as usize
+    }
+
+    fn next_step_frame(&self) -> u64 {
+        self.anchor_frame + self.tempo.step_offset(self.steps_since_anchor)
+    }
+
+    fn set_tempo(&mut self, bpm: f64, steps_per_beat: u32) {
+        if self.playing {
+            // re-anchor on the next unscheduled step so the tempo change
+            // doesn't shift steps that are already queued
+            self.anchor_frame = self.next_step_frame();
+            self.steps_since_anchor = 0;
+        }
+        self.tempo = StepClock::new(bpm, steps_per_beat, self.tempo.sample_rate);
+    }
+
+    fn start(&mut self, start_frame: u64) {
+        if !self.playing {
+            self.playing = true;
+            self.anchor_frame = start_frame;
+            self.steps_since_anchor = 0;
+            self.steps_played = 0;
+            self.rng = StdRng::seed_from_u64(self.seed);
+            self.count_in = if self.recording {
+                self.record.count_in as u64 * 4 * self.tempo.steps_per_beat as u64
+            } else {
+                0
+            };
+            if self.song_mode {
+                self.song_position = SongPosition::default();
+                self.load_slot(self.song[0].pattern);
+            } else {
+                self.load_slot(self.queued.unwrap_or(self.slot));
+            }
+        }
+    }
+
+    fn stop(&mut self) {
+        self.playing = false;
+        self.recording = false;
+        self.count_in = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::loader::decode_bytes;
+    use crate::audio::mixer::MixerControls;
+    use crate::audio::player::SampleBank;
+    use crate::audio::scheduler::ScheduledSource;
+    use crate::sequencer::record::RecordSettings;
+    use crate::sequencer::steps::MAX_VELOCITY;
+    use std::io::Cursor;
+    use std::sync::mpsc;
+    use std::sync::mpsc::Receiver;
+
+    const RATE: u32 = 48_000;
+
+    /// A mono WAV holding a single full-scale sample, so every onset shows up
+    /// as exactly one non-zero frame in the render.
+    fn impulse_wav() -> Vec<u8> {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut cursor = Cursor::new(Vec::new());
+        let mut writer = hound::WavWriter::new(&mut cursor, spec).unwrap();
+        writer.write_sample(i16::MAX).unwrap();
+        writer.write_sample(0i16).unwrap();
+        writer.finalize().unwrap();
+        cursor.into_inner()
+    }
+
+    /// Render `frames` frames of `pattern` without an output device and
+    /// return the frame offset and level of every onset.
+    fn render_hits(
+        bpm: f64,
+        steps_per_beat: u32,
+        swing: u8,
+        pattern: Pattern,
+        frames: u64,
+    ) -> Vec<(u64, f32)> {
+        let bank = SampleBank::default();
+        bank.insert("impulse", decode_bytes(impulse_wav(), RATE).unwrap());
+        bank.set_tracks(&vec!["impulse"; pattern.len()]);
+
+        let (evt_tx, _evt_rx) = mpsc::channel();
+        let (trig_tx, trig_rx) = mpsc::channel();
+        let clock = FrameClock::new();
+        let mixer = MixerControls::default();
+        let mut source = ScheduledSource::new(trig_rx, bank, mixer, 1, RATE, clock.clone());
+
+        let mut engine = SequencerEngine::new(evt_tx, RATE).unwrap();
+        engine.handle_command(
+            SequencerCommand::Configure {
+                bpm,
+                steps: 8,
+                steps_per_beat,
+            },
+            0,
+        );
+        engine.handle_command(SequencerCommand::SetPattern { pattern }, 0);
+        engine.handle_command(SequencerCommand::SetSwing(swing), 0);
+        engine.handle_command(SequencerCommand::Play, 0);
+
+        // render in small blocks, scheduling one block ahead like the engine thread does
+        let block = 256;
+        let mut onsets = vec![];
+        while clock.now() < frames {
+            engine.schedule_until(clock.now() + block * 2, &trig_tx);
+            for _ in 0..block.min(frames - clock.now()) {
+                let frame = clock.now();
+                let level = source.next().unwrap();
+                if level != 0.0 {
+                    onsets.push((frame, level));
+                }
+            }
+        }
+        onsets
+    }
+
+    fn render_onsets(bpm: f64, steps_per_beat: u32, pattern: Pattern, frames: u64) -> Vec<u64> {
+        render_hits(bpm, steps_per_beat, MIN_SWING, pattern, frames)
+            .into_iter()
+            .map(|(frame, _)| frame)
+            .collect()
+    }
+
+    #[test]
+    fn onsets_land_on_exact_frames() {
+        let mut pattern = empty_pattern(2, 8);
+        pattern[0][0] = Step::hit(MAX_VELOCITY);
+        pattern[0][4] = Step::hit(MAX_VELOCITY);
+        pattern[1][2] = Step::hit(MAX_VELOCITY);
+
+        // 120 BPM, 16th notes at 48 kHz => 6000 frames per step
+        let onsets = render_onsets(120.0, 4, pattern, 16 * 6000);
+        assert_eq!(onsets, vec![0, 12_000, 24_000, 48_000, 60_000, 72_000]);
+    }
+
+    #[test]
+    fn fractional_step_lengths_do_not_drift() {
+        let mut pattern = empty_pattern(1, 8);
+        pattern[0][0] = Step::hit(MAX_VELOCITY);
+
+        // 133 BPM triplets => 7218.04.. frames per step; each bar must start
+        // exactly where the clock puts step n * 8, however many bars in
+        let tempo = StepClock::new(133.0, 3, RATE);
+        let onsets = render_onsets(133.0, 3, pattern, tempo.step_offset(8 * 32));
+        let expected: Vec<u64> = (0..32).map(|bar| tempo.step_offset(bar * 8)).collect();
+        assert_eq!(onsets, expected);
+    }
+
+    #[test]
+    fn swing_delays_only_off_beats() {
+        let pattern = vec![vec![Step::hit(MAX_VELOCITY); 8]];
+        // 120 BPM 16ths => 6000 frames per step; off-beats move by (2 * swing - 100)% of that
+        for (swing, delay) in [(50, 0), (58, 960), (66, 1920), (75, 3000)] {
+            let onsets: Vec<u64> = render_hits(120.0, 4, swing, pattern.clone(), 8 * 6000)
+                .into_iter()
+                .map(|(frame, _)| frame)
+                .collect();
+            let expected: Vec<u64> = (0..8)
+                .map(|n| n * 6000 + if n % 2 == 1 { delay } else { 0 })
+                .collect();
+            assert_eq!(onsets, expected, "swing {}%", swing);
+        }
+    }
+
+    #[test]
+    fn velocity_scales_hit_level() {
+        let mut pattern = empty_pattern(1, 8);
+        pattern[0][0] = Step::hit(40);
+
+        let hits = render_hits(120.0, 4, MIN_SWING, pattern, 6000);
+        assert_eq!(hits.len(), 1);
+        assert!((hits[0].1 - 40.0 / 127.0).abs() < 1e-3, "{:?}", hits);
+    }
+
+    #[test]
+    fn every_command_reports_the_new_state() {
+        let (evt_tx, evt_rx) = mpsc::channel();
+        let mut engine = SequencerEngine::new(evt_tx, RATE).unwrap();
+        engine.handle_command(
+            SequencerCommand::SetPattern {
+                pattern: empty_pattern(2, 4),
+            },
+            0,
+        );
+        engine.handle_command(SequencerCommand::ToggleStep { track: 1, step: 3 }, 0);
+        engine.handle_command(SequencerCommand::AdjustBpm(5), 0);
+        engine.handle_command(SequencerCommand::Play, 0);
+        engine.handle_command(
+            SequencerCommand::SetSteps(vec![
+                (0, 0, Step::hit(40)),
+                (1, 3, Step::REST),
+                (5, 0, Step::hit(1)),
+            ]),
+            0,
+        );
+
+        let states: Vec<Snapshot> = evt_rx
+            .try_iter()
+            .filter_map(|e| match e {
+                SequencerEvent::State(s) => Some(s),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(states.len(), 5);
+        assert!(states[1].pattern[1][3].is_on());
+        assert_eq!(states[1].steps, 4);
+        assert_eq!(states[2].bpm, 125.0);
+        assert_eq!(states[2].swing, MIN_SWING);
+        assert!(!states[2].playing && states[3].playing);
+        // cells off the grid are ignored
+        assert_eq!(states[4].pattern[0][0].velocity, 40);
+        assert!(!states[4].pattern[1][3].is_on());
+    }
+
+    #[test]
+    fn undo_reverts_edits_in_any_slot_and_regrows_shrinks() {
+        let (evt_tx, evt_rx) = mpsc::channel();
+        let mut engine = SequencerEngine::new(evt_tx, RATE).unwrap();
+        let mut send = |cmd| engine.handle_command(cmd, 0);
+        send(SequencerCommand::SetBank {
+            patterns: vec![empty_pattern(2, 8)],
+        });
+        send(SequencerCommand::ToggleStep { track: 0, step: 6 });
+        send(SequencerCommand::SetVelocity {
+            track: 0,
+            step: 6,
+            velocity: 40,
+        });
+        // an edit in another slot, then a resize that cuts the first hit off
+        send(SequencerCommand::QueuePattern {
+            slot: 1,
+            after: None,
+        });
+        send(SequencerCommand::ToggleStep { track: 1, step: 0 });
+        send(SequencerCommand::ResizePattern { steps: 4 });
+        // toggling an off-grid step records nothing
+        send(SequencerCommand::ToggleStep { track: 0, step: 6 });
+
+        let last_state = |rx: &Receiver<SequencerEvent>| {
+            rx.try_iter()
+                .filter_map(|e| match e {
+                    SequencerEvent::State(s) => Some(s),
+                    _ => None,
+                })
+                .last()
+                .unwrap()
+        };
+        let state = last_state(&evt_rx);
+        assert_eq!((state.steps, state.history), (4, (4, 0)));
+
+        send(SequencerCommand::Undo);
+        let bank = evt_rx
+            .try_iter()
+            .find_map(|e| match e {
+                SequencerEvent::Bank(bank) => Some(bank),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(bank[0][0][6].velocity, 40);
+        send(SequencerCommand::Undo);
+        send(SequencerCommand::Undo);
+        let state = last_state(&evt_rx);
+        // still on P02, whose hit is gone; P01's step is back to the default hit
+        assert_eq!((state.slot, state.steps), (1, 8));
+        assert!(!state.pattern[1][0].is_on());
+        assert_eq!(engine.bank[0][0][6].velocity, DEFAULT_VELOCITY);
+
+        let mut send = |cmd| engine.handle_command(cmd, 0);
+        send(SequencerCommand::Redo);
+        send(SequencerCommand::Redo);
+        send(SequencerCommand::Redo);
+        send(SequencerCommand::Redo);
+        let state = last_state(&evt_rx);
+        assert_eq!((state.steps, state.history), (4, (4, 0)));
+        assert!(state.pattern[1][0].is_on());
+        // loading a bank starts a fresh history
+        engine.handle_command(
+            SequencerCommand::SetBank {
+                patterns: vec![empty_pattern(2, 8)],
+            },
+            0,
+        );
+        assert_eq!(last_state(&evt_rx).history, (0, 0));
+    }
+
+    #[test]
+    fn loaded_patterns_are_kept_within_the_grid_limits() {
+        let (evt_tx, _evt_rx) = mpsc::channel();
+        let (trig_tx, _trig_rx) = mpsc::channel();
+        let mut engine = SequencerEngine::new(evt_tx, RATE).unwrap();
+        engine.handle_command(
+            SequencerCommand::SetBank {
+                patterns: vec![empty_pattern(2, 8)],
+            },
+            0,
+        );
+        engine.handle_command(SequencerCommand::Play, 0);
+        engine.schedule_until(engine.tempo.step_offset(5), &trig_tx);
+
+        // an empty row used to leave a zero-step grid
+        engine.handle_command(
+            SequencerCommand::SetPattern {
+                pattern: vec![vec![]],
+            },
+            0,
+        );
+        assert_eq!((engine.tracks(), engine.steps), (1, 1));
+        engine.schedule_until(engine.tempo.step_offset(10), &trig_tx);
+        engine.handle_command(SequencerCommand::ResizePattern { steps: 4 }, 0);
+        engine.handle_command(SequencerCommand::Undo, 0);
+        assert_eq!(engine.steps, 1);
+
+        engine.handle_command(
+            SequencerCommand::SetBank {
+                patterns: vec![empty_pattern(MAX_TRACKS + 3, MAX_STEPS * 2)],
+            },
+            0,
+        );
+        assert_eq!((engine.tracks(), engine.steps), (MAX_TRACKS, MAX_STEPS));
+        engine.handle_command(
+            SequencerCommand::SetBank {
+                patterns: vec![vec![vec![]; 3]],
+            },
+            0,
+        );
+        assert_eq!((engine.tracks(), engine.steps), (3, 1));
+        assert!(engine.bank.iter().all(|p| p.len() == 3 && p[0].len() == 1));
+    }
+
+    #[test]
+    fn track_removal_and_addition_undo_with_their_hits() {
+        let (evt_tx, evt_rx) = mpsc::channel();
+        let mut engine = SequencerEngine::new(evt_tx, RATE).unwrap();
+        let track = |name: &str| Track::new(name, format!("{}.wav", name));
+        let mut send = |cmd| engine.handle_command(cmd, 0);
+        send(SequencerCommand::SetBank {
+            patterns: vec![empty_pattern(3, 4), empty_pattern(3, 4)],
+        });
+        send(SequencerCommand::ToggleStep { track: 2, step: 1 });
+        send(SequencerCommand::QueuePattern {
+            slot: 1,
+            after: None,
+        });
+        send(SequencerCommand::ToggleStep { track: 1, step: 3 });
+        send(SequencerCommand::RemoveTrack {
+            track: 1,
+            settings: track("snare"),
+        });
+        send(SequencerCommand::AddTrack(track("tom")));
+        assert_eq!(engine.tracks(), 3);
+        evt_rx.try_iter().for_each(drop);
+
+        // undoing the add takes the new track out again
+        engine.handle_command(SequencerCommand::Undo, 0);
+        assert_eq!(engine.tracks(), 2);
+        assert!(matches!(
+            evt_rx.try_recv(),
+            Ok(SequencerEvent::TrackRemoved { track: 2 })
+        ));
+        evt_rx.try_iter().for_each(drop);
+
+        // undoing the removal brings the track back, hits and all, before
+        // the bank that holds them
+        engine.handle_command(SequencerCommand::Undo, 0);
+        match evt_rx.try_recv() {
+            Ok(SequencerEvent::TrackInserted { track: 1, settings }) => {
+                assert_eq!(settings.name, "snare")
+            }
+            other => panic!("expected the snare back, got {:?}", other),
+        }
+        assert!(matches!(evt_rx.try_recv(), Ok(SequencerEvent::Bank(_))));
+        assert!(engine.bank[1][1][3].is_on());
+        assert!(engine.bank[0][2][1].is_on());
+        // and older edits still undo on the right tracks
+        engine.handle_command(SequencerCommand::Undo, 0);
+        engine.handle_command(SequencerCommand::Undo, 0);
+        assert!(engine.bank.iter().flatten().flatten().all(|s| !s.is_on()));
+
+        let mut send = |cmd| engine.handle_command(cmd, 0);
+        for _ in 0..4 {
+            send(SequencerCommand::Redo);
+        }
+        assert_eq!(engine.tracks(), 3);
+        assert!(engine.bank[0][1][1].is_on());
+        assert!(!engine.bank[1][1][3].is_on());
+
+        // a kit's extra tracks can't be undone piecemeal: history restarts
+        engine.handle_command(SequencerCommand::LoadKit { added: 2 }, 0);
+        assert_eq!(engine.tracks(), 5);
+        engine.handle_command(SequencerCommand::Undo, 0);
+        assert_eq!(engine.tracks(), 5);
+        assert!(engine.bank[0][1][1].is_on());
+    }
+
+    #[test]
+    fn recorded_hits_snap_to_steps_by_quantize_strength() {
+        let (evt_tx, _evt_rx) = mpsc::channel();
+        let (trig_tx, _trig_rx) = mpsc::channel();
+        let mut engine = SequencerEngine::new(evt_tx, RATE).unwrap();
+        let settings = |quantize, replace| {
+            SequencerCommand::SetRecordSettings(RecordSettings {
+                quantize,
+                replace,
+                count_in: 0,
+            })
+        };
+        let hit = |track, frame| SequencerCommand::RecordHit {
+            track,
+            velocity: MAX_VELOCITY,
+            frame,
+        };
+        let mut send = |cmd| engine.handle_command(cmd, 0);
+        send(SequencerCommand::SetBank {
+            patterns: vec![empty_pattern(2, 8)],
+        });
+        send(settings(100, false));
+        // only played, not recorded
+        send(hit(0, 0));
+        send(SequencerCommand::Record(true));
+        engine.schedule_until(engine.tempo.step_offset(5), &trig_tx);
+
+        // 6000 frames per step; hits before and after the scheduled steps
+        let mut send = |cmd| engine.handle_command(cmd, 0);
+        send(hit(0, 8_900));
+        send(hit(0, 9_100));
+        send(hit(0, 46_000));
+        send(settings(0, false));
+        send(hit(1, 11_900));
+        send(settings(50, false));
+        send(hit(1, 16_000));
+        send(hit(1, 34_800));
+        // at 75% swing step 1 is half a step late, so this is nearer step 0
+        send(SequencerCommand::SetSwing(MAX_SWING));
+        send(settings(100, false));
+        send(hit(1, 4_400));
+        let on = |engine: &SequencerEngine, track: usize| -> Vec<usize> {
+            (0..8)
+                .filter(|&step| engine.bank[0][track][step].is_on())
+                .collect()
+        };
+        assert_eq!(on(&engine, 0), [0, 1, 2]);
+        assert_eq!(on(&engine, 1), [0, 1, 2, 6]);
+
+        // a replace take clears each track on its first hit only
+        let mut send = |cmd| engine.handle_command(cmd, 0);
+        send(SequencerCommand::SetSwing(MIN_SWING));
+        send(settings(100, true));
+        send(SequencerCommand::Record(true));
+        send(hit(0, 24_100));
+        send(hit(0, 30_000));
+        assert_eq!(on(&engine, 0), [4, 5]);
+        assert_eq!(on(&engine, 1), [0, 1, 2, 6]);
+        engine.handle_command(SequencerCommand::Undo, 0);
+        engine.handle_command(SequencerCommand::Undo, 0);
+        assert_eq!(on(&engine, 0), [0, 1, 2]);
+    }
+
+    #[test]
+    fn count_in_clicks_a_bar_before_recording() {
+        let (evt_tx, evt_rx) = mpsc::channel();
+        let (trig_tx, trig_rx) = mpsc::channel();
+        let mut engine = SequencerEngine::new(evt_tx, RATE).unwrap();
+        let mut pattern = empty_pattern(1, 8);
+        pattern[0][0] = Step::hit(MAX_VELOCITY);
+        engine.handle_command(SequencerCommand::SetPattern { pattern }, 0);
+        engine.handle_command(
+            SequencerCommand::SetRecordSettings(RecordSettings {
+                count_in: 1,
+                ..RecordSettings::default()
+            }),
+            0,
+        );
+        engine.handle_command(SequencerCommand::Record(true), 0);
+
+        // hits during the count-in are not recorded
+        engine.schedule_until(engine.tempo.step_offset(8), &trig_tx);
+        let hit = SequencerCommand::RecordHit {
+            track: 0,
+            velocity: MAX_VELOCITY,
+            frame: 40_000,
+        };
+        engine.handle_command(hit, 0);
+        engine.schedule_until(engine.tempo.step_offset(17), &trig_tx);
+
+        let triggers: Vec<_> = trig_rx
+            .try_iter()
+            .map(|t| (t.frame, t.track == CLICK_TRACK, t.gain))
+            .collect();
+        assert_eq!(
+            triggers,
+            [
+                (0, true, 1.0),
+                (24_000, true, 0.6),
+                (48_000, true, 0.6),
+                (72_000, true, 0.6),
+                // the pattern starts on the next bar
+                (96_000, false, 1.0),
+            ]
+        );
+        let beats_left: Vec<u64> = evt_rx
+            .try_iter()
+            .filter_map(|e| match e {
+                SequencerEvent::State(s) if s.recording => Some(s.count_in),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(beats_left, [4, 3, 2, 1, 0]);
+        assert_eq!(engine.bank[0][0].iter().filter(|s| s.is_on()).count(), 1);
+
+        engine.handle_command(SequencerCommand::Stop, 0);
+        assert!(!engine.snapshot().recording);
+    }
+
+    /// Drain the step events so far and return whether track 0 played on each.
+    fn track0_hits(evt_rx: &Receiver<SequencerEvent>) -> Vec<bool> {
+        evt_rx
+            .try_iter()
+            .filter_map(|e| match e {
+                SequencerEvent::Step { hits, .. } => Some(hits[0] > 0),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Apply `setup`, press play and return, per pass of `steps` steps,
+    /// which steps of track 0 played.
+    fn played_steps(setup: Vec<SequencerCommand>, steps: usize, loops: usize) -> Vec<Vec<bool>> {
+        let (evt_tx, evt_rx) = mpsc::channel();
+        let (trig_tx, _trig_rx) = mpsc::channel();
+        let mut engine = SequencerEngine::new(evt_tx, RATE).unwrap();
+        for cmd in setup {
+            engine.handle_command(cmd, 0);
+        }
+        engine.handle_command(SequencerCommand::Play, 0);
+        engine.schedule_until(engine.tempo.step_offset((steps * loops) as u64), &trig_tx);
+        track0_hits(&evt_rx)
+            .chunks(steps)
+            .map(|c| c.to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn conditions_follow_the_loop_counter() {
+        let on = Step::hit(MAX_VELOCITY);
+        let with = |condition| Step {
+            condition: Some(condition),
+            ..on
+        };
+        let pattern = vec![vec![
+            with(Condition::Ratio { a: 1, b: 2 }),
+            with(Condition::Ratio { a: 2, b: 4 }),
+            with(Condition::First),
+            with(Condition::NotPre),
+            with(Condition::Fill),
+            with(Condition::NotFill),
+        ]];
+        let loops = played_steps(vec![SequencerCommand::SetPattern { pattern }], 6, 4);
+        assert_eq!(
+            loops,
+            vec![
+                vec![true, false, true, false, false, true],
+                vec![false, true, false, true, false, true],
+                vec![true, false, false, true, false, true],
+                vec![false, false, false, true, false, true],
+            ]
+        );
+    }
+
+    #[test]
+    fn probability_is_reproducible_per_seed() {
+        let step = Step {
+            probability: 50,
+            ..Step::hit(MAX_VELOCITY)
+        };
+        let run = |seed| {
+            let pattern = vec![vec![step; 16]];
+            let setup = vec![
+                SequencerCommand::SetPattern { pattern },
+                SequencerCommand::SetSeed(seed),
+            ];
+            played_steps(setup, 16, 8)
+        };
+        let a = run(7);
+        assert_eq!(a, run(7));
+        assert_ne!(a, run(8));
+
+        let count = a.concat().iter().filter(|&&p| p).count();
+        assert!((32..96).contains(&count), "{} of 128 played", count);
+    }
+
+    /// A bank where slot 0 hits on every step of track 0 and slot 1 is silent.
+    fn loud_and_silent_bank(steps: usize) -> Vec<Pattern> {
+        vec![
+            vec![vec![Step::hit(MAX_VELOCITY); steps]],
+            empty_pattern(1, steps),
+        ]
+    }
+
+    #[test]
+    fn song_mode_plays_the_arrangement() {
+        let setup = vec![
+            SequencerCommand::SetBank {
+                patterns: loud_and_silent_bank(4),
+            },
+            SequencerCommand::SetSong(vec![SongEntry::new(1, 1), SongEntry::new(0, 2)]),
+            SequencerCommand::SetSongMode(true),
+        ];
+        let passes: Vec<bool> = played_steps(setup, 4, 7)
+            .iter()
+            .map(|pass| pass.iter().all(|&p| p))
+            .collect();
+        // silent once, loud twice, then round again
+        assert_eq!(passes, vec![false, true, true, false, true, true, false]);
+    }
+
+    #[test]
+    fn queued_pattern_switches_on_the_bar_line() {
+        let (evt_tx, evt_rx) = mpsc::channel();
+        let (trig_tx, _trig_rx) = mpsc::channel();
+        let mut engine = SequencerEngine::new(evt_tx, RATE).unwrap();
+        engine.handle_command(
+            SequencerCommand::SetBank {
+                patterns: loud_and_silent_bank(8),
+            },
+            0,
+        );
+        engine.handle_command(SequencerCommand::Play, 0);
+
+        // ask for slot 1 a few steps in; at 4 steps per beat a bar is 16 steps
+        engine.schedule_until(engine.tempo.step_offset(3), &trig_tx);
+        engine.handle_command(
+            SequencerCommand::QueuePattern {
+                slot: 1,
+                after: None,
+            },
+            0,
+        );
+        engine.schedule_until(engine.tempo.step_offset(24), &trig_tx);
+
+        let played = track0_hits(&evt_rx);
+        assert_eq!(played.len(), 24);
+        assert!(played[..16].iter().all(|&p| p));
+        assert!(played[16..].iter().all(|&p| !p));
+        assert_eq!(engine.snapshot().slot, 1);
+    }
+
+    #[test]
+    fn queued_pattern_can_switch_after_n_steps() {
+        let (evt_tx, evt_rx) = mpsc::channel();
+        let (trig_tx, _trig_rx) = mpsc::channel();
+        let mut engine = SequencerEngine::new(evt_tx, RATE).unwrap();
+        engine.handle_command(
+            SequencerCommand::SetBank {
+                patterns: loud_and_silent_bank(8),
+            },
+            0,
+        );
+        engine.handle_command(SequencerCommand::Play, 0);
+
+        engine.schedule_until(engine.tempo.step_offset(3), &trig_tx);
+        let cue = SequencerCommand::QueuePattern {
+            slot: 1,
+            after: Some(2),
+        };
+        engine.handle_command(cue, 0);
+        assert_eq!(engine.snapshot().queued, Some(1));
+        engine.schedule_until(engine.tempo.step_offset(8), &trig_tx);
+
+        let events: Vec<_> = evt_rx.try_iter().collect();
+        let played: Vec<bool> = events
+            .iter()
+            .filter_map(|e| match e {
+                SequencerEvent::Step { hits, .. } => Some(hits[0] > 0),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(played, [true, true, true, true, true, false, false, false]);
+        // the switch is announced once, followed by the new state
+        let switched: Vec<usize> = events
+            .iter()
+            .filter_map(|e| match e {
+                SequencerEvent::PatternSwitched { slot } => Some(*slot),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(switched, [1]);
+        assert_eq!(engine.snapshot().queued, None);
     }
 }
@@ -0,0 +1,149 @@
+// Undo history
+// Pattern edits kept as reversible changes: every cell an edit touched,
+// with its contents before and after, in whichever bank slot it was made.
+use std::collections::VecDeque;
+
+use crate::project::Track;
+use crate::sequencer::steps::Step;
+
+/// Edits kept for undo; the oldest are forgotten first.
+pub const HISTORY_LIMIT: usize = 256;
+
+/// One cell's contents on either side of an edit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CellChange {
+    pub slot: usize,
+    pub track: usize,
+    pub step: usize,
+    pub before: Step,
+    pub after: Step,
+}
+
+/// A track added or removed, with its rows in every slot so undoing a
+/// removal brings its hits back.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackChange {
+    pub track: usize,
+    pub settings: Track,
+    /// The track's row in each bank slot
+    pub rows: Vec<Vec<Step>>,
+    /// Whether the edit added the track rather than removed it
+    pub added: bool,
+}
+
+/// Everything one command changed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Change {
+    pub cells: Vec<CellChange>,
+    /// Step count before and after, for a resize. Cells a shrink cut off
+    /// are in `cells`, going to rests.
+    pub steps: Option<(usize, usize)>,
+    pub track: Option<TrackChange>,
+}
+
+impl Change {
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty() && self.steps.is_none() && self.track.is_none()
+    }
+}
+
+/// Undo and redo stacks.
+#[derive(Debug)]
+pub struct History {
+    undo: VecDeque<Change>,
+    redo: Vec<Change>,
+    limit: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new(HISTORY_LIMIT)
+    }
+}
+
+impl History {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            limit: limit.max(1),
+        }
+    }
+
+    /// Remember a new edit; anything that was undone can't be redone after it.
+    pub fn record(&mut self, change: Change) {
+        if change.is_empty() {
+            return;
+        }
+        self.redo.clear();
+        self.undo.push_back(change);
+        if self.undo.len() > self.limit {
+            self.undo.pop_front();
+        }
+    }
+
+    /// The latest edit, to be reverted; it moves to the redo stack.
+    pub fn undo(&mut self) -> Option<Change> {
+        let change = self.undo.pop_back()?;
+        self.redo.push(change.clone());
+        Some(change)
+    }
+
+    /// The latest undone edit, to be made again; it moves back to the undo stack.
+    pub fn redo(&mut self) -> Option<Change> {
+        let change = self.redo.pop()?;
+        self.undo.push_back(change.clone());
+        Some(change)
+    }
+
+    /// Forget everything, e.g. when a new bank is loaded.
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+
+    /// How many edits can be undone and redone.
+    pub fn depth(&self) -> (usize, usize) {
+        (self.undo.len(), self.redo.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edit(step: usize) -> Change {
+        Change {
+            cells: vec![CellChange {
+                slot: 0,
+                track: 0,
+                step,
+                before: Step::REST,
+                after: Step::hit(100),
+            }],
+            ..Change::default()
+        }
+    }
+
+    #[test]
+    fn bounded_undo_and_redo_cleared_by_new_edits() {
+        let mut history = History::new(3);
+        for step in 0..5 {
+            history.record(edit(step));
+        }
+        history.record(Change::default());
+        // only the last three are kept
+        assert_eq!(history.depth(), (3, 0));
+        assert_eq!(history.undo(), Some(edit(4)));
+        assert_eq!(history.undo(), Some(edit(3)));
+        assert_eq!(history.redo(), Some(edit(3)));
+        assert_eq!(history.depth(), (2, 1));
+
+        history.record(edit(7));
+        assert_eq!(history.redo(), None);
+        assert_eq!(history.undo(), Some(edit(7)));
+        assert_eq!(history.undo(), Some(edit(3)));
+        assert_eq!(history.undo(), Some(edit(2)));
+        assert_eq!(history.undo(), None);
+    }
+}
@@ -1,4 +1,7 @@
 // Sequencer module
 pub mod clock;
-pub mod steps;
 pub mod engine;
+pub mod history;
+pub mod record;
+pub mod song;
+pub mod steps;
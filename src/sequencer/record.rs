@@ -0,0 +1,65 @@
+// Live recording
+// Settings for recording pad hits into the playing pattern, and the rule
+// that snaps a hit between two steps onto one of them.
+
+/// Bars of count-in allowed.
+pub const MAX_COUNT_IN: u32 = 4;
+
+/// How pad hits are recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordSettings {
+    /// Quantize strength, 0-100%. 100% puts a hit on the nearest step; lower
+    /// values only pull it forward onto the next step when it is that close
+    /// to it, and 0% leaves it on the step it was played in.
+    pub quantize: u8,
+    /// The first hit on a track in a take clears that track's row, so the
+    /// take replaces its part instead of adding to it
+    pub replace: bool,
+    /// Bars of metronome clicks before recording from a stop
+    pub count_in: u32,
+}
+
+impl Default for RecordSettings {
+    fn default() -> Self {
+        Self {
+            quantize: 100,
+            replace: false,
+            count_in: 1,
+        }
+    }
+}
+
+impl RecordSettings {
+    /// `overdub` or `replace`, as shown and typed in `:recmode`.
+    pub fn mode_name(&self) -> &'static str {
+        if self.replace {
+            "replace"
+        } else {
+            "overdub"
+        }
+    }
+}
+
+/// Whether a hit `fraction` of the way from one step to the next goes on
+/// the later one.
+pub fn snaps_forward(fraction: f64, quantize: u8) -> bool {
+    let reach = quantize.min(100) as f64 / 200.0;
+    fraction >= 1.0 - reach
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strength_sets_how_close_a_hit_must_be_to_move_forward() {
+        // full strength: nearest step
+        assert!(!snaps_forward(0.49, 100));
+        assert!(snaps_forward(0.5, 100));
+        // half strength: only the last quarter of a step moves on
+        assert!(!snaps_forward(0.7, 50));
+        assert!(snaps_forward(0.75, 50));
+        // none: always the step the hit fell in
+        assert!(!snaps_forward(0.99, 0));
+    }
+}
@@ -0,0 +1,51 @@
+// Song mode
+// A pattern bank plus an arrangement that plays bank slots in order.
+use serde::{Deserialize, Serialize};
+
+/// Number of pattern slots in the bank.
+pub const BANK_SLOTS: usize = 16;
+
+/// One section of an arrangement: a bank slot played `repeats` times through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SongEntry {
+    pub pattern: usize,
+    pub repeats: u32,
+}
+
+impl SongEntry {
+    pub fn new(pattern: usize, repeats: u32) -> Self {
+        Self {
+            pattern: pattern.min(BANK_SLOTS - 1),
+            repeats: repeats.max(1),
+        }
+    }
+}
+
+/// Where playback is in an arrangement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SongPosition {
+    /// Index into the arrangement
+    pub entry: usize,
+    /// Completed passes of that entry's pattern
+    pub repeat: u32,
+}
+
+impl SongPosition {
+    /// Count one finished pass of the current pattern. Returns the slot to
+    /// switch to if that finished the entry; the song wraps around at the end.
+    pub fn advance(&mut self, song: &[SongEntry]) -> Option<usize> {
+        let current = song.get(self.entry)?;
+        self.repeat += 1;
+        if self.repeat < current.repeats {
+            return None;
+        }
+        self.entry = (self.entry + 1) % song.len();
+        self.repeat = 0;
+        Some(song[self.entry].pattern)
+    }
+}
+
+/// Short display name of a bank slot: `P01` to `P16`.
+pub fn slot_name(slot: usize) -> String {
+    format!("P{:02}", slot + 1)
+}
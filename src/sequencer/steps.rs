@@ -1,8 +1,176 @@
 // Step grid data
 // Step grid utilities and types
+use std::fmt;
+use std::str::FromStr;
 
-pub type Pattern = Vec<Vec<bool>>; // [track][step]
+use anyhow::{anyhow, bail};
+
+/// One cell of the grid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Step {
+    /// MIDI-style velocity, 1-127; 0 is a rest
+    pub velocity: u8,
+    /// Chance of the hit playing, 0-100%
+    pub probability: u8,
+    /// Extra rule deciding which loops the hit plays on
+    pub condition: Option<Condition>,
+}
+
+impl Default for Step {
+    fn default() -> Self {
+        Self::REST
+    }
+}
+
+impl Step {
+    pub const REST: Step = Step {
+        velocity: 0,
+        probability: 100,
+        condition: None,
+    };
+
+    pub fn hit(velocity: u8) -> Self {
+        Self {
+            velocity: velocity.min(MAX_VELOCITY),
+            ..Self::REST
+        }
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.velocity > 0
+    }
+
+    /// Whether playback has to roll a die or check a condition for this step.
+    pub fn is_conditional(&self) -> bool {
+        self.probability < 100 || self.condition.is_some()
+    }
+}
+
+/// Elektron-style trig conditions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    /// `a:b`: plays on the `a`th of every `b` loops, e.g. `1:2` on every other loop
+    Ratio {
+        a: u8,
+        b: u8,
+    },
+    /// `fill` / `!fill`: only while fill mode is on / off
+    Fill,
+    NotFill,
+    /// `1st` / `!1st`: only on / never on the first loop after pressing play
+    First,
+    NotFirst,
+    /// `pre` / `!pre`: only if the last conditional step on this track played / didn't
+    Pre,
+    NotPre,
+}
+
+/// The conditions the UI cycles through, in order.
+pub const CONDITION_PRESETS: [Condition; 12] = [
+    Condition::Ratio { a: 1, b: 2 },
+    Condition::Ratio { a: 2, b: 2 },
+    Condition::Ratio { a: 1, b: 4 },
+    Condition::Ratio { a: 2, b: 4 },
+    Condition::Ratio { a: 3, b: 4 },
+    Condition::Ratio { a: 4, b: 4 },
+    Condition::Fill,
+    Condition::NotFill,
+    Condition::First,
+    Condition::NotFirst,
+    Condition::Pre,
+    Condition::NotPre,
+];
+
+/// The preset after `condition`, going from none through every preset and back to none.
+pub fn next_condition(condition: Option<Condition>) -> Option<Condition> {
+    let next = match condition {
+        None => 0,
+        Some(c) => CONDITION_PRESETS
+            .iter()
+            .position(|&p| p == c)
+            .map_or(0, |i| i + 1),
+    };
+    CONDITION_PRESETS.get(next).copied()
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Condition::Ratio { a, b } => write!(f, "{}:{}", a, b),
+            Condition::Fill => f.write_str("fill"),
+            Condition::NotFill => f.write_str("!fill"),
+            Condition::First => f.write_str("1st"),
+            Condition::NotFirst => f.write_str("!1st"),
+            Condition::Pre => f.write_str("pre"),
+            Condition::NotPre => f.write_str("!pre"),
+        }
+    }
+}
+
+impl FromStr for Condition {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "fill" => Condition::Fill,
+            "!fill" => Condition::NotFill,
+            "1st" => Condition::First,
+            "!1st" => Condition::NotFirst,
+            "pre" => Condition::Pre,
+            "!pre" => Condition::NotPre,
+            _ => {
+                let (a, b) = s
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("unknown condition `{}`", s))?;
+                let (a, b): (u8, u8) = (a.parse()?, b.parse()?);
+                if a == 0 || a > b {
+                    bail!("condition `{}` needs 1 <= a <= b", s);
+                }
+                Condition::Ratio { a, b }
+            }
+        })
+    }
+}
+
+pub type Pattern = Vec<Vec<Step>>; // [track][step]
+
+/// Limits on the grid size, in both directions.
+pub const MAX_TRACKS: usize = 64;
+pub const MAX_STEPS: usize = 64;
+
+pub const MAX_VELOCITY: u8 = 127;
+/// The levels the UI cycles through: soft, medium, accent.
+pub const VELOCITY_LEVELS: [u8; 3] = [40, 100, MAX_VELOCITY];
+/// Velocity of a step switched on without picking a level.
+pub const DEFAULT_VELOCITY: u8 = VELOCITY_LEVELS[1];
 
 pub fn empty_pattern(tracks: usize, steps: usize) -> Pattern {
-    vec![vec![false; steps]; tracks]
+    vec![vec![Step::REST; steps]; tracks]
+}
+
+/// Change the step count of every track, keeping existing steps that still fit.
+pub fn resize_pattern(pattern: &mut Pattern, steps: usize) {
+    for row in pattern.iter_mut() {
+        row.resize(steps, Step::REST);
+    }
+}
+
+/// Index into `VELOCITY_LEVELS` of the level closest to `velocity`.
+pub fn velocity_level(velocity: u8) -> usize {
+    (0..VELOCITY_LEVELS.len())
+        .min_by_key(|&i| VELOCITY_LEVELS[i].abs_diff(velocity))
+        .unwrap_or(0)
+}
+
+/// The level after `velocity`'s, wrapping from accent back to soft. Rests become soft.
+pub fn next_velocity_level(velocity: u8) -> u8 {
+    if velocity == 0 {
+        return VELOCITY_LEVELS[0];
+    }
+    VELOCITY_LEVELS[(velocity_level(velocity) + 1) % VELOCITY_LEVELS.len()]
+}
+
+/// Linear output gain for a hit at `velocity`; full velocity plays at unity.
+pub fn velocity_gain(velocity: u8) -> f32 {
+    velocity.min(MAX_VELOCITY) as f32 / MAX_VELOCITY as f32
 }
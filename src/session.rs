@@ -0,0 +1,439 @@
+// Session
+// The engine thread, audio player and open project, wired together without
+// any terminal UI so the TUI and headless mode can share them.
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::audio::player::Player;
+use crate::config::Config;
+use crate::kit::{self, Discovery};
+use crate::midi::{export_midi, import_midi, MidiOptions, NoteMap};
+use crate::osc::server::OscServer;
+use crate::project::{sample_path, Project, Track, DEFAULT_SOUND_DIR};
+use crate::render::{render_to_wav, RenderOptions};
+use crate::sequencer::engine::{SequencerCommand, SequencerEngine, SequencerEvent, Snapshot};
+use crate::sequencer::steps::{Pattern, MAX_VELOCITY};
+
+/// Where a session gets its samples and sound from.
+#[derive(Debug, Clone)]
+pub struct SessionOptions {
+    /// Directory new tracks load `<name>.wav` from
+    pub sound_dir: PathBuf,
+    /// Settings with every override applied: output device, buffer size and
+    /// defaults for a project that doesn't exist yet
+    pub config: Config,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        Self {
+            sound_dir: PathBuf::from(DEFAULT_SOUND_DIR),
+            config: Config::default(),
+        }
+    }
+}
+
+pub struct Session {
+    player: Player,
+    seq_tx: Sender<SequencerCommand>,
+    seq_rx: Receiver<SequencerEvent>,
+    // the document being edited and where it was loaded from / saves to
+    project: Project,
+    project_path: Option<PathBuf>,
+    // what the engine is playing, so bounces render the same thing
+    slot: usize,
+    song_mode: bool,
+    // remote control, once started
+    osc: Option<OscServer>,
+    // why the session is running without sound, if it is
+    warning: Option<String>,
+    sound_dir: PathBuf,
+    // why each track's sample failed to load, in track order
+    sample_errors: Vec<Option<String>>,
+    // track name to note, for MIDI export and import
+    notes: NoteMap,
+}
+
+impl Session {
+    /// `project_path` is opened if it exists; otherwise a new project will be saved there.
+    pub fn new(project_path: Option<PathBuf>, opts: &SessionOptions) -> Result<Self> {
+        let project = match &project_path {
+            Some(path) if path.exists() => Project::load(path)?,
+            _ => {
+                let dir = project_path.as_deref().and_then(Path::parent);
+                opts.config.new_project(&opts.sound_dir, dir)?
+            }
+        };
+
+        // Audio player (samples come from the project's tracks); without a
+        // default device the session still runs, silently. A device asked
+        // for by name has to exist.
+        let device = opts.config.device.as_deref();
+        let (player, warning) = match Player::new(device, opts.config.buffer_size) {
+            Ok(player) => (player, None),
+            Err(e) if device.is_some() => return Err(e),
+            Err(e) => (
+                Player::silent(),
+                Some(format!("No audio output ({:#}); running silent", e)),
+            ),
+        };
+
+        // Sequencer channels
+        let (cmd_tx, cmd_rx) = mpsc::channel();
+        let (evt_tx, evt_rx) = mpsc::channel();
+
+        // Create and run sequencer engine in another thread
+        // Hits are queued straight into the player's output stream, not via this thread
+        let mut engine = SequencerEngine::new(evt_tx, player.sample_rate())?;
+        let clock = player.clock();
+        let triggers = player.triggers();
+        thread::spawn(move || {
+            engine.run(cmd_rx, clock, triggers);
+        });
+
+        Ok(Self {
+            player,
+            seq_tx: cmd_tx,
+            seq_rx: evt_rx,
+            project,
+            project_path,
+            slot: 0,
+            song_mode: false,
+            osc: None,
+            warning,
+            sound_dir: opts.sound_dir.clone(),
+            sample_errors: Vec::new(),
+            notes: opts.config.note_map(),
+        })
+    }
+
+    /// Set when the session fell back to running without an audio device.
+    pub fn warning(&self) -> Option<&str> {
+        self.warning.as_deref()
+    }
+
+    /// Errors from the audio output and the OSC server since the last call,
+    /// for the status line.
+    pub fn take_errors(&self) -> Vec<String> {
+        let mut errors = self.player.take_errors();
+        if let Some(osc) = &self.osc {
+            errors.extend(osc.take_errors());
+        }
+        errors
+    }
+
+    pub fn player(&self) -> &Player {
+        &self.player
+    }
+
+    pub fn send(&self, cmd: SequencerCommand) {
+        self.seq_tx.send(cmd).ok();
+    }
+
+    /// Play a pad and hand the hit to the engine, which writes it into the
+    /// pattern while recording.
+    pub fn hit(&self, track: usize) {
+        let frame = self.player.clock().now();
+        self.player.play(track);
+        self.send(SequencerCommand::RecordHit {
+            track,
+            velocity: MAX_VELOCITY,
+            frame,
+        });
+    }
+
+    /// Take the engine's pending events, keeping the project in sync and
+    /// passing them on to OSC subscribers.
+    pub fn poll_events(&mut self) -> Vec<SequencerEvent> {
+        let events: Vec<_> = self.seq_rx.try_iter().collect();
+        for ev in &events {
+            if let Some(osc) = &self.osc {
+                osc.broadcast(ev);
+            }
+            match ev {
+                SequencerEvent::State(state) => self.sync_project(state),
+                SequencerEvent::Bank(patterns) => self.sync_bank(patterns),
+                SequencerEvent::TrackInserted { track, settings } => {
+                    self.insert_track(*track, settings.clone())
+                }
+                SequencerEvent::TrackRemoved { track } => {
+                    self.drop_track(*track);
+                }
+                _ => {}
+            }
+        }
+        events
+    }
+
+    /// Push the current project into the player and sequencer.
+    pub fn apply_project(&mut self) {
+        self.load_samples();
+
+        self.send(SequencerCommand::Configure {
+            bpm: self.project.bpm,
+            steps: self.project.steps,
+            steps_per_beat: self.project.steps_per_beat,
+        });
+        self.send(SequencerCommand::SetBank {
+            patterns: self.project.bank(),
+        });
+        self.send(SequencerCommand::SetSwing(self.project.swing));
+        self.send(SequencerCommand::SetSong(self.project.song.clone()));
+    }
+
+    /// Keep the project in step with what the engine reports, so every edit
+    /// lands in the slot it was made in.
+    fn sync_project(&mut self, state: &Snapshot) {
+        self.project.bpm = state.bpm;
+        self.project.swing = state.swing;
+        self.project.song = state.song.clone();
+        if state.pattern.len() == self.project.tracks.len() && state.steps == self.project.steps {
+            self.project.set_pattern(state.slot, &state.pattern);
+        }
+        self.slot = state.slot;
+        self.song_mode = state.song_mode;
+    }
+
+    /// Take every slot from the engine after an undo or redo, which can reach
+    /// slots other than the playing one and change the step count.
+    fn sync_bank(&mut self, patterns: &[Pattern]) {
+        let Some(steps) = patterns.first().and_then(|p| p.first()).map(Vec::len) else {
+            return;
+        };
+        if patterns[0].len() != self.project.tracks.len() {
+            return;
+        }
+        if steps != self.project.steps {
+            self.project.resize(steps);
+        }
+        for (slot, pattern) in patterns.iter().enumerate() {
+            self.project.set_pattern(slot, pattern);
+        }
+    }
+
+    /// Load every track's sample and mixer defaults. Tracks whose sample
+    /// fails stay silent; the errors are kept for `sample_errors`.
+    fn load_samples(&mut self) {
+        self.sample_errors = self
+            .project
+            .tracks
+            .iter()
+            .map(|track| {
+                let loaded = self.player.load(&track.name, &track.sample);
+                loaded.err().map(|e| format!("{:#}", e))
+            })
+            .collect();
+        self.player.mixer().set_strips(self.project.strips());
+        self.sync_tracks();
+    }
+
+    /// Display names of the project's tracks.
+    pub fn track_names(&self) -> Vec<String> {
+        self.project.track_names()
+    }
+
+    /// Why each track is silent, if it is, in track order.
+    pub fn sample_errors(&self) -> &[Option<String>] {
+        &self.sample_errors
+    }
+
+    /// One line per sample that failed to load.
+    pub fn sample_warnings(&self) -> Vec<String> {
+        self.project
+            .tracks
+            .iter()
+            .zip(&self.sample_errors)
+            .filter_map(|(track, error)| {
+                let error = error.as_ref()?;
+                Some(format!("No sound for `{}`: {}", track.name, error))
+            })
+            .collect()
+    }
+
+    /// Which tracks are silent, as one line, if any are.
+    pub fn missing_samples(&self) -> Option<String> {
+        let missing: Vec<&str> = self
+            .project
+            .tracks
+            .iter()
+            .zip(&self.sample_errors)
+            .filter(|(_, error)| error.is_some())
+            .map(|(track, _)| track.name.as_str())
+            .collect();
+        if missing.is_empty() {
+            return None;
+        }
+        Some(format!(
+            "{} sample(s) failed to load: {}",
+            missing.len(),
+            missing.join(", ")
+        ))
+    }
+
+    fn with_missing_samples(&self, message: String) -> String {
+        match self.missing_samples() {
+            Some(missing) => format!("{} ({})", message, missing),
+            None => message,
+        }
+    }
+
+    /// Tell the player about the project's current tracks.
+    /// The grid itself comes from the engine's state events.
+    fn sync_tracks(&mut self) {
+        let names = self.project.track_names();
+        let refs: Vec<&str> = names.iter().map(String::as_str).collect();
+        self.player.set_tracks(&refs);
+    }
+
+    /// Add a track; it is kept even if its sample fails to load, and the
+    /// error says why it is silent.
+    pub fn add_track(&mut self, name: Option<String>) -> Result<()> {
+        let name = name.unwrap_or_else(|| format!("track{}", self.project.tracks.len() + 1));
+        let sample = sample_path(&self.sound_dir, &name);
+        if !self.project.add_track(&name, sample) {
+            bail!("Track limit reached");
+        }
+        let track = self.project.tracks[self.project.tracks.len() - 1].clone();
+        self.send(SequencerCommand::AddTrack(track.clone()));
+        self.sync_tracks();
+        let loaded = self.player.load(&track.name, &track.sample);
+        self.sample_errors
+            .push(loaded.as_ref().err().map(|e| format!("{:#}", e)));
+        loaded
+    }
+
+    pub fn remove_track(&mut self, track: usize) {
+        let Some(settings) = self.project.tracks.get(track).cloned() else {
+            return;
+        };
+        if self.drop_track(track) {
+            self.send(SequencerCommand::RemoveTrack { track, settings });
+        }
+    }
+
+    /// Take a track out of the project and the player.
+    fn drop_track(&mut self, track: usize) -> bool {
+        if !self.project.remove_track(track) {
+            return false;
+        }
+        self.player.mixer().remove_track(track);
+        if track < self.sample_errors.len() {
+            self.sample_errors.remove(track);
+        }
+        self.sync_tracks();
+        true
+    }
+
+    /// Put back a track an undo restored; its hits follow with the bank.
+    fn insert_track(&mut self, track: usize, settings: Track) {
+        let strip = settings.strip();
+        let loaded = self.player.load(&settings.name, &settings.sample);
+        self.project.insert_track(track, settings);
+        self.player.mixer().insert_track(track, strip);
+        if track <= self.sample_errors.len() {
+            self.sample_errors
+                .insert(track, loaded.err().map(|e| format!("{:#}", e)));
+        }
+        self.sync_tracks();
+    }
+
+    pub fn resize_pattern(&mut self, steps: usize) {
+        self.project.resize(steps);
+        self.send(SequencerCommand::ResizePattern { steps });
+        self.sync_tracks();
+    }
+
+    pub fn save_project(&mut self, path: Option<String>) -> Result<String> {
+        let path = path
+            .map(PathBuf::from)
+            .or_else(|| self.project_path.clone())
+            .ok_or_else(|| anyhow!("E32: No file name"))?;
+        self.project.save(&path)?;
+        let message = format!("\"{}\" written", path.display());
+        self.project_path = Some(path);
+        Ok(message)
+    }
+
+    pub fn open_project(&mut self, path: String) -> Result<String> {
+        let path = PathBuf::from(path);
+        self.project = Project::load(&path)?;
+        self.project_path = Some(path.clone());
+        self.apply_project();
+        Ok(self.with_missing_samples(format!("\"{}\" opened", path.display())))
+    }
+
+    /// Where kits are looked for: next to the project, then the user's data directory.
+    pub fn kit_dirs(&self) -> Vec<PathBuf> {
+        let project_dir = self.project_path.as_deref().and_then(Path::parent);
+        kit::search_dirs(project_dir)
+    }
+
+    pub fn discover_kits(&self) -> Discovery {
+        kit::discover(&self.kit_dirs())
+    }
+
+    /// Swap the project's samples for the kit `name`; the patterns stay.
+    pub fn load_kit(&mut self, name: &str) -> Result<String> {
+        let kit = kit::find(&self.kit_dirs(), name)?;
+        let added = self.project.set_kit(&kit);
+        self.send(SequencerCommand::LoadKit { added });
+        self.load_samples();
+        Ok(self.with_missing_samples(format!("Kit `{}` loaded", kit.name)))
+    }
+
+    pub fn bounce(&self, path: String, bars: u32) -> Result<String> {
+        // bounce what you hear: same mutes, solos and levels as the live mixer
+        let opts = RenderOptions {
+            bars,
+            slot: self.slot,
+            song: self.song_mode,
+            strips: self.player.mixer().strips(),
+            ..RenderOptions::default()
+        };
+        render_to_wav(&self.project, &opts, path.as_ref())?;
+        Ok(format!("Bounced {} bar(s) to \"{}\"", bars, path))
+    }
+
+    pub fn export_midi(&self, path: String, song: bool) -> Result<String> {
+        let opts = MidiOptions {
+            slot: self.slot,
+            song,
+            notes: self.notes.clone(),
+        };
+        export_midi(&self.project, &opts, path.as_ref())?;
+        Ok(format!("\"{}\" written", path))
+    }
+
+    /// Quantize a drum file onto the playing slot's grid.
+    pub fn import_midi(&self, path: String) -> Result<String> {
+        let import = import_midi(path.as_ref(), &self.project, &self.notes)?;
+        let summary = import.summary();
+        self.send(SequencerCommand::SetPattern {
+            pattern: import.pattern,
+        });
+        Ok(summary)
+    }
+
+    pub fn start_osc(&mut self, port: u16) -> Result<SocketAddr> {
+        if let Some(osc) = &self.osc {
+            bail!("OSC already listening on {}", osc.local_addr()?);
+        }
+        let osc = OscServer::start(("0.0.0.0", port), self.seq_tx.clone())?;
+        let addr = osc.local_addr()?;
+        self.osc = Some(osc);
+        Ok(addr)
+    }
+
+    pub fn has_osc(&self) -> bool {
+        self.osc.is_some()
+    }
+
+    /// Stop playback, e.g. on exit.
+    pub fn stop(&self) {
+        self.send(SequencerCommand::Stop);
+    }
+}
@@ -1 +1,684 @@
 // Input handling
+// Key names, the actions keys are bound to, and the keymap that turns key
+// presses (including multi-key chords) into actions.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+/// Where a binding applies. Global bindings work on every screen; the grid's
+/// insert and visual modes fall back to the grid's bindings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Context {
+    Global,
+    Grid,
+    Insert,
+    Visual,
+    Song,
+    Kits,
+}
+
+impl Context {
+    pub const ALL: [Context; 6] = [
+        Context::Global,
+        Context::Grid,
+        Context::Insert,
+        Context::Visual,
+        Context::Song,
+        Context::Kits,
+    ];
+
+    /// Name of the context's table under `[keys]` in the config.
+    pub fn name(self) -> &'static str {
+        match self {
+            Context::Global => "global",
+            Context::Grid => "grid",
+            Context::Insert => "insert",
+            Context::Visual => "visual",
+            Context::Song => "song",
+            Context::Kits => "kits",
+        }
+    }
+
+    /// The context whose bindings apply where this one has none. A mode's
+    /// bindings shadow the grid's, so they may reuse its keys.
+    fn parent(self) -> Option<Context> {
+        match self {
+            Context::Global => None,
+            Context::Insert | Context::Visual => Some(Context::Grid),
+            Context::Grid | Context::Song | Context::Kits => Some(Context::Global),
+        }
+    }
+
+    /// Whether bindings in the two contexts can be pressed on the same screen.
+    fn overlaps(self, other: Context) -> bool {
+        self == other || self == Context::Global || other == Context::Global
+    }
+}
+
+/// Everything a key can be bound to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Quit,
+    CommandLine,
+    PlayToggle,
+    Record,
+    BpmUp,
+    BpmDown,
+    TapTempo,
+    NextScreen,
+    Kits,
+    Help,
+    Left,
+    Right,
+    Up,
+    Down,
+    ToggleStep,
+    CycleVelocity,
+    ProbabilityDown,
+    ProbabilityUp,
+    CycleCondition,
+    ToggleFill,
+    NextPattern,
+    PrevPattern,
+    Mute,
+    Solo,
+    SwingDown,
+    SwingUp,
+    Pad(usize),
+    InsertMode,
+    VisualMode,
+    Paste,
+    Undo,
+    Redo,
+    EndInsert,
+    EndVisual,
+    SelectionFill,
+    SelectionClear,
+    SelectionInvert,
+    SelectionLeft,
+    SelectionRight,
+    SelectionYank,
+    SelectionRepeat,
+    SongUp,
+    SongDown,
+    SongPatternDown,
+    SongPatternUp,
+    SongMode,
+    SongInsert,
+    SongDelete,
+    SongFewer,
+    SongMore,
+    KitUp,
+    KitDown,
+    KitLoad,
+    KitBack,
+}
+
+/// One row of the action table.
+struct ActionInfo {
+    action: Action,
+    context: Context,
+    name: &'static str,
+    /// Default bindings, each a chord as written in the config
+    keys: &'static [&'static str],
+    help: &'static str,
+}
+
+const fn info(
+    action: Action,
+    context: Context,
+    name: &'static str,
+    keys: &'static [&'static str],
+    help: &'static str,
+) -> ActionInfo {
+    ActionInfo {
+        action,
+        context,
+        name,
+        keys,
+        help,
+    }
+}
+
+/// Every action with its config name, default keys and help text, in help order.
+#[rustfmt::skip]
+const ACTIONS: &[ActionInfo] = &[
+    info(Action::Quit, Context::Global, "quit", &["q"], "Quit"),
+    info(Action::CommandLine, Context::Global, "command", &[":"], "Command line"),
+    info(Action::PlayToggle, Context::Global, "play", &["p"], "Play / stop"),
+    info(Action::Record, Context::Global, "record", &["R"], "Record pads on / off"),
+    info(Action::BpmUp, Context::Global, "bpm-up", &["+"], "BPM +5"),
+    info(Action::BpmDown, Context::Global, "bpm-down", &["-"], "BPM -5"),
+    info(Action::TapTempo, Context::Global, "tap", &["T"], "Tap tempo"),
+    info(Action::NextScreen, Context::Global, "screen", &["tab"], "Grid / song view"),
+    info(Action::Kits, Context::Global, "kits", &["k"], "Kit browser"),
+    info(Action::Help, Context::Global, "help", &["?"], "This help"),
+    info(Action::Left, Context::Grid, "left", &["left"], "Cursor left"),
+    info(Action::Right, Context::Grid, "right", &["right"], "Cursor right"),
+    info(Action::Up, Context::Grid, "up", &["up"], "Cursor up"),
+    info(Action::Down, Context::Grid, "down", &["down"], "Cursor down"),
+    info(Action::ToggleStep, Context::Grid, "toggle", &["space"], "Toggle step"),
+    info(Action::CycleVelocity, Context::Grid, "velocity", &["v"], "Cycle velocity"),
+    info(Action::ProbabilityDown, Context::Grid, "prob-down", &[","], "Probability -10%"),
+    info(Action::ProbabilityUp, Context::Grid, "prob-up", &["."], "Probability +10%"),
+    info(Action::CycleCondition, Context::Grid, "condition", &["c"], "Cycle condition"),
+    info(Action::ToggleFill, Context::Grid, "fill", &["F"], "Fill mode"),
+    info(Action::NextPattern, Context::Grid, "next-pattern", &["n"], "Queue next pattern"),
+    info(Action::PrevPattern, Context::Grid, "prev-pattern", &["N"], "Queue previous pattern"),
+    info(Action::Mute, Context::Grid, "mute", &["m"], "Mute track"),
+    info(Action::Solo, Context::Grid, "solo", &["o"], "Solo track"),
+    info(Action::SwingDown, Context::Grid, "swing-down", &["["], "Less swing"),
+    info(Action::SwingUp, Context::Grid, "swing-up", &["]"], "More swing"),
+    info(Action::Pad(0), Context::Grid, "pad1", &["a"], "Pad 1"),
+    info(Action::Pad(1), Context::Grid, "pad2", &["s"], "Pad 2"),
+    info(Action::Pad(2), Context::Grid, "pad3", &["d"], "Pad 3"),
+    info(Action::Pad(3), Context::Grid, "pad4", &["f"], "Pad 4"),
+    info(Action::InsertMode, Context::Grid, "insert", &["i"], "Insert mode"),
+    info(Action::VisualMode, Context::Grid, "visual", &["V", "ctrl-v"], "Visual block mode"),
+    info(Action::Paste, Context::Grid, "paste", &["P"], "Paste at the cursor"),
+    info(Action::Undo, Context::Grid, "undo", &["u"], "Undo (back to the last :e)"),
+    info(Action::Redo, Context::Grid, "redo", &["ctrl-r"], "Redo"),
+    info(Action::EndInsert, Context::Insert, "normal", &["esc"], "Back to normal mode"),
+    info(Action::EndVisual, Context::Visual, "normal", &["esc"], "Back to normal mode"),
+    info(Action::SelectionFill, Context::Visual, "fill", &["f"], "Fill the selection"),
+    info(Action::SelectionClear, Context::Visual, "clear", &["d", "x"], "Clear the selection"),
+    info(Action::SelectionInvert, Context::Visual, "invert", &["~"], "Invert the selection"),
+    info(Action::SelectionLeft, Context::Visual, "shift-left", &["<"], "Shift left"),
+    info(Action::SelectionRight, Context::Visual, "shift-right", &[">"], "Shift right"),
+    info(Action::SelectionYank, Context::Visual, "yank", &["y"], "Copy the selection"),
+    info(Action::SelectionRepeat, Context::Visual, "repeat", &["r"], "Repeat every N steps"),
+    info(Action::SongUp, Context::Song, "up", &["up"], "Previous entry"),
+    info(Action::SongDown, Context::Song, "down", &["down"], "Next entry"),
+    info(Action::SongPatternDown, Context::Song, "pattern-down", &["left"], "Entry's pattern -1"),
+    info(Action::SongPatternUp, Context::Song, "pattern-up", &["right"], "Entry's pattern +1"),
+    info(Action::SongMode, Context::Song, "mode", &["enter"], "Song mode on / off"),
+    info(Action::SongInsert, Context::Song, "insert", &["i"], "Insert a copy"),
+    info(Action::SongDelete, Context::Song, "delete", &["x"], "Delete entry"),
+    info(Action::SongFewer, Context::Song, "fewer", &[","], "Fewer repeats"),
+    info(Action::SongMore, Context::Song, "more", &["."], "More repeats"),
+    info(Action::KitUp, Context::Kits, "up", &["up"], "Previous kit"),
+    info(Action::KitDown, Context::Kits, "down", &["down"], "Next kit"),
+    info(Action::KitLoad, Context::Kits, "load", &["enter"], "Load kit"),
+    info(Action::KitBack, Context::Kits, "back", &["esc"], "Back to the grid"),
+];
+
+impl Action {
+    fn info(self) -> &'static ActionInfo {
+        ACTIONS
+            .iter()
+            .find(|i| i.action == self)
+            .expect("every action is in the table")
+    }
+
+    pub fn context(self) -> Context {
+        self.info().context
+    }
+
+    /// `context.name`, as used in messages and by `cratebeat keys`.
+    pub fn id(self) -> String {
+        let info = self.info();
+        format!("{}.{}", info.context.name(), info.name)
+    }
+
+    pub fn help(self) -> &'static str {
+        self.info().help
+    }
+
+    /// The action called `name` in `context`'s table.
+    pub fn find(context: Context, name: &str) -> Option<Action> {
+        ACTIONS
+            .iter()
+            .find(|i| i.context == context && i.name == name)
+            .map(|i| i.action)
+    }
+}
+
+/// One key press: a key plus Ctrl / Alt (and Shift, for keys without case).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Key {
+    code: KeyCode,
+    modifiers: KeyModifiers,
+}
+
+impl Key {
+    pub fn new(code: KeyCode, modifiers: KeyModifiers) -> Self {
+        let mut modifiers =
+            modifiers & (KeyModifiers::CONTROL | KeyModifiers::ALT | KeyModifiers::SHIFT);
+        let code = match code {
+            // a character's case already says whether Shift was held
+            KeyCode::Char(_) => {
+                modifiers.remove(KeyModifiers::SHIFT);
+                code
+            }
+            KeyCode::BackTab => {
+                modifiers.remove(KeyModifiers::SHIFT);
+                code
+            }
+            code => code,
+        };
+        Self { code, modifiers }
+    }
+}
+
+impl From<KeyEvent> for Key {
+    fn from(event: KeyEvent) -> Self {
+        Self::new(event.code, event.modifiers)
+    }
+}
+
+/// Names of keys that aren't a single character.
+const NAMED_KEYS: &[(&str, KeyCode)] = &[
+    ("space", KeyCode::Char(' ')),
+    ("enter", KeyCode::Enter),
+    ("esc", KeyCode::Esc),
+    ("tab", KeyCode::Tab),
+    ("backtab", KeyCode::BackTab),
+    ("backspace", KeyCode::Backspace),
+    ("delete", KeyCode::Delete),
+    ("insert", KeyCode::Insert),
+    ("left", KeyCode::Left),
+    ("right", KeyCode::Right),
+    ("up", KeyCode::Up),
+    ("down", KeyCode::Down),
+    ("home", KeyCode::Home),
+    ("end", KeyCode::End),
+    ("pageup", KeyCode::PageUp),
+    ("pagedown", KeyCode::PageDown),
+];
+
+/// Modifier prefixes, as in `ctrl-r`.
+const MODIFIERS: &[(&str, KeyModifiers)] = &[
+    ("ctrl-", KeyModifiers::CONTROL),
+    ("alt-", KeyModifiers::ALT),
+    ("shift-", KeyModifiers::SHIFT),
+];
+
+impl FromStr for Key {
+    type Err = anyhow::Error;
+
+    /// `p`, `F`, `space`, `ctrl-r`, `shift-tab`, `alt-left`, `f5`, ...
+    fn from_str(s: &str) -> Result<Self> {
+        let mut rest = s;
+        let mut modifiers = KeyModifiers::NONE;
+        'prefixes: while rest.chars().count() > 1 {
+            for (prefix, modifier) in MODIFIERS {
+                if rest.len() > prefix.len() && rest[..prefix.len()].eq_ignore_ascii_case(prefix) {
+                    modifiers |= *modifier;
+                    rest = &rest[prefix.len()..];
+                    continue 'prefixes;
+                }
+            }
+            break;
+        }
+
+        let mut chars = rest.chars();
+        let code = match (chars.next(), chars.next()) {
+            (Some(c), None) => KeyCode::Char(c),
+            _ => {
+                let name = rest.to_ascii_lowercase();
+                let named = NAMED_KEYS.iter().find(|(n, _)| *n == name);
+                match named {
+                    Some((_, code)) => *code,
+                    None => match name.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+                        Some(n @ 1..=12) => KeyCode::F(n),
+                        _ => bail!("unknown key `{}`", s),
+                    },
+                }
+            }
+        };
+        // `shift-tab` is what terminals send as back-tab
+        let code = match code {
+            KeyCode::Tab if modifiers.contains(KeyModifiers::SHIFT) => KeyCode::BackTab,
+            code => code,
+        };
+        Ok(Self::new(code, modifiers))
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (prefix, modifier) in MODIFIERS {
+            if self.modifiers.contains(*modifier) {
+                f.write_str(prefix)?;
+            }
+        }
+        if let Some((name, _)) = NAMED_KEYS.iter().find(|(_, code)| *code == self.code) {
+            return f.write_str(name);
+        }
+        match self.code {
+            KeyCode::Char(c) => write!(f, "{}", c),
+            KeyCode::F(n) => write!(f, "f{}", n),
+            code => write!(f, "{:?}", code),
+        }
+    }
+}
+
+/// A sequence of keys pressed one after another, written space separated (`g f`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chord(Vec<Key>);
+
+impl FromStr for Chord {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let keys = s
+            .split_whitespace()
+            .map(str::parse)
+            .collect::<Result<Vec<Key>>>()?;
+        if keys.is_empty() {
+            bail!("empty key binding");
+        }
+        Ok(Self(keys))
+    }
+}
+
+impl fmt::Display for Chord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<String> = self.0.iter().map(Key::to_string).collect();
+        f.write_str(&names.join(" "))
+    }
+}
+
+/// Two bindings where pressing one would also (or instead) trigger the other.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    pub first: (Action, Chord),
+    pub second: (Action, Chord),
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (a, a_keys) = &self.first;
+        let (b, b_keys) = &self.second;
+        if a_keys == b_keys {
+            write!(f, "`{}` is bound to both {} and {}", a_keys, a.id(), b.id())
+        } else {
+            write!(
+                f,
+                "`{}` ({}) starts `{}` ({}), so only one of them can be pressed",
+                a_keys,
+                a.id(),
+                b_keys,
+                b.id()
+            )
+        }
+    }
+}
+
+impl std::error::Error for Conflict {}
+
+/// Parse a config entry: the action with id `context.name` bound to `keys`.
+pub fn parse_binding(id: &str, keys: &[String]) -> Result<(Action, Vec<Chord>)> {
+    let (context, name) = id
+        .split_once('.')
+        .ok_or_else(|| anyhow!("unknown action `{}`", id))?;
+    let context = Context::ALL
+        .into_iter()
+        .find(|ctx| ctx.name() == context)
+        .ok_or_else(|| anyhow!("unknown key table [keys.{}]", context))?;
+    let found = Action::find(context, name)
+        .ok_or_else(|| anyhow!("unknown action `{}` in [keys.{}]", name, context.name()))?;
+    let chords = keys
+        .iter()
+        .map(|k| k.parse())
+        .collect::<Result<Vec<Chord>>>()?;
+    Ok((found, chords))
+}
+
+/// Which chords trigger which actions.
+#[derive(Debug, Clone)]
+pub struct Keymap {
+    bindings: Vec<(Action, Chord)>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        let bindings = ACTIONS
+            .iter()
+            .flat_map(|info| {
+                info.keys
+                    .iter()
+                    .map(|k| (info.action, k.parse().expect("default keys parse")))
+            })
+            .collect();
+        Self { bindings }
+    }
+}
+
+impl Keymap {
+    /// The defaults with some actions rebound. `overrides` maps an action id
+    /// (`grid.toggle`) to all of its keys; an empty list unbinds it.
+    pub fn with_overrides(overrides: &BTreeMap<String, Vec<String>>) -> Result<Self> {
+        let mut keymap = Self::default();
+        for (id, keys) in overrides {
+            let (action, chords) = parse_binding(id, keys)?;
+            keymap.bindings.retain(|(a, _)| *a != action);
+            keymap
+                .bindings
+                .extend(chords.into_iter().map(|chord| (action, chord)));
+        }
+        if let Some(conflict) = keymap.conflict() {
+            return Err(conflict.into());
+        }
+        Ok(keymap)
+    }
+
+    /// The first pair of bindings that get in each other's way, if any.
+    pub fn conflict(&self) -> Option<Conflict> {
+        for (i, (a, a_keys)) in self.bindings.iter().enumerate() {
+            for (b, b_keys) in &self.bindings[i + 1..] {
+                let prefix = a_keys.0.starts_with(&b_keys.0) || b_keys.0.starts_with(&a_keys.0);
+                if prefix && a.context().overlaps(b.context()) {
+                    return Some(Conflict {
+                        first: (*a, a_keys.clone()),
+                        second: (*b, b_keys.clone()),
+                    });
+                }
+            }
+        }
+        None
+    }
+
+    /// Keys for `action`, as shown to the user (`p`, or `g f / F`).
+    pub fn keys_for(&self, action: Action) -> String {
+        let keys: Vec<String> = self
+            .bindings
+            .iter()
+            .filter(|(a, _)| *a == action)
+            .map(|(_, chord)| chord.to_string())
+            .collect();
+        if keys.is_empty() {
+            "unbound".to_string()
+        } else {
+            keys.join(" / ")
+        }
+    }
+
+    /// Every action in help order, with its keys, for the help overlay.
+    pub fn describe(&self) -> Vec<(Context, Action, String)> {
+        ACTIONS
+            .iter()
+            .map(|info| (info.context, info.action, self.keys_for(info.action)))
+            .collect()
+    }
+}
+
+/// What a key press amounted to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Press {
+    Action(Action),
+    /// The start of a chord; waiting for the next key
+    Pending,
+    Unbound,
+}
+
+/// Keys pressed so far towards a chord.
+#[derive(Debug, Default)]
+pub struct Chords {
+    pending: Vec<Key>,
+}
+
+impl Chords {
+    /// Feed one key press made on a screen with `context`'s bindings.
+    pub fn press(&mut self, key: Key, context: Context, keymap: &Keymap) -> Press {
+        self.pending.push(key);
+        // the innermost context that knows the keys so far decides
+        let mut live = Some(context);
+        while let Some(ctx) = live {
+            let mut prefix = false;
+            for (action, chord) in keymap.bindings.iter().filter(|(a, _)| a.context() == ctx) {
+                if chord.0 == self.pending {
+                    self.pending.clear();
+                    return Press::Action(*action);
+                }
+                prefix |= chord.0.starts_with(&self.pending);
+            }
+            if prefix {
+                return Press::Pending;
+            }
+            live = ctx.parent();
+        }
+        // a wrong second key starts over, so it can still begin a binding of its own
+        let retry = self.pending.len() > 1;
+        self.pending.clear();
+        if retry {
+            return self.press(key, context, keymap);
+        }
+        Press::Unbound
+    }
+
+    /// The unfinished chord, for the footer.
+    pub fn pending(&self) -> Option<String> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        Some(Chord(self.pending.clone()).to_string())
+    }
+
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(s: &str) -> Key {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn key_names_parse_and_print_back() {
+        for name in [
+            "p", "F", "space", "ctrl-r", "alt-left", "backtab", "f5", "-", "ctrl--", "?",
+        ] {
+            assert_eq!(key(name).to_string(), name);
+        }
+        assert_eq!(key("Ctrl-R").to_string(), "ctrl-R");
+        assert_eq!(key("shift-tab"), key("backtab"));
+        // terminals report capitals with Shift held
+        assert_eq!(
+            Key::from(KeyEvent::new(KeyCode::Char('F'), KeyModifiers::SHIFT)),
+            key("F")
+        );
+        assert!("hyper-x".parse::<Key>().is_err());
+        assert!("f13".parse::<Key>().is_err());
+    }
+
+    #[test]
+    fn defaults_have_no_conflicts_and_overrides_are_checked() {
+        assert_eq!(Keymap::default().conflict(), None);
+
+        let overrides = |pairs: &[(&str, &[&str])]| -> BTreeMap<String, Vec<String>> {
+            pairs
+                .iter()
+                .map(|(id, keys)| (id.to_string(), keys.iter().map(|k| k.to_string()).collect()))
+                .collect()
+        };
+        let keymap = Keymap::with_overrides(&overrides(&[
+            ("global.play", &["space"]),
+            ("grid.toggle", &["x", "g t"]),
+            ("grid.fill", &[]),
+        ]))
+        .unwrap();
+        assert_eq!(keymap.keys_for(Action::PlayToggle), "space");
+        assert_eq!(keymap.keys_for(Action::ToggleStep), "x / g t");
+        assert_eq!(keymap.keys_for(Action::ToggleFill), "unbound");
+
+        // the song view's `x` is on another screen, but `q` is global
+        let err = Keymap::with_overrides(&overrides(&[("grid.mute", &["q"])])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "`q` is bound to both global.quit and grid.mute"
+        );
+        let err = Keymap::with_overrides(&overrides(&[("grid.solo", &["m x"])])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "`m` (grid.mute) starts `m x` (grid.solo), so only one of them can be pressed"
+        );
+        let err = Keymap::with_overrides(&overrides(&[("grid.jump", &["j"])])).unwrap_err();
+        assert_eq!(err.to_string(), "unknown action `jump` in [keys.grid]");
+    }
+
+    #[test]
+    fn chords_wait_for_their_second_key() {
+        let mut keys = BTreeMap::new();
+        keys.insert("grid.fill".to_string(), vec!["g f".to_string()]);
+        let keymap = Keymap::with_overrides(&keys).unwrap();
+        let mut chords = Chords::default();
+
+        assert_eq!(
+            chords.press(key("g"), Context::Grid, &keymap),
+            Press::Pending
+        );
+        assert_eq!(chords.pending().as_deref(), Some("g"));
+        assert_eq!(
+            chords.press(key("f"), Context::Grid, &keymap),
+            Press::Action(Action::ToggleFill)
+        );
+        // a key that doesn't finish the chord is taken on its own
+        chords.press(key("g"), Context::Grid, &keymap);
+        assert_eq!(
+            chords.press(key("m"), Context::Grid, &keymap),
+            Press::Action(Action::Mute)
+        );
+        // grid bindings don't fire on the song view
+        assert_eq!(
+            chords.press(key("g"), Context::Song, &keymap),
+            Press::Unbound
+        );
+        assert_eq!(
+            chords.press(key("p"), Context::Song, &keymap),
+            Press::Action(Action::PlayToggle)
+        );
+    }
+
+    #[test]
+    fn modes_shadow_the_grid_and_fall_back_to_it() {
+        let keymap = Keymap::default();
+        let mut chords = Chords::default();
+        let mut press = |k, context| chords.press(key(k), context, &keymap);
+
+        assert_eq!(press("f", Context::Grid), Press::Action(Action::Pad(3)));
+        assert_eq!(
+            press("f", Context::Visual),
+            Press::Action(Action::SelectionFill)
+        );
+        assert_eq!(press("f", Context::Insert), Press::Action(Action::Pad(3)));
+        assert_eq!(
+            press("right", Context::Visual),
+            Press::Action(Action::Right)
+        );
+        assert_eq!(press("esc", Context::Grid), Press::Unbound);
+        assert_eq!(
+            press("esc", Context::Insert),
+            Press::Action(Action::EndInsert)
+        );
+        assert_eq!(press("q", Context::Visual), Press::Action(Action::Quit));
+        assert_eq!(press("y", Context::Song), Press::Unbound);
+    }
+}
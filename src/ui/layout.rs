@@ -1,21 +1,139 @@
 // Layout rendering
-use ratatui::text::{Span, Spans};
+use std::ops::Range;
+
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
 use ratatui::{
     layout::{Constraint, Direction, Layout, Rect},
-    style::{Modifier, Style},
-    widgets::{Block, Borders, Gauge, Paragraph, Row, Table},
+    widgets::block::{Position, Title},
+    widgets::{Block, Borders, Clear, Paragraph, Row, Table},
     Frame,
 };
 
+use crate::audio::mixer::ChannelStrip;
+use crate::kit::Kit;
+use crate::sequencer::engine::Snapshot;
+use crate::sequencer::song::slot_name;
+use crate::sequencer::steps::{velocity_level, Step};
 use crate::ui::ascii::BANNER;
+use crate::ui::input::{Action, Context, Keymap};
+use crate::ui::visual::Selection;
+
+/// Width of the track name column and of each step cell.
+const NAME_WIDTH: u16 = 8;
+const STEP_WIDTH: u16 = 4;
+const COLUMN_SPACING: u16 = 1;
+/// Cell glyphs for soft, medium and accented hits.
+const HIT_GLYPHS: [&str; 3] = ["[-]", "[x]", "[X]"];
+
+/// Which view fills the middle of the screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Screen {
+    Grid,
+    Song,
+    Kits,
+}
+
+/// Editing mode of the step grid, vim style.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Moving around and single-step commands
+    Normal,
+    /// Step entry: toggles and pads write at the cursor and move it on
+    Insert,
+    /// Selecting a block of steps between an anchor and the cursor
+    Visual,
+}
+
+/// Styles for the parts of the screen that carry meaning.
+#[derive(Debug, Clone, Copy)]
+pub struct Theme {
+    /// The playhead column
+    pub playhead: Style,
+    /// Cells the playhead is triggering, on top of `playhead`
+    pub sounding: Style,
+    /// Tracks without a sound
+    pub warning: Style,
+    /// Empty bank slots, paths and other secondary text
+    pub dim: Style,
+    /// Cells in the visual block selection
+    pub selected: Style,
+}
+
+impl Theme {
+    pub const DEFAULT: &'static str = "dark";
+    pub const NAMES: &'static [&'static str] = &["dark", "light", "mono"];
+
+    /// One of the built-in themes, by name.
+    pub fn named(name: &str) -> Option<Self> {
+        let theme = match name {
+            "dark" => Self {
+                playhead: Style::default().bg(Color::DarkGray),
+                sounding: Style::default()
+                    .fg(Color::Yellow)
+                    .add_modifier(Modifier::BOLD),
+                warning: Style::default().fg(Color::Red),
+                dim: Style::default().fg(Color::DarkGray),
+                selected: Style::default().bg(Color::Blue),
+            },
+            "light" => Self {
+                playhead: Style::default().bg(Color::Gray),
+                sounding: Style::default()
+                    .fg(Color::Blue)
+                    .add_modifier(Modifier::BOLD),
+                warning: Style::default().fg(Color::Red),
+                dim: Style::default().fg(Color::Gray),
+                selected: Style::default().bg(Color::LightBlue),
+            },
+            // for terminals without colour: modifiers only
+            "mono" => Self {
+                playhead: Style::default().add_modifier(Modifier::UNDERLINED),
+                sounding: Style::default().add_modifier(Modifier::BOLD),
+                warning: Style::default().add_modifier(Modifier::BOLD | Modifier::ITALIC),
+                dim: Style::default().add_modifier(Modifier::DIM),
+                selected: Style::default().add_modifier(Modifier::BOLD | Modifier::UNDERLINED),
+            },
+            _ => return None,
+        };
+        Some(theme)
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self::named(Self::DEFAULT).unwrap()
+    }
+}
 
-pub fn render_layout<B: ratatui::backend::Backend>(
-    f: &mut Frame<B>,
-    playhead: usize,
-    cursor: (usize, usize),
-    playing: bool,
-    bpm: u32,
-) {
+/// Everything the layout draws, borrowed from the UI state.
+pub struct View<'a> {
+    pub tracks: &'a [String],
+    /// Pattern, tempo and transport as last reported by the engine
+    pub state: &'a Snapshot,
+    pub playhead: usize,
+    /// Velocity of each track on the playhead step (0 = silent)
+    pub sounding: &'a [u8],
+    pub cursor: (usize, usize), // track, step
+    pub screen: Screen,
+    pub mode: Mode,
+    /// The visual block, in visual mode
+    pub selection: Option<Selection>,
+    pub song_cursor: usize,
+    /// Blink phase for the queued pattern
+    pub blink: bool,
+    pub strips: &'a [ChannelStrip],
+    /// Why each track's sample failed to load, if it did
+    pub sample_errors: &'a [Option<String>],
+    pub kits: &'a [Kit],
+    pub kit_cursor: usize,
+    pub theme: &'a Theme,
+    pub keymap: &'a Keymap,
+    /// Whether the key binding overlay is open
+    pub help: bool,
+    pub message: &'a str,
+}
+
+pub fn render_layout(f: &mut Frame, view: &View) {
     let size = f.size();
 
     // Split: top banner, middle grid, bottom info
@@ -37,63 +155,368 @@ pub fn render_layout<B: ratatui::backend::Backend>(
         Paragraph::new(BANNER).block(Block::default().borders(Borders::ALL).title("CrateBeat"));
     f.render_widget(banner, chunks[0]);
 
-    // Sequencer grid
-    let grid_block = Block::default()
-        .borders(Borders::ALL)
-        .title("Sequencer (use arrow keys, space to toggle)");
-    let inner = grid_block.inner(chunks[1]);
-    f.render_widget(grid_block, chunks[1]);
-
-    render_grid(f, inner, playhead, cursor);
+    match view.screen {
+        Screen::Grid => render_sequencer(f, chunks[1], view),
+        Screen::Song => render_song(f, chunks[1], view),
+        Screen::Kits => render_kits(f, chunks[1], view),
+    }
 
     // Bottom info: BPM and play status
-    let status = if playing {
+    let mut status = if view.state.playing {
         "Playing ▶"
     } else {
         "Stopped ■"
-    };
-    let footer = Paragraph::new(vec![Spans::from(vec![Span::raw(format!(
-        "Status: {}  |  BPM: {}  |  Controls: p Play/Stop  q Quit  a/s/d/f Pads  +/- BPM",
-        status, bpm
-    ))])]);
+    }
+    .to_string();
+    if view.state.fill {
+        status += " FILL";
+    }
+    let record = &view.state.record;
+    if view.state.count_in > 0 {
+        status += &format!(" ● REC in {}", view.state.count_in);
+    } else if view.state.recording {
+        status += &format!(" ● REC {} q{}%", record.mode_name(), record.quantize);
+    }
+    if view.screen == Screen::Grid {
+        match view.mode {
+            Mode::Normal => {}
+            Mode::Insert => status += "  -- INSERT --",
+            Mode::Visual => status += "  -- VISUAL BLOCK --",
+        }
+    }
+    let keys = |action| view.keymap.keys_for(action);
+    let footer = Paragraph::new(vec![
+        Line::from(vec![Span::raw(format!(
+            "Status: {}  |  BPM: {}  |  Swing: {}%  |  Controls: {} Play/Stop  {} Quit  {} Keys  {} Song  {} Kits  :w/:e Save/Open",
+            status,
+            view.state.bpm,
+            view.state.swing,
+            keys(Action::PlayToggle),
+            keys(Action::Quit),
+            keys(Action::Help),
+            keys(Action::NextScreen),
+            keys(Action::Kits),
+        ))]),
+        Line::from(view.message),
+    ]);
     f.render_widget(footer, chunks[2]);
+
+    if view.help {
+        render_help(f, chunks[1], view);
+    }
 }
 
-fn render_grid<B: ratatui::backend::Backend>(
-    f: &mut Frame<B>,
-    area: Rect,
-    playhead: usize,
-    cursor: (usize, usize),
-) {
+/// Every action with the keys it is bound to, over the middle of the screen.
+fn render_help(f: &mut Frame, area: Rect, view: &View) {
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title("Keys (any key closes; rebind them under [keys] in the config)");
+    let inner = block.inner(area);
+    f.render_widget(Clear, area);
+    f.render_widget(block, area);
+
+    // contexts one after another, flowing into as many columns as it takes
+    let described = view.keymap.describe();
+    let mut lines = Vec::new();
+    for context in Context::ALL {
+        if !lines.is_empty() {
+            lines.push(Line::from(""));
+        }
+        lines.push(Line::styled(
+            context.name(),
+            Style::default().add_modifier(Modifier::BOLD),
+        ));
+        for (_, action, keys) in described.iter().filter(|(c, _, _)| *c == context) {
+            let style = if keys == "unbound" {
+                view.theme.dim
+            } else {
+                Style::default()
+            };
+            lines.push(Line::styled(
+                format!("{:<10} {}", keys, action.help()),
+                style,
+            ));
+        }
+    }
+    let height = (inner.height as usize).max(1);
+    let count = lines.len().div_ceil(height);
+    let columns = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints(vec![Constraint::Ratio(1, count as u32); count])
+        .split(inner);
+    for (chunk, column) in lines.chunks(height).zip(columns.iter()) {
+        f.render_widget(Paragraph::new(chunk.to_vec()), *column);
+    }
+}
+
+/// The step grid, scrolled a page at a time so the cursor stays visible.
+fn render_sequencer(f: &mut Frame, area: Rect, view: &View) {
+    let inner = Block::default().borders(Borders::ALL).inner(area);
+    let visible_steps =
+        ((inner.width.saturating_sub(NAME_WIDTH)) / (STEP_WIDTH + COLUMN_SPACING)).max(1) as usize;
+    let visible_tracks = (inner.height as usize).max(1);
+    let first_step = view.cursor.1 / visible_steps * visible_steps;
+    let first_track = view.cursor.0 / visible_tracks * visible_tracks;
+    let total_steps = view.state.steps;
+    let total_tracks = view.state.pattern.len();
+    let steps = first_step..(first_step + visible_steps).min(total_steps);
+    let tracks = first_track..(first_track + visible_tracks).min(total_tracks);
+
+    let mut title = format!(
+        "Sequencer {} ({} for keys)",
+        slot_name(view.state.slot),
+        view.keymap.keys_for(Action::Help)
+    );
+    // the queued pattern blinks until it takes over
+    if let Some(next) = view.state.queued {
+        let next = if view.blink {
+            slot_name(next)
+        } else {
+            " ".repeat(3)
+        };
+        title += &format!("  next: {}", next);
+    }
+    if steps.len() < total_steps {
+        title += &format!(
+            "  steps {}-{} of {}",
+            steps.start + 1,
+            steps.end,
+            total_steps
+        );
+    }
+    if tracks.len() < total_tracks {
+        title += &format!(
+            "  tracks {}-{} of {}",
+            tracks.start + 1,
+            tracks.end,
+            total_tracks
+        );
+    }
+    // details of the cursor cell along the bottom edge
+    let cell = view
+        .state
+        .pattern
+        .get(view.cursor.0)
+        .and_then(|row| row.get(view.cursor.1))
+        .copied()
+        .unwrap_or_default();
+    let mut details = format!("step {}", view.cursor.1 + 1);
+    if cell.is_on() {
+        details += &format!("  vel {}", cell.velocity);
+    }
+    if cell.probability < 100 {
+        details += &format!("  prob {}%", cell.probability);
+    }
+    if let Some(condition) = cell.condition {
+        details += &format!("  cond {}", condition);
+    }
+    if let Some(Some(error)) = view.sample_errors.get(view.cursor.0) {
+        details += &format!("  {}", error);
+    }
+    let grid_block = Block::default()
+        .borders(Borders::ALL)
+        .title(title)
+        .title(Title::from(details).position(Position::Bottom));
+    f.render_widget(grid_block, area);
+
+    render_grid(f, inner, view, tracks, steps);
+}
+
+/// The pattern bank and the arrangement, with the playing entry marked.
+fn render_song(f: &mut Frame, area: Rect, view: &View) {
+    let state = view.state;
+    let mode = if state.song_mode { "on" } else { "off" };
+    let block = Block::default().borders(Borders::ALL).title(format!(
+        "Song mode {} ({} toggles, {} for keys)",
+        mode,
+        view.keymap.keys_for(Action::SongMode),
+        view.keymap.keys_for(Action::Help)
+    ));
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+
+    // bank: used slots bright, the playing one highlighted, the queued one blinking
+    let mut bank = vec![Span::raw("Bank ")];
+    for (slot, &used) in state.used.iter().enumerate() {
+        let mut style = if used {
+            Style::default().add_modifier(Modifier::BOLD)
+        } else {
+            view.theme.dim
+        };
+        if slot == state.slot {
+            style = style.add_modifier(Modifier::REVERSED);
+        }
+        if state.queued == Some(slot) && view.blink {
+            style = style.add_modifier(Modifier::REVERSED);
+        }
+        bank.push(Span::styled(slot_name(slot), style));
+        bank.push(Span::raw(" "));
+    }
+
+    let mut lines = vec![Line::from(bank), Line::from("")];
+    if state.song.is_empty() {
+        lines.push(Line::from(format!(
+            "Empty arrangement: press {} to add a section",
+            view.keymap.keys_for(Action::SongInsert)
+        )));
+    }
+    // keep the selected entry on screen
+    let visible = (inner.height as usize).saturating_sub(2).max(1);
+    let first = view.song_cursor / visible * visible;
+    for (i, entry) in state.song.iter().enumerate().skip(first).take(visible) {
+        let here = state.song_mode && state.song_position.entry == i;
+        let marker = if here { "▶" } else { " " };
+        let progress = if here {
+            format!("  ({}/{})", state.song_position.repeat + 1, entry.repeats)
+        } else {
+            String::new()
+        };
+        let text = format!(
+            "{} {:>2}. {} x{}{}",
+            marker,
+            i + 1,
+            slot_name(entry.pattern),
+            entry.repeats,
+            progress
+        );
+        let style = if i == view.song_cursor {
+            Style::default().add_modifier(Modifier::REVERSED)
+        } else {
+            Style::default()
+        };
+        lines.push(Line::styled(text, style));
+    }
+    f.render_widget(Paragraph::new(lines), inner);
+}
+
+/// The kits found, with the selected kit's sounds.
+fn render_kits(f: &mut Frame, area: Rect, view: &View) {
+    let block = Block::default().borders(Borders::ALL).title(format!(
+        "Kits ({} loads onto the tracks, {} back)",
+        view.keymap.keys_for(Action::KitLoad),
+        view.keymap.keys_for(Action::KitBack)
+    ));
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+
+    if view.kits.is_empty() {
+        let help = "No kits found. Add a directory with a kit.toml to ./kits or ~/.local/share/cratebeat/kits";
+        f.render_widget(Paragraph::new(help), inner);
+        return;
+    }
+
+    let chunks = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Length(24), Constraint::Min(10)].as_ref())
+        .split(inner);
+
+    // keep the selected kit on screen
+    let visible = (chunks[0].height as usize).max(1);
+    let first = view.kit_cursor / visible * visible;
+    let names: Vec<Line> = view
+        .kits
+        .iter()
+        .enumerate()
+        .skip(first)
+        .take(visible)
+        .map(|(i, kit)| {
+            let style = if i == view.kit_cursor {
+                Style::default().add_modifier(Modifier::REVERSED)
+            } else {
+                Style::default()
+            };
+            Line::styled(format!("{} ({})", kit.name, kit.tracks.len()), style)
+        })
+        .collect();
+    f.render_widget(Paragraph::new(names), chunks[0]);
+
+    let Some(kit) = view.kits.get(view.kit_cursor) else {
+        return;
+    };
+    let mut lines = vec![
+        Line::styled(kit.dir.display().to_string(), view.theme.dim),
+        Line::from(""),
+    ];
+    for track in &kit.tracks {
+        let mut text = format!(
+            "{:<8} {}  gain {:.2}  pan {:+.2}",
+            track.name,
+            track.sample.display(),
+            track.gain,
+            track.pan
+        );
+        if let Some(group) = track.choke {
+            text += &format!("  choke {}", group);
+        }
+        lines.push(Line::from(text));
+    }
+    f.render_widget(Paragraph::new(lines), chunks[1]);
+}
+
+/// Velocity glyph, with a `?` after hits that don't always play.
+fn cell_glyph(cell: Step) -> String {
+    if !cell.is_on() {
+        return "[ ]".to_string();
+    }
+    let glyph = HIT_GLYPHS[velocity_level(cell.velocity)];
+    if cell.is_conditional() {
+        format!("{}?", glyph)
+    } else {
+        glyph.to_string()
+    }
+}
+
+fn render_grid(f: &mut Frame, area: Rect, view: &View, tracks: Range<usize>, steps: Range<usize>) {
     use ratatui::widgets::Cell;
-    let tracks = vec!["Kick", "Snare", "Hat", "Clap"];
-    let steps = 8;
 
     let mut rows = Vec::new();
-    for (ti, name) in tracks.iter().enumerate() {
-        let mut cells = vec![Cell::from(*name)];
-        for si in 0..steps {
-            let mut label = "[ ]".to_string();
-            if si == playhead {
-                // show playhead marker inside
-                label = format!("[{}]", if si == playhead { "•" } else { " " });
+    for ti in tracks {
+        let name = view.tracks.get(ti).map(String::as_str).unwrap_or("");
+        // mute / solo flags after the track name
+        let strip = view.strips.get(ti).copied().unwrap_or_default();
+        let flags = match (strip.mute, strip.solo) {
+            (true, _) => " M",
+            (false, true) => " S",
+            _ => "",
+        };
+        // tracks without a sound are flagged in red
+        let name_cell = if view.sample_errors.get(ti).is_some_and(Option::is_some) {
+            Cell::from(format!("{}{} !", name, flags)).style(view.theme.warning)
+        } else {
+            Cell::from(format!("{}{}", name, flags))
+        };
+        let mut cells = vec![name_cell];
+        for si in steps.clone() {
+            let cell = view.state.pattern[ti].get(si).copied().unwrap_or_default();
+            let mut style = Style::default();
+            if view.state.playing && si == view.playhead {
+                // playhead column, with the cells it is triggering lit up
+                style = view.theme.playhead;
+                if view.sounding.get(ti).is_some_and(|&v| v > 0) {
+                    style = style.patch(view.theme.sounding);
+                }
             }
-            // indicate cursor
-            if cursor == (ti, si) {
-                label = format!("({})", si);
+            if view
+                .selection
+                .as_ref()
+                .is_some_and(|sel| sel.contains(ti, si))
+            {
+                style = style.patch(view.theme.selected);
             }
-            cells.push(Cell::from(label));
+            if view.cursor == (ti, si) {
+                style = style.add_modifier(Modifier::REVERSED);
+            }
+            cells.push(Cell::from(cell_glyph(cell)).style(style));
         }
         rows.push(Row::new(cells));
     }
 
-    let mut table = Table::new(rows, None)
-        .widths(
-            &std::iter::once(Constraint::Length(8))
-                .chain(std::iter::repeat(Constraint::Length(6)).take(steps))
-                .collect::<Vec<_>>(),
-        )
-        .column_spacing(1)
+    let widths = std::iter::once(Constraint::Length(NAME_WIDTH)).chain(std::iter::repeat_n(
+        Constraint::Length(STEP_WIDTH),
+        steps.len(),
+    ));
+    let table = Table::new(rows, widths)
+        .column_spacing(COLUMN_SPACING)
         .block(Block::default());
 
     f.render_widget(table, area);
@@ -2,42 +2,116 @@
 pub mod ascii;
 pub mod input;
 pub mod layout;
+pub mod tap;
+pub mod visual;
 
 use anyhow::Result;
 use crossterm::{
-    event::{Event, KeyCode, KeyEvent, KeyModifiers},
+    event::{Event, KeyCode, KeyEvent},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
-use ratatui::{
-    backend::CrosstermBackend,
-    Terminal,
-};
+use ratatui::{backend::CrosstermBackend, Terminal};
 use std::io::{stdout, Stdout};
+use std::time::Instant;
+
+use crate::audio::mixer::ChannelStrip;
+use crate::kit::Kit;
+use crate::sequencer::clock::{MAX_BPM, MAX_SWING, MIN_BPM, MIN_SWING};
+use crate::sequencer::engine::Snapshot;
+use crate::sequencer::record::{RecordSettings, MAX_COUNT_IN};
+use crate::sequencer::song::{slot_name, SongEntry, BANK_SLOTS};
+use crate::sequencer::steps::{
+    next_condition, next_velocity_level, Condition, Step, DEFAULT_VELOCITY, MAX_STEPS,
+};
+use crate::ui::input::{Action, Chords, Context, Key, Keymap, Press};
+use crate::ui::layout::{render_layout, Mode, Screen, Theme, View};
+use crate::ui::tap::TapTempo;
+use crate::ui::visual::{paste, Clipboard, Edits, Selection};
 
-use crate::ui::ascii::BANNER;
+/// Half-period of the queued pattern's blink.
+const BLINK_MS: u128 = 250;
 
 #[derive(Debug)]
 pub enum UiEvent {
     Exit,
     PlayToggle,
     BpmChange(i32),
-    ToggleStep(usize, usize), // track, step
-    ManualHit(Option<String>),
+    SetBpm(f64),                                   // tap tempo or `:bpm N`
+    SetSwing(u8),                                  // percent, already clamped
+    ToggleStep(usize, usize),                      // track, step
+    SetVelocity(usize, usize, u8),                 // track, step, velocity
+    SetProbability(usize, usize, u8),              // track, step, percent
+    SetCondition(usize, usize, Option<Condition>), // track, step, condition
+    SetSteps(Edits),                               // visual block edits and pastes
+    Undo,
+    Redo,
+    SetFill(bool),
+    QueuePattern(usize, Option<usize>), // bank slot, steps until the switch (next bar if None)
+    SetSong(Vec<SongEntry>),
+    SetSongMode(bool),
+    ManualHit(usize), // track
+    Record(bool),
+    SetRecordSettings(RecordSettings),
+    ToggleMute(usize),
+    ToggleSolo(usize),
+    SaveProject(Option<String>), // `:w [path]`
+    OpenProject(String),         // `:e path`
+    Bounce(String, u32),         // `:bounce path [bars]`
+    ExportMidi(String, bool),    // `:midi path [song]`
+    ImportMidi(String),          // `:import path`
+    StartOsc(u16),               // `:osc [port]`
+    BrowseKits,                  // `k` / `:kits`
+    LoadKit(String),             // `:kit name`, or Enter in the kit browser
+    AddTrack(Option<String>),    // `:addtrack [name]`
+    RemoveTrack(usize),          // `:deltrack` (track under cursor)
+    ResizePattern(usize),        // `:steps N`
     Noop,
 }
 
 pub struct Ui {
     terminal: Terminal<CrosstermBackend<Stdout>>,
     playhead: usize,
+    // velocity of each track on the playhead step (0 = silent)
+    sounding: Vec<u8>,
     // UI state (simple)
     pub cursor: (usize, usize), // track, step
-    pub playing: bool,
-    pub bpm: u32,
+    screen: Screen,
+    mode: Mode,
+    // other corner of the visual block
+    anchor: (usize, usize),
+    // last block yanked in visual mode
+    clipboard: Clipboard,
+    // selected entry in the song view
+    song_cursor: usize,
+    // last state reported by the engine; the grid is drawn from this
+    state: Snapshot,
+    // `:` command line being typed, if any
+    command: Option<String>,
+    // last status / error message shown under the footer
+    message: String,
+    // mixer state per track, for the mute / solo flags
+    strips: Vec<ChannelStrip>,
+    // track display names
+    tracks: Vec<String>,
+    // why each track's sample failed to load, if it did
+    sample_errors: Vec<Option<String>>,
+    // kits found when the browser was opened, and the selected one
+    kits: Vec<Kit>,
+    kit_cursor: usize,
+    theme: Theme,
+    keymap: Keymap,
+    // keys pressed so far towards a multi-key binding
+    chords: Chords,
+    // whether the key binding overlay is open
+    help: bool,
+    tap: TapTempo,
+    // drives the blinking of a queued pattern
+    started: Instant,
 }
 
 impl Ui {
-    pub fn new() -> Result<Self> {
+    pub fn new(theme: Theme, keymap: Keymap) -> Result<Self> {
         enable_raw_mode()?;
         let mut stdout = stdout();
         execute!(stdout, EnterAlternateScreen)?;
@@ -48,30 +122,124 @@ impl Ui {
         Ok(Self {
             terminal,
             playhead: 0,
+            sounding: Vec::new(),
             cursor: (0, 0),
-            playing: false,
-            bpm: 120,
+            screen: Screen::Grid,
+            mode: Mode::Normal,
+            anchor: (0, 0),
+            clipboard: Clipboard::new(),
+            song_cursor: 0,
+            state: Snapshot::default(),
+            command: None,
+            message: String::new(),
+            strips: Vec::new(),
+            tracks: Vec::new(),
+            sample_errors: Vec::new(),
+            kits: Vec::new(),
+            kit_cursor: 0,
+            theme,
+            keymap,
+            chords: Chords::default(),
+            help: false,
+            tap: TapTempo::default(),
+            started: Instant::now(),
         })
     }
 
     /// Called by app to advance visual playhead
-    pub fn set_playhead(&mut self, idx: usize) {
+    pub fn set_playhead(&mut self, idx: usize, hits: Vec<u8>) {
         self.playhead = idx;
+        self.sounding = hits;
+    }
+
+    /// Called by app whenever the engine reports a new state
+    pub fn set_state(&mut self, state: Snapshot) {
+        if !state.playing {
+            self.sounding.clear();
+        }
+        self.state = state;
+        self.cursor.0 = self
+            .cursor
+            .0
+            .min(self.state.pattern.len().saturating_sub(1));
+        self.cursor.1 = self.cursor.1.min(self.state.steps.saturating_sub(1));
+        self.anchor.0 = self
+            .anchor
+            .0
+            .min(self.state.pattern.len().saturating_sub(1));
+        self.anchor.1 = self.anchor.1.min(self.state.steps.saturating_sub(1));
+        self.song_cursor = self
+            .song_cursor
+            .min(self.state.song.len().saturating_sub(1));
+    }
+
+    /// Called by app when tracks are added, removed or renamed, or their samples change
+    pub fn set_tracks(&mut self, tracks: Vec<String>, sample_errors: Vec<Option<String>>) {
+        self.tracks = tracks;
+        self.sample_errors = sample_errors;
+    }
+
+    /// Called by app with the kits found, to open the kit browser
+    pub fn show_kits(&mut self, kits: Vec<Kit>) {
+        self.kit_cursor = self.kit_cursor.min(kits.len().saturating_sub(1));
+        self.kits = kits;
+        self.screen = Screen::Kits;
+        self.mode = Mode::Normal;
+        self.chords.clear();
+    }
+
+    /// Called by app after mixer changes
+    pub fn set_strips(&mut self, strips: Vec<ChannelStrip>) {
+        self.strips = strips;
+    }
+
+    /// Called by app when a queued pattern takes over during playback
+    pub fn pattern_switched(&mut self, slot: usize) {
+        self.set_message(format!("Now playing {}", slot_name(slot)));
+    }
+
+    /// Show a one-line status or error message in the footer
+    pub fn set_message(&mut self, msg: impl Into<String>) {
+        self.message = msg.into();
     }
 
     /// Ticks the UI: render and handle one input cycle.
     /// Blocking but with a short timeout for drawing smoother UI.
     pub fn tick(&mut self) -> Result<UiEvent> {
         // Render UI
-        self.terminal.draw(|f| {
-            crate::ui::layout::render_layout(f, self.playhead, self.cursor, self.playing, self.bpm);
-        })?;
+        let message = match (&self.command, self.chords.pending()) {
+            (Some(cmd), _) => format!(":{}", cmd),
+            (None, Some(keys)) => format!("{} …", keys),
+            (None, None) => self.message.clone(),
+        };
+        let view = View {
+            tracks: &self.tracks,
+            state: &self.state,
+            playhead: self.playhead,
+            sounding: &self.sounding,
+            cursor: self.cursor,
+            screen: self.screen,
+            mode: self.mode,
+            selection: self.selection(),
+            song_cursor: self.song_cursor,
+            blink: (self.started.elapsed().as_millis() / BLINK_MS).is_multiple_of(2),
+            strips: &self.strips,
+            sample_errors: &self.sample_errors,
+            kits: &self.kits,
+            kit_cursor: self.kit_cursor,
+            theme: &self.theme,
+            keymap: &self.keymap,
+            help: self.help,
+            message: &message,
+        };
+        self.terminal.draw(|f| render_layout(f, &view))?;
 
         // Poll for input with timeout
         use crossterm::event::{poll, read};
         use std::time::Duration;
 
-        if poll(Duration::from_millis(200))? {
+        // short enough that the playhead keeps up with fast tempos
+        if poll(Duration::from_millis(16))? {
             if let Event::Key(k) = read()? {
                 return Ok(self.handle_key(k));
             }
@@ -80,48 +248,502 @@ impl Ui {
     }
 
     fn handle_key(&mut self, key: KeyEvent) -> UiEvent {
-        match key.code {
-            KeyCode::Char('q') => UiEvent::Exit,
-            KeyCode::Char('p') => {
-                self.playing = !self.playing;
-                UiEvent::PlayToggle
+        if self.command.is_some() {
+            return self.handle_command_key(key);
+        }
+        // any key closes the help overlay
+        if self.help {
+            self.help = false;
+            return UiEvent::Noop;
+        }
+
+        let context = match (self.screen, self.mode) {
+            (Screen::Grid, Mode::Normal) => Context::Grid,
+            (Screen::Grid, Mode::Insert) => Context::Insert,
+            (Screen::Grid, Mode::Visual) => Context::Visual,
+            (Screen::Song, _) => Context::Song,
+            (Screen::Kits, _) => Context::Kits,
+        };
+        match self.chords.press(Key::from(key), context, &self.keymap) {
+            Press::Action(action) => self.perform(action),
+            Press::Pending | Press::Unbound => UiEvent::Noop,
+        }
+    }
+
+    fn perform(&mut self, action: Action) -> UiEvent {
+        // actions that work the same on every screen
+        match action {
+            Action::Quit => return UiEvent::Exit,
+            Action::CommandLine => {
+                self.command = Some(String::new());
+                return UiEvent::Noop;
             }
-            KeyCode::Char('+') => {
-                self.bpm = (self.bpm + 5).min(300);
-                UiEvent::BpmChange(5)
+            Action::PlayToggle => return UiEvent::PlayToggle,
+            Action::Record => return UiEvent::Record(!self.state.recording),
+            Action::BpmUp => return UiEvent::BpmChange(5),
+            Action::BpmDown => return UiEvent::BpmChange(-5),
+            Action::TapTempo => return self.tap_tempo(),
+            Action::Kits => return UiEvent::BrowseKits,
+            Action::Help => {
+                self.help = true;
+                return UiEvent::Noop;
             }
-            KeyCode::Char('-') => {
-                self.bpm = (self.bpm.saturating_sub(5)).max(20);
-                UiEvent::BpmChange(-5)
+            Action::NextScreen => {
+                self.mode = Mode::Normal;
+                self.screen = match self.screen {
+                    Screen::Grid => Screen::Song,
+                    Screen::Song | Screen::Kits => Screen::Grid,
+                };
+                return UiEvent::Noop;
             }
-            KeyCode::Right => {
-                self.cursor.1 = (self.cursor.1 + 1) % 8;
+            _ => {}
+        }
+
+        match action.context() {
+            Context::Grid | Context::Insert | Context::Visual => self.grid_action(action),
+            Context::Song => self.song_action(action),
+            Context::Kits => self.kit_action(action),
+            Context::Global => UiEvent::Noop,
+        }
+    }
+
+    /// Kit browser: pick a kit and load it onto the tracks
+    fn kit_action(&mut self, action: Action) -> UiEvent {
+        let kits = self.kits.len();
+        match action {
+            Action::KitDown if kits > 0 => self.kit_cursor = (self.kit_cursor + 1) % kits,
+            Action::KitUp if kits > 0 => self.kit_cursor = (self.kit_cursor + kits - 1) % kits,
+            Action::KitLoad => {
+                if let Some(kit) = self.kits.get(self.kit_cursor) {
+                    self.screen = Screen::Grid;
+                    return UiEvent::LoadKit(kit.name.clone());
+                }
+            }
+            Action::KitBack => self.screen = Screen::Grid,
+            _ => {}
+        }
+        UiEvent::Noop
+    }
+
+    fn grid_action(&mut self, action: Action) -> UiEvent {
+        // cursor wraps around the grid
+        let tracks = self.state.pattern.len().max(1);
+        let steps = self.state.steps.max(1);
+
+        match action {
+            // step entry: write at the cursor and move on to the next step
+            Action::ToggleStep if self.mode == Mode::Insert => {
+                let (track, step) = self.cursor;
+                self.cursor.1 = (step + 1) % steps;
+                UiEvent::ToggleStep(track, step)
+            }
+            Action::Pad(track) if self.mode == Mode::Insert && track < tracks => {
+                let step = self.cursor.1;
+                self.cursor.1 = (step + 1) % steps;
+                UiEvent::SetSteps(vec![(track, step, Step::hit(DEFAULT_VELOCITY))])
+            }
+            Action::InsertMode => {
+                self.mode = Mode::Insert;
+                UiEvent::Noop
+            }
+            Action::VisualMode => {
+                self.mode = Mode::Visual;
+                self.anchor = self.cursor;
                 UiEvent::Noop
             }
-            KeyCode::Left => {
-                self.cursor.1 = (8 + self.cursor.1 - 1) % 8;
+            Action::EndInsert | Action::EndVisual => {
+                self.mode = Mode::Normal;
                 UiEvent::Noop
             }
-            KeyCode::Down => {
-                self.cursor.0 = (self.cursor.0 + 1) % 4;
+            Action::Paste => {
+                // onto the block's corner in visual mode, else at the cursor
+                let at = self.selection().map_or(self.cursor, |sel| sel.start());
+                self.mode = Mode::Normal;
+                block_edit(paste(&self.state.pattern, &self.clipboard, at))
+            }
+            Action::SelectionFill
+            | Action::SelectionClear
+            | Action::SelectionInvert
+            | Action::SelectionLeft
+            | Action::SelectionRight
+            | Action::SelectionYank
+            | Action::SelectionRepeat => self.selection_action(action),
+            Action::Undo => self.undo(),
+            Action::Redo => self.redo(),
+            Action::SwingUp => self.swing_by(1),
+            Action::SwingDown => self.swing_by(-1),
+            Action::Right => {
+                self.cursor.1 = (self.cursor.1 + 1) % steps;
+                UiEvent::Noop
+            }
+            Action::Left => {
+                self.cursor.1 = (steps + self.cursor.1 - 1) % steps;
                 UiEvent::Noop
             }
-            KeyCode::Up => {
-                self.cursor.0 = (4 + self.cursor.0 - 1) % 4;
+            Action::Down => {
+                self.cursor.0 = (self.cursor.0 + 1) % tracks;
                 UiEvent::Noop
             }
-            KeyCode::Char(' ') => {
+            Action::Up => {
+                self.cursor.0 = (tracks + self.cursor.0 - 1) % tracks;
+                UiEvent::Noop
+            }
+            Action::ToggleStep => {
                 // toggle step under cursor
                 UiEvent::ToggleStep(self.cursor.0, self.cursor.1)
             }
-            KeyCode::Char('a') => UiEvent::ManualHit(Some("kick".into())),
-            KeyCode::Char('s') => UiEvent::ManualHit(Some("snare".into())),
-            KeyCode::Char('d') => UiEvent::ManualHit(Some("hat".into())),
-            KeyCode::Char('f') => UiEvent::ManualHit(Some("clap".into())),
+            Action::CycleVelocity => {
+                // cycle soft / medium / accent on the cursor cell
+                let (track, step) = self.cursor;
+                let velocity = next_velocity_level(self.cursor_cell().velocity);
+                UiEvent::SetVelocity(track, step, velocity)
+            }
+            Action::ProbabilityDown => self.probability_by(-10),
+            Action::ProbabilityUp => self.probability_by(10),
+            Action::CycleCondition => {
+                let (track, step) = self.cursor;
+                UiEvent::SetCondition(track, step, next_condition(self.cursor_cell().condition))
+            }
+            Action::ToggleFill => UiEvent::SetFill(!self.state.fill),
+            Action::NextPattern => self.select_pattern_by(1),
+            Action::PrevPattern => self.select_pattern_by(BANK_SLOTS - 1),
+            Action::Mute => UiEvent::ToggleMute(self.cursor.0),
+            Action::Solo => UiEvent::ToggleSolo(self.cursor.0),
+            Action::Pad(track) => UiEvent::ManualHit(track),
             _ => UiEvent::Noop,
         }
     }
 
+    /// Visual mode edits of the selected block. Shifts keep the block
+    /// selected so they can be repeated; the rest go back to normal mode.
+    fn selection_action(&mut self, action: Action) -> UiEvent {
+        let Some(sel) = self.selection() else {
+            return UiEvent::Noop;
+        };
+        let pattern = &self.state.pattern;
+        let edits = match action {
+            Action::SelectionFill => sel.fill(pattern),
+            Action::SelectionClear => sel.clear(pattern),
+            Action::SelectionInvert => sel.invert(pattern),
+            Action::SelectionLeft => return block_edit(sel.shift(pattern, false)),
+            Action::SelectionRight => return block_edit(sel.shift(pattern, true)),
+            Action::SelectionYank => {
+                self.clipboard = sel.yank(pattern);
+                self.set_message(format!(
+                    "Copied {} track(s) x {} step(s)",
+                    sel.tracks.len(),
+                    sel.steps.len()
+                ));
+                Edits::new()
+            }
+            Action::SelectionRepeat => {
+                // `:every N` asks for the period
+                self.command = Some("every ".to_string());
+                return UiEvent::Noop;
+            }
+            _ => return UiEvent::Noop,
+        };
+        self.mode = Mode::Normal;
+        block_edit(edits)
+    }
+
+    fn tap_tempo(&mut self) -> UiEvent {
+        match self.tap.tap(Instant::now()) {
+            Some(bpm) => {
+                self.set_message(format!(
+                    "Tap tempo: {} BPM ({} taps)",
+                    bpm,
+                    self.tap.count()
+                ));
+                UiEvent::SetBpm(bpm)
+            }
+            None => {
+                self.set_message("Tap again to set the tempo");
+                UiEvent::Noop
+            }
+        }
+    }
+
+    fn undo(&mut self) -> UiEvent {
+        if self.state.history.0 == 0 {
+            self.set_message("Already at oldest change");
+            return UiEvent::Noop;
+        }
+        UiEvent::Undo
+    }
+
+    fn redo(&mut self) -> UiEvent {
+        if self.state.history.1 == 0 {
+            self.set_message("Already at newest change");
+            return UiEvent::Noop;
+        }
+        UiEvent::Redo
+    }
+
+    /// The visual block, while in visual mode
+    fn selection(&self) -> Option<Selection> {
+        (self.mode == Mode::Visual).then(|| Selection::new(self.anchor, self.cursor))
+    }
+
+    /// Song view: edit the arrangement under the song cursor
+    fn song_action(&mut self, action: Action) -> UiEvent {
+        let mut song = self.state.song.clone();
+        let entry = self.song_cursor;
+        match action {
+            Action::SongDown if !song.is_empty() => {
+                self.song_cursor = (entry + 1) % song.len();
+                return UiEvent::Noop;
+            }
+            Action::SongUp if !song.is_empty() => {
+                self.song_cursor = (entry + song.len() - 1) % song.len();
+                return UiEvent::Noop;
+            }
+            Action::SongMode => return UiEvent::SetSongMode(!self.state.song_mode),
+            Action::SongInsert => {
+                // new entries start as a copy of the selected one
+                let new = song
+                    .get(entry)
+                    .copied()
+                    .unwrap_or(SongEntry::new(self.state.slot, 1));
+                let at = if song.is_empty() { 0 } else { entry + 1 };
+                song.insert(at, new);
+                self.song_cursor = at;
+            }
+            Action::SongDelete if entry < song.len() => {
+                song.remove(entry);
+            }
+            Action::SongPatternUp if entry < song.len() => {
+                song[entry].pattern = (song[entry].pattern + 1) % BANK_SLOTS;
+            }
+            Action::SongPatternDown if entry < song.len() => {
+                song[entry].pattern = (song[entry].pattern + BANK_SLOTS - 1) % BANK_SLOTS;
+            }
+            Action::SongMore if entry < song.len() => {
+                song[entry].repeats += 1;
+            }
+            Action::SongFewer if entry < song.len() => {
+                song[entry].repeats = song[entry].repeats.saturating_sub(1).max(1);
+            }
+            _ => return UiEvent::Noop,
+        }
+        UiEvent::SetSong(song)
+    }
+
+    /// Queue the slot `offset` places after the playing (or already queued) one
+    fn select_pattern_by(&self, offset: usize) -> UiEvent {
+        let from = self.state.queued.unwrap_or(self.state.slot);
+        UiEvent::QueuePattern((from + offset) % BANK_SLOTS, None)
+    }
+
+    /// The step under the cursor, as the engine last reported it
+    fn cursor_cell(&self) -> Step {
+        let (track, step) = self.cursor;
+        self.state
+            .pattern
+            .get(track)
+            .and_then(|row| row.get(step))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn probability_by(&self, delta: i32) -> UiEvent {
+        let (track, step) = self.cursor;
+        let probability = (self.cursor_cell().probability as i32 + delta).clamp(0, 100);
+        UiEvent::SetProbability(track, step, probability as u8)
+    }
+
+    fn swing_by(&self, delta: i32) -> UiEvent {
+        let swing = (self.state.swing as i32 + delta).clamp(MIN_SWING as i32, MAX_SWING as i32);
+        UiEvent::SetSwing(swing as u8)
+    }
+
+    /// Keys typed while the `:` command line is open
+    fn handle_command_key(&mut self, key: KeyEvent) -> UiEvent {
+        let Some(cmd) = self.command.as_mut() else {
+            return UiEvent::Noop;
+        };
+        match key.code {
+            KeyCode::Esc => self.command = None,
+            KeyCode::Backspace if cmd.is_empty() => self.command = None,
+            KeyCode::Backspace => {
+                cmd.pop();
+            }
+            KeyCode::Char(c) => cmd.push(c),
+            KeyCode::Enter => {
+                let cmd = self.command.take().unwrap_or_default();
+                return self.run_command(cmd.trim());
+            }
+            _ => {}
+        }
+        UiEvent::Noop
+    }
+
+    fn run_command(&mut self, cmd: &str) -> UiEvent {
+        let (name, arg) = match cmd.split_once(char::is_whitespace) {
+            Some((name, arg)) => (name, Some(arg.trim().to_string())),
+            None => (cmd, None),
+        };
+        match (name, arg) {
+            ("w", path) => UiEvent::SaveProject(path),
+            ("e", Some(path)) => UiEvent::OpenProject(path),
+            ("e", None) => {
+                self.set_message("E32: No file name");
+                UiEvent::Noop
+            }
+            ("bounce", Some(arg)) => match crate::render::parse_bounce_args(&arg) {
+                Ok((path, bars)) => UiEvent::Bounce(path, bars),
+                Err(e) => {
+                    self.set_message(format!("Bounce: {}", e));
+                    UiEvent::Noop
+                }
+            },
+            ("midi", Some(arg)) => match arg.rsplit_once(char::is_whitespace) {
+                Some((path, "song")) => UiEvent::ExportMidi(path.trim().to_string(), true),
+                _ => UiEvent::ExportMidi(arg, false),
+            },
+            ("import", Some(path)) => UiEvent::ImportMidi(path),
+            ("osc", None) => UiEvent::StartOsc(crate::osc::DEFAULT_PORT),
+            ("osc", Some(port)) => match port.parse() {
+                Ok(port) => UiEvent::StartOsc(port),
+                Err(_) => {
+                    self.set_message("Usage: :osc [port]");
+                    UiEvent::Noop
+                }
+            },
+            ("kits", None) => UiEvent::BrowseKits,
+            ("kit", Some(name)) => UiEvent::LoadKit(name),
+            ("kit", None) => UiEvent::BrowseKits,
+            ("addtrack", name) => UiEvent::AddTrack(name),
+            ("deltrack", None) => UiEvent::RemoveTrack(self.cursor.0),
+            ("steps", Some(n)) => match n.parse() {
+                Ok(n) if (1..=MAX_STEPS).contains(&n) => UiEvent::ResizePattern(n),
+                _ => {
+                    self.set_message(format!("Steps must be 1-{}", MAX_STEPS));
+                    UiEvent::Noop
+                }
+            },
+            ("bpm", Some(n)) => match n.parse() {
+                Ok(bpm) if (MIN_BPM..=MAX_BPM).contains(&bpm) => UiEvent::SetBpm(bpm),
+                _ => {
+                    self.set_message(format!("BPM must be {}-{}", MIN_BPM, MAX_BPM));
+                    UiEvent::Noop
+                }
+            },
+            ("swing", Some(n)) => match n.trim_end_matches('%').parse() {
+                Ok(n) if (MIN_SWING..=MAX_SWING).contains(&n) => UiEvent::SetSwing(n),
+                _ => {
+                    self.set_message(format!("Swing must be {}-{}%", MIN_SWING, MAX_SWING));
+                    UiEvent::Noop
+                }
+            },
+            ("prob", Some(n)) => match n.trim_end_matches('%').parse() {
+                Ok(n) if n <= 100 => UiEvent::SetProbability(self.cursor.0, self.cursor.1, n),
+                _ => {
+                    self.set_message("Probability must be 0-100%");
+                    UiEvent::Noop
+                }
+            },
+            ("cond", None) => UiEvent::SetCondition(self.cursor.0, self.cursor.1, None),
+            ("cond", Some(c)) => match c.parse() {
+                Ok(c) => UiEvent::SetCondition(self.cursor.0, self.cursor.1, Some(c)),
+                Err(e) => {
+                    self.set_message(format!("{:#}", e));
+                    UiEvent::Noop
+                }
+            },
+            ("pat", Some(arg)) => {
+                // `:pat N` switches at the next bar, `:pat N S` after S steps
+                let mut args = arg.split_whitespace();
+                let slot = args.next().map(str::parse::<usize>);
+                let after = args.next().map(str::parse::<usize>);
+                match (slot, after) {
+                    (Some(Ok(n)), None) if (1..=BANK_SLOTS).contains(&n) => {
+                        UiEvent::QueuePattern(n - 1, None)
+                    }
+                    (Some(Ok(n)), Some(Ok(s))) if (1..=BANK_SLOTS).contains(&n) && s > 0 => {
+                        UiEvent::QueuePattern(n - 1, Some(s))
+                    }
+                    _ => {
+                        self.set_message(format!("Usage: :pat N [steps] (N = 1-{})", BANK_SLOTS));
+                        UiEvent::Noop
+                    }
+                }
+            }
+            ("every", arg) => match (self.selection(), arg.and_then(|n| n.parse().ok())) {
+                (Some(sel), Some(n)) if n > 0 => {
+                    self.mode = Mode::Normal;
+                    block_edit(sel.repeat(&self.state.pattern, n))
+                }
+                (None, _) => {
+                    self.set_message("Select a block in visual mode first");
+                    UiEvent::Noop
+                }
+                _ => {
+                    self.set_message("Usage: :every N");
+                    UiEvent::Noop
+                }
+            },
+            ("u" | "undo", None) => self.undo(),
+            ("redo", None) => self.redo(),
+            ("song", None) => UiEvent::SetSongMode(!self.state.song_mode),
+            ("song", Some(arg)) => match arg.as_str() {
+                "on" => UiEvent::SetSongMode(true),
+                "off" => UiEvent::SetSongMode(false),
+                _ => {
+                    self.set_message("Usage: :song [on|off]");
+                    UiEvent::Noop
+                }
+            },
+            ("rec", None) => UiEvent::Record(!self.state.recording),
+            ("rec", Some(arg)) => match arg.as_str() {
+                "on" => UiEvent::Record(true),
+                "off" => UiEvent::Record(false),
+                _ => {
+                    self.set_message("Usage: :rec [on|off]");
+                    UiEvent::Noop
+                }
+            },
+            ("quantize", Some(n)) => match n.trim_end_matches('%').parse() {
+                Ok(quantize) if quantize <= 100 => UiEvent::SetRecordSettings(RecordSettings {
+                    quantize,
+                    ..self.state.record
+                }),
+                _ => {
+                    self.set_message("Quantize must be 0-100%");
+                    UiEvent::Noop
+                }
+            },
+            ("recmode", Some(mode)) => match mode.as_str() {
+                "overdub" | "replace" => UiEvent::SetRecordSettings(RecordSettings {
+                    replace: mode == "replace",
+                    ..self.state.record
+                }),
+                _ => {
+                    self.set_message("Usage: :recmode overdub|replace");
+                    UiEvent::Noop
+                }
+            },
+            ("countin", Some(n)) => match n.parse() {
+                Ok(count_in) if count_in <= MAX_COUNT_IN => {
+                    UiEvent::SetRecordSettings(RecordSettings {
+                        count_in,
+                        ..self.state.record
+                    })
+                }
+                _ => {
+                    self.set_message(format!("Count-in must be 0-{} bars", MAX_COUNT_IN));
+                    UiEvent::Noop
+                }
+            },
+            ("q", _) => UiEvent::Exit,
+            ("", _) => UiEvent::Noop,
+            _ => {
+                self.set_message(format!("Not an editor command: {}", cmd));
+                UiEvent::Noop
+            }
+        }
+    }
+
     pub fn shutdown(&mut self) -> Result<()> {
         disable_raw_mode()?;
         execute!(self.terminal.backend_mut(), LeaveAlternateScreen)?;
@@ -129,3 +751,12 @@ impl Ui {
         Ok(())
     }
 }
+
+/// Send a block edit, unless it changes nothing
+fn block_edit(edits: Edits) -> UiEvent {
+    if edits.is_empty() {
+        UiEvent::Noop
+    } else {
+        UiEvent::SetSteps(edits)
+    }
+}
@@ -0,0 +1,107 @@
+// Tap tempo
+// Turns a run of key taps into a tempo: the gaps between the last few taps
+// are averaged, leaving out any that are far off the median.
+use std::time::{Duration, Instant};
+
+use crate::sequencer::clock::{MAX_BPM, MIN_BPM};
+
+/// Taps kept; older ones drop out so the tempo follows the latest taps.
+const TAPS: usize = 8;
+/// Gaps further than this from the median, as a fraction of it, count as
+/// mistimed taps.
+const OUTLIER: f64 = 0.25;
+
+#[derive(Debug, Default)]
+pub struct TapTempo {
+    taps: Vec<Instant>,
+}
+
+impl TapTempo {
+    /// Record a tap at `at`. Returns the tempo, to a tenth of a BPM, once at
+    /// least two taps are in a row; a pause longer than a beat at the
+    /// slowest tempo starts a new count.
+    pub fn tap(&mut self, at: Instant) -> Option<f64> {
+        let timeout = Duration::from_secs_f64(60.0 / MIN_BPM);
+        if self.taps.last().is_some_and(|&last| at - last > timeout) {
+            self.taps.clear();
+        }
+        self.taps.push(at);
+        if self.taps.len() > TAPS {
+            self.taps.remove(0);
+        }
+
+        let mut gaps: Vec<f64> = self
+            .taps
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).as_secs_f64())
+            .collect();
+        if gaps.is_empty() {
+            return None;
+        }
+        let mut sorted = gaps.clone();
+        sorted.sort_by(f64::total_cmp);
+        let median = sorted[sorted.len() / 2];
+        gaps.retain(|gap| (gap - median).abs() <= median * OUTLIER);
+        let beat = gaps.iter().sum::<f64>() / gaps.len() as f64;
+        let bpm = (60.0 / beat).clamp(MIN_BPM, MAX_BPM);
+        Some((bpm * 10.0).round() / 10.0)
+    }
+
+    /// Taps in the current count.
+    pub fn count(&self) -> usize {
+        self.taps.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tap once per gap (in ms) after a first tap, returning the last tempo.
+    fn taps(tempo: &mut TapTempo, start: Instant, gaps: &[u64]) -> Option<f64> {
+        let mut at = start;
+        let mut bpm = tempo.tap(at);
+        for &gap in gaps {
+            at += Duration::from_millis(gap);
+            bpm = tempo.tap(at);
+        }
+        bpm
+    }
+
+    #[test]
+    fn averages_recent_taps_without_outliers() {
+        let start = Instant::now();
+        let mut tempo = TapTempo::default();
+        assert_eq!(tempo.tap(start), None);
+        assert_eq!(taps(&mut tempo, start, &[500]), Some(120.0));
+
+        // a late and an early tap don't pull the average
+        let mut tempo = TapTempo::default();
+        assert_eq!(
+            taps(&mut tempo, start, &[500, 500, 800, 500, 200, 500]),
+            Some(120.0)
+        );
+        // tempos are rounded to a tenth, so can be fractional
+        let mut tempo = TapTempo::default();
+        assert_eq!(taps(&mut tempo, start, &[484, 484, 484]), Some(124.0));
+        let mut tempo = TapTempo::default();
+        assert_eq!(taps(&mut tempo, start, &[497, 498, 497]), Some(120.6));
+        // only the last eight taps count
+        let mut tempo = TapTempo::default();
+        assert_eq!(taps(&mut tempo, start, &[550, 550, 550]), Some(109.1));
+        let last = start + Duration::from_millis(2200);
+        assert_eq!(taps(&mut tempo, last, &[600; 7]), Some(100.0));
+        assert_eq!(tempo.count(), 8);
+    }
+
+    #[test]
+    fn a_long_pause_starts_over() {
+        let start = Instant::now();
+        let mut tempo = TapTempo::default();
+        taps(&mut tempo, start, &[500, 500]);
+        let later = start + Duration::from_secs(5);
+        assert_eq!(tempo.tap(later), None);
+        assert_eq!(tempo.count(), 1);
+        assert_eq!(tempo.tap(later + Duration::from_millis(750)), Some(80.0));
+    }
+}
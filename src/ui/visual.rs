@@ -0,0 +1,237 @@
+// Visual block selection
+// The rectangle of steps picked in visual mode and the edits made to it.
+// Edits are worked out against the grid the engine last reported and sent
+// back as the cells that change.
+use std::ops::Range;
+
+use crate::sequencer::steps::{Pattern, Step, DEFAULT_VELOCITY};
+
+/// Cells to write: track, step and the new contents.
+pub type Edits = Vec<(usize, usize, Step)>;
+
+/// Steps copied with yank, one row per track.
+pub type Clipboard = Vec<Vec<Step>>;
+
+/// Tracks and steps between the anchor and the cursor, both included.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selection {
+    pub tracks: Range<usize>,
+    pub steps: Range<usize>,
+}
+
+impl Selection {
+    /// The block with corners `anchor` and `cursor`, each a (track, step).
+    pub fn new(anchor: (usize, usize), cursor: (usize, usize)) -> Self {
+        Self {
+            tracks: anchor.0.min(cursor.0)..anchor.0.max(cursor.0) + 1,
+            steps: anchor.1.min(cursor.1)..anchor.1.max(cursor.1) + 1,
+        }
+    }
+
+    pub fn contains(&self, track: usize, step: usize) -> bool {
+        self.tracks.contains(&track) && self.steps.contains(&step)
+    }
+
+    /// Top-left corner, where pastes land.
+    pub fn start(&self) -> (usize, usize) {
+        (self.tracks.start, self.steps.start)
+    }
+
+    /// Turn every rest in the block into a hit; existing hits are kept.
+    pub fn fill(&self, pattern: &Pattern) -> Edits {
+        self.edit(pattern, |_, _, cell| {
+            if cell.is_on() {
+                cell
+            } else {
+                Step::hit(DEFAULT_VELOCITY)
+            }
+        })
+    }
+
+    pub fn clear(&self, pattern: &Pattern) -> Edits {
+        self.edit(pattern, |_, _, _| Step::REST)
+    }
+
+    /// Hits become rests and rests become hits.
+    pub fn invert(&self, pattern: &Pattern) -> Edits {
+        self.edit(pattern, |_, _, cell| {
+            if cell.is_on() {
+                Step::REST
+            } else {
+                Step::hit(DEFAULT_VELOCITY)
+            }
+        })
+    }
+
+    /// Rotate each row of the block one step right (or left); the step that
+    /// falls off one end comes back in at the other.
+    pub fn shift(&self, pattern: &Pattern, right: bool) -> Edits {
+        let width = self.steps.len();
+        let from = |step: usize| {
+            let offset = step - self.steps.start;
+            let offset = if right {
+                (offset + width - 1) % width
+            } else {
+                (offset + 1) % width
+            };
+            self.steps.start + offset
+        };
+        self.edit(pattern, |track, step, _| cell(pattern, track, from(step)))
+    }
+
+    /// Repeat the block's first `every` steps across the rest of it.
+    pub fn repeat(&self, pattern: &Pattern, every: usize) -> Edits {
+        let every = every.max(1);
+        self.edit(pattern, |track, step, _| {
+            let offset = (step - self.steps.start) % every;
+            cell(pattern, track, self.steps.start + offset)
+        })
+    }
+
+    pub fn yank(&self, pattern: &Pattern) -> Clipboard {
+        self.tracks
+            .clone()
+            .map(|track| {
+                self.steps
+                    .clone()
+                    .map(|step| cell(pattern, track, step))
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// The cells in the block that `f` changes.
+    fn edit(&self, pattern: &Pattern, f: impl Fn(usize, usize, Step) -> Step) -> Edits {
+        let mut edits = Vec::new();
+        for track in self.tracks.clone() {
+            for step in self.steps.clone() {
+                let Some(&old) = pattern.get(track).and_then(|row| row.get(step)) else {
+                    continue;
+                };
+                let new = f(track, step, old);
+                if new != old {
+                    edits.push((track, step, new));
+                }
+            }
+        }
+        edits
+    }
+}
+
+/// Write `clipboard` with its top-left corner at `at`; whatever falls off
+/// the grid is dropped.
+pub fn paste(pattern: &Pattern, clipboard: &Clipboard, at: (usize, usize)) -> Edits {
+    let mut edits = Vec::new();
+    for (i, row) in clipboard.iter().enumerate() {
+        for (j, &new) in row.iter().enumerate() {
+            let (track, step) = (at.0 + i, at.1 + j);
+            match pattern.get(track).and_then(|r| r.get(step)) {
+                Some(&old) if old != new => edits.push((track, step, new)),
+                _ => {}
+            }
+        }
+    }
+    edits
+}
+
+fn cell(pattern: &Pattern, track: usize, step: usize) -> Step {
+    pattern
+        .get(track)
+        .and_then(|row| row.get(step))
+        .copied()
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `x` hits and `.` rests, one string per track.
+    fn grid(rows: &[&str]) -> Pattern {
+        rows.iter()
+            .map(|row| {
+                row.chars()
+                    .map(|c| match c {
+                        'x' => Step::hit(DEFAULT_VELOCITY),
+                        _ => Step::REST,
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn apply(pattern: &Pattern, edits: Edits) -> Vec<String> {
+        let mut pattern = pattern.clone();
+        for (track, step, cell) in edits {
+            pattern[track][step] = cell;
+        }
+        pattern
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|c| if c.is_on() { 'x' } else { '.' })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn block_edits_stay_inside_the_selection_across_tracks() {
+        let pattern = grid(&["x.x.x.x.", "........", "xx..xx.."]);
+        // anchor and cursor can be any two corners
+        let sel = Selection::new((2, 4), (0, 1));
+        assert_eq!(
+            sel,
+            Selection {
+                tracks: 0..3,
+                steps: 1..5
+            }
+        );
+
+        let edited = |edits| apply(&pattern, edits);
+        assert_eq!(
+            edited(sel.fill(&pattern)),
+            ["xxxxx.x.", ".xxxx...", "xxxxxx.."]
+        );
+        assert_eq!(
+            edited(sel.clear(&pattern)),
+            ["x.....x.", "........", "x....x.."]
+        );
+        assert_eq!(
+            edited(sel.invert(&pattern)),
+            ["xx.x..x.", ".xxxx...", "x.xx.x.."]
+        );
+        assert_eq!(
+            edited(sel.shift(&pattern, true)),
+            ["xx.x..x.", "........", "xxx..x.."]
+        );
+        assert_eq!(
+            edited(sel.shift(&pattern, false)),
+            ["xx.x..x.", "........", "x..xxx.."]
+        );
+        // only cells that change are sent
+        assert!(sel
+            .fill(&pattern)
+            .iter()
+            .all(|&(_, step, _)| (1..5).contains(&step)));
+        assert_eq!(sel.clear(&pattern).len(), 4);
+    }
+
+    #[test]
+    fn repeat_and_paste() {
+        let pattern = grid(&["x.x.x.x.", "........", "xx..xx.."]);
+        let row = |track| Selection::new((track, 1), (track, 4));
+        assert_eq!(apply(&pattern, row(2).repeat(&pattern, 1))[2], "xxxxxx..");
+        assert_eq!(row(0).repeat(&pattern, 2), vec![]);
+        assert_eq!(apply(&pattern, row(2).repeat(&pattern, 2))[2], "xx.x.x..");
+
+        let clipboard = Selection::new((0, 0), (0, 2)).yank(&pattern);
+        assert_eq!(clipboard.len(), 1);
+        // the third step would land past the end of the row and is dropped
+        assert_eq!(
+            apply(&pattern, paste(&pattern, &clipboard, (2, 6)))[2],
+            "xx..xxx."
+        );
+        assert_eq!(paste(&pattern, &clipboard, (5, 0)), vec![]);
+    }
+}